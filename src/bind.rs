@@ -0,0 +1,256 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{self as std_net, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::{self, TcpStream};
+
+#[derive(Debug)]
+pub enum BindError {
+    // neither an address nor the name of an interface
+    UnknownInterface(String),
+    // the interface has no address to bind to
+    NoAddress(String),
+    InterfaceFailure(io::Error),
+}
+
+impl fmt::Display for BindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindError::UnknownInterface(name) => write!(f, "no interface named {}", name),
+            BindError::NoAddress(name) => write!(f, "interface {} has no address", name),
+            BindError::InterfaceFailure(e) => write!(f, "can't list interfaces: {}", e),
+        }
+    }
+}
+
+impl Error for BindError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BindError::InterfaceFailure(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// the addresses to bind to for `spec`, an IP address or the name of a
+// network interface
+pub fn parse_bind(spec: &str) -> Result<Vec<IpAddr>, BindError> {
+    if let Ok(ip) = spec.parse() {
+        return Ok(vec![ip]);
+    }
+    let interfaces = if_addrs::get_if_addrs().map_err(BindError::InterfaceFailure)?;
+    if !interfaces.iter().any(|i| i.name == spec) {
+        return Err(BindError::UnknownInterface(spec.to_owned()));
+    }
+    let addrs: Vec<IpAddr> = interfaces
+        .iter()
+        .filter(|i| i.name == spec)
+        .map(|i| i.ip())
+        // link-local IPv6 addresses can't be used without a scope
+        .filter(|ip| match ip {
+            IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 != 0xfe80,
+            IpAddr::V4(_) => true,
+        })
+        .collect();
+    if addrs.is_empty() {
+        return Err(BindError::NoAddress(spec.to_owned()));
+    }
+    Ok(addrs)
+}
+
+// the address of `bind` to reach `ip` from: the first one of its family
+pub fn source_for(bind: &[IpAddr], ip: IpAddr) -> Option<IpAddr> {
+    bind.iter().copied().find(|b| b.is_ipv4() == ip.is_ipv4())
+}
+
+fn is_global(ip: &Ipv6Addr) -> bool {
+    // 2000::/3 is all of the global unicast space
+    ip.segments()[0] & 0xe000 == 0x2000
+}
+
+// our global IPv6 address: the first one we are bound to, or the first one of
+// any interface when we aren't bound
+pub fn public_ipv6(bind: &[IpAddr]) -> Option<Ipv6Addr> {
+    let interfaces;
+    let addrs = if bind.is_empty() {
+        interfaces = if_addrs::get_if_addrs().ok()?;
+        interfaces.iter().map(|i| i.ip()).collect()
+    } else {
+        bind.to_vec()
+    };
+    addrs.into_iter().find_map(|ip| match ip {
+        IpAddr::V6(ip) if is_global(&ip) => Some(ip),
+        _ => None,
+    })
+}
+
+fn unreachable(to: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AddrNotAvailable,
+        format!("no bind address can reach {}", to),
+    )
+}
+
+// a TCP connection to `addr`, made from an address of `bind` unless it is
+// empty
+pub async fn connect(addr: SocketAddr, bind: &[IpAddr]) -> io::Result<TcpStream> {
+    if bind.is_empty() {
+        return TcpStream::connect(addr).await;
+    }
+    let source = source_for(bind, addr.ip()).ok_or_else(|| unreachable(addr))?;
+    let builder = match source {
+        IpAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+        IpAddr::V6(_) => net2::TcpBuilder::new_v6()?,
+    };
+    let stream = builder.bind(SocketAddr::new(source, 0))?.to_tcp_stream()?;
+    TcpStream::connect_std(stream, &addr).await
+}
+
+// a listener on `addr`; IPv6 listeners take IPv6 connections only, so that
+// one on every IPv4 address can share their port
+pub fn listen(addr: SocketAddr) -> io::Result<std::net::TcpListener> {
+    let builder = match addr {
+        SocketAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => {
+            let builder = net2::TcpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+    // like std, so that a restart doesn't wait for old connections to time out
+    if cfg!(unix) {
+        builder.reuse_address(true)?;
+    }
+    builder.bind(addr)?.listen(128)
+}
+
+// a UDP socket on `port` for talking to addresses of `to`'s family, on the
+// address of `bind` of that family, or on all of them when `bind` is empty;
+// IPv6 sockets take IPv6 only, so that an IPv4 one can share their port
+pub fn udp(to: IpAddr, port: u16, bind: &[IpAddr]) -> io::Result<std_net::UdpSocket> {
+    let ip = if bind.is_empty() {
+        match to {
+            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        }
+    } else {
+        source_for(bind, to).ok_or_else(|| unreachable(to))?
+    };
+    let builder = match ip {
+        IpAddr::V4(_) => net2::UdpBuilder::new_v4()?,
+        IpAddr::V6(_) => {
+            let builder = net2::UdpBuilder::new_v6()?;
+            builder.only_v6(true)?;
+            builder
+        }
+    };
+    builder.bind((ip, port))
+}
+
+// a blocking TCP connection to `addr`, made from an address of `bind` unless
+// it is empty; std can't time out the connect of a bound socket, so
+// `timeout` only applies to unbound ones
+pub fn connect_std(
+    addr: SocketAddr,
+    bind: &[IpAddr],
+    timeout: Duration,
+) -> io::Result<std_net::TcpStream> {
+    if bind.is_empty() {
+        return std_net::TcpStream::connect_timeout(&addr, timeout);
+    }
+    let source = source_for(bind, addr.ip()).ok_or_else(|| unreachable(addr))?;
+    let builder = match source {
+        IpAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+        IpAddr::V6(_) => net2::TcpBuilder::new_v6()?,
+    };
+    builder.bind(SocketAddr::new(source, 0))?.connect(addr)
+}
+
+// a TCP connection to `host`, trying each of its addresses in turn
+pub async fn connect_host(host: &str, port: u16, bind: &[IpAddr]) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in net::lookup_host((host, port)).await? {
+        match connect(addr, bind).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound)))
+}
+
+// the address of `bind` to talk to `host` from, for clients that connect by
+// themselves; None when `bind` is empty
+pub async fn source_for_host(host: &str, port: u16, bind: &[IpAddr]) -> io::Result<Option<IpAddr>> {
+    if bind.is_empty() {
+        return Ok(None);
+    }
+    let mut addrs = net::lookup_host((host, port)).await?;
+    match addrs.find_map(|addr| source_for(bind, addr.ip())) {
+        Some(source) => Ok(Some(source)),
+        None => Err(unreachable(host)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, TcpListener, UdpSocket};
+
+    #[test]
+    fn sources_match_the_family() {
+        let bind: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+        let source = |ip: &str| super::source_for(&bind, ip.parse().unwrap());
+        assert_eq!(source("192.0.2.7"), Some(bind[0]));
+        assert_eq!(source("2001:db8::99"), Some(bind[1]));
+        assert_eq!(super::source_for(&bind[..1], "::1".parse().unwrap()), None);
+
+        assert_eq!(
+            super::parse_bind("127.0.0.2").unwrap(),
+            vec![Ipv4Addr::new(127, 0, 0, 2)]
+        );
+        assert!(super::parse_bind("no-such-interface0").is_err());
+
+        let local: Vec<IpAddr> = vec!["::1".parse().unwrap(), "fe80::1".parse().unwrap()];
+        assert_eq!(super::public_ipv6(&local), None);
+        assert_eq!(
+            super::public_ipv6(&bind),
+            Some("2001:db8::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn connections_come_from_the_bind_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let bind: Vec<IpAddr> = vec!["127.0.0.3".parse().unwrap()];
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let stream = runtime.block_on(super::connect(addr, &bind)).unwrap();
+        assert_eq!(stream.local_addr().unwrap().ip(), bind[0]);
+        let (_, from) = listener.accept().unwrap();
+        assert_eq!(from.ip(), bind[0]);
+
+        let v6 = "[::1]:1".parse().unwrap();
+        assert!(runtime.block_on(super::connect(v6, &bind)).is_err());
+    }
+
+    #[test]
+    fn datagrams_come_from_the_bind_address() {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let to = peer.local_addr().unwrap();
+        let bind: Vec<IpAddr> = vec!["127.0.0.3".parse().unwrap()];
+
+        let socket = super::udp(to.ip(), 0, &bind).unwrap();
+        socket.send_to(b"hi", to).unwrap();
+        let (_, from) = peer.recv_from(&mut [0u8; 2]).unwrap();
+        assert_eq!(from.ip(), bind[0]);
+
+        assert!(super::udp("::1".parse().unwrap(), 0, &bind).is_err());
+        let any = super::udp(to.ip(), 0, &[]).unwrap();
+        assert!(any.local_addr().unwrap().ip().is_unspecified());
+    }
+}
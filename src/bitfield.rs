@@ -1,8 +1,14 @@
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Bitfield {
     pub array: Vec<u8>,
 }
 
+pub fn new_bitfield(num_pieces: i64) -> Bitfield {
+    Bitfield {
+        array: vec![0u8; ((num_pieces + 7) / 8) as usize],
+    }
+}
+
 impl Bitfield {
     pub fn has_piece(&self, index: i64) -> bool {
         let byte_index = index / 8;
@@ -12,7 +18,7 @@ impl Bitfield {
         }
 
         // magic stolen from https://github.com/veggiedefender/torrent-client/blob/a83013d250dd9b4268cceace28e4cd82b07f2cbd/bitfield/bitfield.go
-        return self.array[byte_index as usize] >> (7 - offset) & 1 != 0;
+        self.array[byte_index as usize] >> (7 - offset) & 1 != 0
     }
 
     pub fn set_piece(&mut self, index: i64) {
@@ -27,6 +33,18 @@ impl Bitfield {
         // magic stolen from https://github.com/veggiedefender/torrent-client/blob/a83013d250dd9b4268cceace28e4cd82b07f2cbd/bitfield/bitfield.go
         self.array[byte_index as usize] |= 1 << (7 - offset)
     }
+
+    pub fn clear_piece(&mut self, index: i64) {
+        let byte_index = index / 8;
+        let offset = index % 8;
+
+        // silently discard invalid bounded index
+        if byte_index >= self.array.len() as i64 {
+            return;
+        }
+
+        self.array[byte_index as usize] &= !(1 << (7 - offset))
+    }
 }
 
 #[cfg(test)]
@@ -42,8 +60,8 @@ mod tests {
             false, true, false, true, false, true, false, false, false, true, false, true, false,
             true, false, false, false, false, false, false,
         ];
-        for index in 0..outputs.len() {
-            assert_eq!(outputs[index], bf.has_piece(index as i64));
+        for (index, output) in outputs.iter().enumerate() {
+            assert_eq!(*output, bf.has_piece(index as i64));
         }
     }
 
@@ -104,4 +122,24 @@ mod tests {
         bf.set_piece(19); // beyond 15 is out of bounds
         assert_eq!(bf, result_bf);
     }
+
+    #[test]
+    fn clear_piece_works_index_1() {
+        let mut bf = super::Bitfield {
+            array: vec![0b01010100, 0b01010100],
+            //              ^ clearing this one
+        };
+        bf.clear_piece(1);
+
+        let result_bf = super::Bitfield {
+            array: vec![0b00010100, 0b01010100],
+        };
+        assert_eq!(bf, result_bf);
+    }
+
+    #[test]
+    fn new_bitfield_rounds_up() {
+        assert_eq!(super::new_bitfield(9).array, vec![0, 0]);
+        assert_eq!(super::new_bitfield(8).array, vec![0]);
+    }
 }
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often the upload slots are handed out again
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+// how often the optimistic unchoke moves on to another peer
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+// PeerState is what the choker knows about one connection; the download
+// worker keeps it up to date and applies the `choked` decision
+#[derive(Debug)]
+pub struct PeerState {
+    pub interested: bool,
+    pub choked: bool,
+    pub downloaded: i64,
+    pub uploaded: i64,
+    pub last_unchoked: Option<Instant>,
+}
+
+// connections are keyed by the counter the session hands to each download
+// worker
+pub type Peers = Arc<Mutex<HashMap<usize, PeerState>>>;
+
+// Choker fills a fixed number of upload slots with the peers that give us the
+// most back, plus one optimistic unchoke so new peers get a chance to prove
+// themselves
+pub struct Choker {
+    upload_slots: usize,
+    optimistic: Option<usize>,
+    last_optimistic: Option<Instant>,
+}
+
+pub fn new_peer_state() -> PeerState {
+    PeerState {
+        interested: false,
+        choked: true,
+        downloaded: 0,
+        uploaded: 0,
+        last_unchoked: None,
+    }
+}
+
+pub fn new_choker(upload_slots: usize) -> Choker {
+    Choker {
+        upload_slots,
+        optimistic: None,
+        last_optimistic: None,
+    }
+}
+
+impl Choker {
+    // decides who is choked for the next round; while downloading peers are
+    // ranked by how much they sent us, while seeding by how fast we upload to
+    // them
+    pub fn rechoke(&mut self, peers: &mut HashMap<usize, PeerState>, seeding: bool, now: Instant) {
+        let mut ranked: Vec<(usize, i64)> = peers
+            .iter()
+            .filter(|(_, p)| p.interested)
+            .map(|(id, p)| (*id, if seeding { p.uploaded } else { p.downloaded }))
+            .collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let rotate = match (self.optimistic, self.last_optimistic) {
+            (Some(id), Some(last)) => {
+                now.duration_since(last) >= OPTIMISTIC_INTERVAL
+                    || !peers.get(&id).is_some_and(|p| p.interested)
+            }
+            _ => true,
+        };
+
+        let regular: Vec<usize> = ranked
+            .iter()
+            .map(|(id, _)| *id)
+            .filter(|id| rotate || Some(*id) != self.optimistic)
+            .take(self.upload_slots)
+            .collect();
+
+        if rotate {
+            // the interested peer that has waited the longest for a slot
+            self.optimistic = ranked
+                .iter()
+                .map(|(id, _)| *id)
+                .filter(|id| !regular.contains(id))
+                .min_by_key(|id| (peers[id].last_unchoked, *id));
+            self.last_optimistic = Some(now);
+        }
+
+        for (id, p) in peers.iter_mut() {
+            p.choked = !(regular.contains(id) || Some(*id) == self.optimistic);
+            if !p.choked {
+                p.last_unchoked = Some(now);
+            }
+            // rates are measured over one rechoke interval
+            p.downloaded = 0;
+            p.uploaded = 0;
+        }
+    }
+}
+
+// runs until `stop` is set, which is checked once per rechoke interval
+pub fn start_choker(
+    peers: Peers,
+    upload_slots: usize,
+    seeding: Arc<AtomicBool>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut choker = new_choker(upload_slots);
+    while !stop.load(Ordering::Relaxed) {
+        {
+            let mut peers = peers.lock().unwrap();
+            choker.rechoke(&mut peers, seeding.load(Ordering::Relaxed), Instant::now());
+        }
+        thread::sleep(RECHOKE_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    use super::PeerState;
+
+    fn peer(interested: bool, downloaded: i64, uploaded: i64) -> PeerState {
+        PeerState {
+            interested,
+            downloaded,
+            uploaded,
+            ..super::new_peer_state()
+        }
+    }
+
+    fn unchoked(peers: &HashMap<usize, PeerState>) -> Vec<usize> {
+        let mut ids: Vec<usize> = peers
+            .iter()
+            .filter(|(_, p)| !p.choked)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn rechoke_unchokes_best_reciprocators_and_one_optimistic() {
+        let mut peers = HashMap::new();
+        peers.insert(0, peer(true, 100, 0));
+        peers.insert(1, peer(true, 500, 0));
+        peers.insert(2, peer(true, 300, 0));
+        peers.insert(3, peer(false, 900, 0)); // not interested, never unchoked
+        peers.insert(4, peer(true, 0, 0));
+
+        let mut choker = super::new_choker(2);
+        choker.rechoke(&mut peers, false, Instant::now());
+
+        // 1 and 2 sent us the most, 0 waited longest among the rest
+        assert_eq!(unchoked(&peers), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn rechoke_ranks_by_upload_when_seeding() {
+        let mut peers = HashMap::new();
+        peers.insert(0, peer(true, 500, 10));
+        peers.insert(1, peer(true, 0, 900));
+        peers.insert(2, peer(true, 0, 0));
+
+        let mut choker = super::new_choker(1);
+        choker.rechoke(&mut peers, true, Instant::now());
+
+        assert_eq!(unchoked(&peers), vec![0, 1]);
+        assert_eq!(choker.optimistic, Some(0));
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_after_interval() {
+        let mut peers = HashMap::new();
+        for id in 0..4 {
+            peers.insert(id, peer(true, 0, 0));
+        }
+        let mut choker = super::new_choker(1);
+        let start = Instant::now();
+
+        choker.rechoke(&mut peers, false, start);
+        let first = choker.optimistic;
+
+        // still within the interval, the optimistic peer stays
+        choker.rechoke(&mut peers, false, start + Duration::from_secs(10));
+        assert_eq!(choker.optimistic, first);
+
+        // past the interval it moves to a peer that waited longer
+        choker.rechoke(&mut peers, false, start + Duration::from_secs(30));
+        assert_ne!(choker.optimistic, first);
+        assert!(!peers[&choker.optimistic.unwrap()].choked);
+    }
+}
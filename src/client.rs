@@ -1,235 +1,433 @@
-use std::convert::TryInto;
-use std::io::{Read, Write};
-use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Elapsed};
 
+use crate::bind;
 use crate::bitfield;
+use crate::extension;
 use crate::handshake;
 use crate::message;
 use crate::p2p;
+use crate::pipeline;
+use crate::proxy;
+use crate::ratelimit;
 
-#[derive(Debug, Clone)]
+// ClientError is why the connection to a peer failed; every variant names
+// the peer
+#[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum ClientError {
-    ConnectionFailure,
-    BitfieldFailure,
-    PayloadFailure,
-    MessageFailure,
+    // connecting, reading or writing failed
+    ConnectionFailure(SocketAddr, io::Error),
+    ConnectionClosed(SocketAddr),
+    // nothing happened within IO_TIMEOUT; the connection may still be usable
+    Timeout(SocketAddr),
+    HandshakeFailure(SocketAddr, handshake::HandshakeError),
+    // the peer didn't start with its bitfield
+    BitfieldFailure(SocketAddr),
+    MessageFailure(SocketAddr, message::MessageError),
+    ProxyFailure(SocketAddr, proxy::ProxyError),
 }
 
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::ConnectionFailure(addr, e) => write!(f, "{}: {}", addr, e),
+            ClientError::ConnectionClosed(addr) => write!(f, "{}: connection closed", addr),
+            ClientError::Timeout(addr) => write!(f, "{}: timed out", addr),
+            ClientError::HandshakeFailure(addr, e) => write!(f, "{}: bad handshake: {}", addr, e),
+            ClientError::BitfieldFailure(addr) => write!(f, "{}: expected a bitfield", addr),
+            ClientError::MessageFailure(addr, e) => write!(f, "{}: {}", addr, e),
+            ClientError::ProxyFailure(addr, e) => write!(f, "{}: {}", addr, e),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClientError::ConnectionFailure(_, e) => Some(e),
+            ClientError::HandshakeFailure(_, e) => Some(e),
+            ClientError::MessageFailure(_, e) => Some(e),
+            ClientError::ProxyFailure(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// how long a connect, a read or a write may take before we give up on it
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
 // Client is a TCP connection with a peer
 pub struct Client {
     pub conn: TcpStream,
+    decoder: message::Decoder,
     pub choked: bool,
+    pub am_choking: bool,
+    pub peer_interested: bool,
+    pub downloaded: i64,
+    pub uploaded: i64,
+    pub peer_requests: VecDeque<(u32, u32, u32)>,
+    pub peer_hash_requests: VecDeque<message::HashRange>,
     pub bitfield: bitfield::Bitfield,
     pub peer: p2p::Peer,
-    pub info_hash: [u8; 20],
-    pub peer_id: [u8; 20],
+    // the id the peer sent in its handshake
+    pub remote_peer_id: [u8; 20],
+    pub pipeline: pipeline::Pipeline,
+    pub extended_handshake: Option<extension::ExtendedHandshake>,
+    // the info dictionary we hand out over ut_metadata, once we know it
+    metadata: Option<Arc<Vec<u8>>>,
+    // whether we exchange peers with the peer, and the ones it told us about
+    // that nobody took yet
+    pex: bool,
+    pub pex_peers: Vec<p2p::Peer>,
+    pub download_throttle: ratelimit::Throttle,
+    pub upload_throttle: ratelimit::Throttle,
 }
 
-pub fn new(p: p2p::Peer, peer_id: [u8; 20], info_hash: [u8; 20]) -> Result<Client, ClientError> {
-    // println!("connecting to peer {}", p.ip);
+pub async fn new(
+    p: p2p::Peer,
+    peer_id: [u8; 20],
+    info_hash: [u8; 20],
+    metadata: Option<Arc<Vec<u8>>>,
+    pex: bool,
+    proxy: Option<&proxy::Proxy>,
+    bind: &[IpAddr],
+) -> Result<Client, ClientError> {
     let addr = SocketAddr::new(p.ip, p.port);
-    match TcpStream::connect_timeout(&addr, Duration::new(5, 0)) {
-        Ok(mut stream) => {
-            // println!("successfully connected to peer {}", addr);
-            let handshake = handshake::new_handshake(info_hash, peer_id);
-            // println!("handshake: {:?}", handshake);
-
-            let handshake_serialized = handshake.serialize();
-
-            stream
-                .set_write_timeout(Some(Duration::new(5, 0)))
-                .expect("cannot set write timeout, lol");
-
-            stream
-                .write_all(&handshake_serialized)
-                .expect("handshake response error");
-
-            stream
-                .set_read_timeout(Some(Duration::new(5, 0)))
-                .expect("could not set read timeout :(");
-
-            // println!("send handshake to peer {}", addr);
-            // handshake is 68 bytes
-            let mut data: Vec<u8> = vec![0u8; 68];
-            match stream.read_exact(&mut data) {
-                Ok(_) => {
-                    let handshake_response = handshake::read_handshake(&data);
-                    if handshake_response.pstr.len() != 0 {
-                        // println!("handshake response success, len: {}", data.len());
-                        match receive_bitfield(&mut stream) {
-                            Ok(bitfield) => Ok(Client {
-                                conn: stream,
-                                choked: true,
-                                peer: p,
-                                bitfield,
-                                info_hash,
-                                peer_id,
-                            }),
-                            Err(_) => Err(ClientError::BitfieldFailure),
-                        }
-                    } else {
-                        // println!("handshake response, failed, equals 0, for: {}", addr);
-                        Err(ClientError::ConnectionFailure)
-                    }
-                }
-                Err(_) => {
-                    // println!("failed to receive data from: {}, err: {}", addr, e);
-                    Err(ClientError::ConnectionFailure)
-                }
-            }
-        }
-        Err(_) => {
-            // println!("could not connect to: {}, err: {}", addr, e);
-            Err(ClientError::ConnectionFailure)
-        }
+    let mut stream = match proxy {
+        Some(proxy) => match timeout(IO_TIMEOUT, proxy.connect(addr, bind)).await {
+            Ok(connected) => connected.map_err(|e| ClientError::ProxyFailure(addr, e))?,
+            Err(_) => return Err(ClientError::Timeout(addr)),
+        },
+        None => io_result(addr, timeout(IO_TIMEOUT, bind::connect(addr, bind)).await)?,
+    };
+    let handshake = handshake::new_handshake(info_hash, peer_id);
+    io_result(
+        addr,
+        timeout(IO_TIMEOUT, stream.write_all(&handshake.serialize())).await,
+    )?;
+
+    let mut data = vec![0u8; handshake::HANDSHAKE_LENGTH];
+    io_result(
+        addr,
+        timeout(IO_TIMEOUT, stream.read_exact(&mut data)).await,
+    )?;
+    let handshake_response = handshake::read_handshake(&data)
+        .and_then(|h| {
+            h.validate(&info_hash, &peer_id, p.id.as_ref())?;
+            Ok(h)
+        })
+        .map_err(|e| ClientError::HandshakeFailure(addr, e))?;
+
+    let mut client = new_client(stream, p, metadata, pex);
+    client.remote_peer_id = handshake_response.peer_id;
+    if handshake_response.supports_extension_protocol() {
+        client.send_extended_handshake().await?;
     }
+    client.receive_bitfield().await?;
+    Ok(client)
 }
 
-fn receive_bitfield(stream: &mut TcpStream) -> Result<bitfield::Bitfield, ClientError> {
-    let mut length_buf: Vec<u8> = vec![0, 0, 0, 0];
-    match stream.read_exact(&mut length_buf) {
-        Ok(_) => {
-            let length_u32: u32 =
-                u32::from_be_bytes([length_buf[0], length_buf[1], length_buf[2], length_buf[3]]);
-            let length: usize = length_u32.try_into().unwrap();
-
-            let mut payload = vec![0u8; length];
-            match stream.read_exact(&mut payload) {
-                Ok(_) => {
-                    let msg_id: u8 = payload[0];
-                    if msg_id != message::MSG_BITFIELD {
-                        println!("Expected bitfield but got type: {}", msg_id);
-                        return Err(ClientError::PayloadFailure);
-                    }
-                    if let Some((_, elements)) = payload.split_first() {
-                        Ok(bitfield::Bitfield {
-                            array: elements.to_owned(),
-                        })
-                    } else {
-                        Err(ClientError::BitfieldFailure)
-                    }
-                }
-                Err(_) => Err(ClientError::PayloadFailure),
-            }
-        }
-        Err(e) => {
-            println!("shutting down: error: {}", e);
-            stream.shutdown(Shutdown::Both).unwrap();
-            Err(ClientError::ConnectionFailure)
+// answers a connection a peer opened to us, after the listener read its
+// handshake; the peer may have nothing yet, so no bitfield is required
+pub async fn accept(
+    mut stream: TcpStream,
+    handshake_request: &handshake::Handshake,
+    peer_id: [u8; 20],
+    num_pieces: i64,
+    metadata: Option<Arc<Vec<u8>>>,
+    pex: bool,
+) -> Result<Client, ClientError> {
+    let addr = stream.peer_addr().map_err(|e| {
+        let unknown = SocketAddr::from(([0, 0, 0, 0], 0));
+        ClientError::ConnectionFailure(unknown, e)
+    })?;
+    let info_hash = handshake_request.info_hash;
+    handshake_request
+        .validate(&info_hash, &peer_id, None)
+        .map_err(|e| ClientError::HandshakeFailure(addr, e))?;
+    let handshake = handshake::new_handshake(info_hash, peer_id);
+    io_result(
+        addr,
+        timeout(IO_TIMEOUT, stream.write_all(&handshake.serialize())).await,
+    )?;
+
+    let p = p2p::Peer {
+        ip: addr.ip(),
+        port: addr.port(),
+        id: Some(handshake_request.peer_id),
+    };
+    let mut client = new_client(stream, p, metadata, pex);
+    client.remote_peer_id = handshake_request.peer_id;
+    client.bitfield = bitfield::new_bitfield(num_pieces);
+    if handshake_request.supports_extension_protocol() {
+        client.send_extended_handshake().await?;
+    }
+    Ok(client)
+}
+
+// turns the outcome of an IO operation bounded by IO_TIMEOUT into a
+// ClientError for the peer at `addr`
+fn io_result<T>(addr: SocketAddr, res: Result<io::Result<T>, Elapsed>) -> Result<T, ClientError> {
+    match res {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            Err(ClientError::ConnectionClosed(addr))
         }
+        Ok(Err(e)) => Err(ClientError::ConnectionFailure(addr, e)),
+        Err(_) => Err(ClientError::Timeout(addr)),
+    }
+}
+
+fn new_client(
+    stream: TcpStream,
+    p: p2p::Peer,
+    metadata: Option<Arc<Vec<u8>>>,
+    pex: bool,
+) -> Client {
+    Client {
+        conn: stream,
+        decoder: message::new_decoder(),
+        choked: true,
+        am_choking: true,
+        peer_interested: false,
+        downloaded: 0,
+        uploaded: 0,
+        peer_requests: VecDeque::new(),
+        peer_hash_requests: VecDeque::new(),
+        peer: p,
+        bitfield: bitfield::Bitfield { array: vec![] },
+        remote_peer_id: [0u8; 20],
+        pipeline: pipeline::new_pipeline(),
+        extended_handshake: None,
+        metadata,
+        pex,
+        pex_peers: vec![],
+        download_throttle: ratelimit::Throttle::default(),
+        upload_throttle: ratelimit::Throttle::default(),
     }
 }
 
 impl Client {
-    // reads from the client for a message with potential payload
-    pub fn read_client(&mut self) -> Option<message::Message> {
-        let mut msg_length = vec![0u8; 4];
-        match self.conn.read_exact(&mut msg_length) {
-            Ok(_) => {
-                // println!(
-                //     "{}: READ_CLIENT: msg_length: {:?}",
-                //     self.peer.ip, msg_length
-                // );
-
-                // check if keep-alive message
-                let msg_length_arr = [msg_length[0], msg_length[1], msg_length[2], msg_length[3]];
-                let msg_length_u32 = u32::from_be_bytes(msg_length_arr);
-                // println!(
-                //     "{}: READ_CLIENT: msg_length_u32: {}",
-                //     self.peer.ip, msg_length_u32
-                // );
-                if msg_length_u32 == 0 {
-                    // println!("{}: READ_CLIENT: msg_length_u32 = 0", self.peer.ip);
-                    return None;
-                }
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.peer.ip, self.peer.port)
+    }
 
-                let mut msg_data = vec![0u8; msg_length_u32 as usize];
-                match self.conn.read_exact(&mut msg_data) {
-                    Ok(_) => {
-                        // println!("{}: READ_CLIENT: msg_data: {:?}", self.peer.ip, msg_data);
+    async fn send_extended_handshake(&mut self) -> Result<(), ClientError> {
+        let msg = message::Message::Extended {
+            id: extension::EXT_HANDSHAKE,
+            payload: extension::new_handshake_payload(
+                self.metadata.as_ref().map(|m| m.len() as i64),
+                self.pex,
+            ),
+        };
+        self.send_message(msg).await
+    }
 
-                        let mut msg_data_full: Vec<u8> = vec![0u8; 4 + msg_length_u32 as usize];
+    // waits for the peer's bitfield; peers speaking the extension protocol may
+    // send their extended handshake first
+    async fn receive_bitfield(&mut self) -> Result<(), ClientError> {
+        loop {
+            match self.read_client().await? {
+                message::Message::Bitfield(array) => {
+                    self.bitfield = bitfield::Bitfield { array };
+                    return Ok(());
+                }
+                message::Message::Extended { id, payload } => {
+                    self.handle_extended_handshake(id, &payload);
+                }
+                message::Message::KeepAlive | message::Message::Unknown(_) => {}
+                _ => return Err(ClientError::BitfieldFailure(self.addr())),
+            }
+        }
+    }
 
-                        // copy length's 4 bytes into msg_data_full
-                        for (index, item) in msg_length.iter().enumerate() {
-                            msg_data_full[index] = *item;
-                        }
+    // remembers what the peer told us in its extended handshake
+    pub fn handle_extended_handshake(&mut self, id: u8, payload: &[u8]) {
+        if let Some(hs) = extension::parse_handshake(id, payload) {
+            if let Some(reqq) = hs.reqq {
+                self.pipeline.set_reqq(reqq);
+            }
+            self.extended_handshake = Some(hs);
+        }
+    }
 
-                        // copy data bytes into msg_data_full, after length's bytes
-                        for (index, item) in msg_data.iter().enumerate() {
-                            msg_data_full[4 + index] = *item;
-                        }
+    // handles an extended message from the peer: its handshake, the peers it
+    // exchanges with us, or a request for a piece of our metadata
+    pub async fn handle_extended(&mut self, id: u8, payload: &[u8]) -> Result<(), ClientError> {
+        if id == extension::EXT_HANDSHAKE {
+            self.handle_extended_handshake(id, payload);
+            return Ok(());
+        }
+        if id == extension::UT_PEX_ID {
+            if self.pex {
+                self.pex_peers.append(&mut extension::parse_pex(payload));
+            }
+            return Ok(());
+        }
+        if id != extension::UT_METADATA_ID {
+            return Ok(());
+        }
+        let msg = match extension::parse_metadata_message(payload) {
+            Some((msg, _)) if msg.msg_type == extension::METADATA_REQUEST => msg,
+            _ => return Ok(()),
+        };
+        let reply_id = match self
+            .extended_handshake
+            .as_ref()
+            .and_then(|h| h.ut_metadata())
+        {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let metadata = self.metadata.clone().unwrap_or_default();
+        let msg = message::Message::Extended {
+            id: reply_id,
+            payload: extension::new_metadata_reply(msg.piece, &metadata),
+        };
+        self.send_message(msg).await
+    }
 
-                        // return msg into message struct
-                        let msg_struct = message::new_message(msg_data_full);
-                        Some(msg_struct)
-                    }
-                    Err(e) => {
-                        println!("{}: Unable to read message content: {}", self.peer.ip, e);
-                        None
+    // reads from the client until a whole message has arrived
+    pub async fn read_client(&mut self) -> Result<message::Message, ClientError> {
+        let mut chunk = vec![0u8; 32768];
+        loop {
+            match self.decoder.decode() {
+                Ok(Some(msg)) => {
+                    // only block payload counts against the rate limits
+                    if let message::Message::Piece { block, .. } = &msg {
+                        self.download_throttle.wait(block.len() as u64).await;
                     }
+                    return Ok(msg);
                 }
+                Ok(None) => {}
+                Err(e) => return Err(ClientError::MessageFailure(self.addr(), e)),
             }
-            Err(e) => {
-                println!("{}: Unable to read message length: {}", self.peer.ip, e);
-                self.conn.shutdown(Shutdown::Both).unwrap();
-                None
+
+            // a timeout leaves the connection usable
+            let read = timeout(IO_TIMEOUT, self.conn.read(&mut chunk)).await;
+            match io_result(self.addr(), read) {
+                Ok(0) => return Err(ClientError::ConnectionClosed(self.addr())),
+                Ok(n) => self.decoder.extend(&chunk[..n]),
+                Err(e) => {
+                    if let ClientError::ConnectionFailure(..) = e {
+                        let _ = self.conn.shutdown(Shutdown::Both);
+                    }
+                    return Err(e);
+                }
             }
         }
     }
 
-    pub fn send_request(&mut self, index: i64, begin: i64, length: i64) -> Option<ClientError> {
-        let req = message::format_request(index, begin, length);
-        match self.conn.write(&req.serialize()) {
-            Ok(_) => None,
-            Err(e) => {
-                println!("Error on send_request: {}", e);
-                Some(ClientError::MessageFailure)
-            }
-        };
-        None
+    pub async fn send_message(&mut self, msg: message::Message) -> Result<(), ClientError> {
+        let written = timeout(IO_TIMEOUT, self.conn.write_all(&msg.serialize())).await;
+        io_result(self.addr(), written)
     }
 
-    pub fn send_have(&mut self, index: i64) -> Option<ClientError> {
-        let req = message::format_have(index);
-        match self.conn.write(&req.serialize()) {
-            Ok(_) => None,
-            Err(e) => {
-                println!("Error on send_have: {}", e);
-                Some(ClientError::MessageFailure)
-            }
+    pub async fn send_request(
+        &mut self,
+        index: i64,
+        begin: i64,
+        length: i64,
+    ) -> Result<(), ClientError> {
+        let req = message::Message::Request {
+            index: index as u32,
+            begin: begin as u32,
+            length: length as u32,
         };
-        None
+        self.send_message(req).await
     }
 
-    pub fn send_unchoke(&mut self) -> Option<ClientError> {
-        let msg = message::Message {
-            id: message::MSG_UNCHOKE,
-            payload: vec![],
+    pub async fn send_have(&mut self, index: i64) -> Result<(), ClientError> {
+        self.send_message(message::Message::Have(index as u32))
+            .await
+    }
+
+    // sends a block the peer asked for
+    pub async fn send_piece(
+        &mut self,
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    ) -> Result<(), ClientError> {
+        self.upload_throttle.wait(block.len() as u64).await;
+        self.uploaded += block.len() as i64;
+        let msg = message::Message::Piece {
+            index,
+            begin,
+            block,
         };
-        match self.conn.write(&msg.serialize()) {
-            Ok(_) => None,
-            Err(e) => {
-                println!("Error on send_unchoke: {}", e);
-                Some(ClientError::MessageFailure)
-            }
+        self.send_message(msg).await
+    }
+
+    // answers a hash request, or rejects it when we can't
+    pub async fn send_hashes(
+        &mut self,
+        range: message::HashRange,
+        hashes: Option<Vec<[u8; 32]>>,
+    ) -> Result<(), ClientError> {
+        let msg = match hashes {
+            Some(hashes) => message::Message::Hashes(range, hashes),
+            None => message::Message::HashReject(range),
         };
-        None
+        self.send_message(msg).await
+    }
+
+    pub async fn send_bitfield(
+        &mut self,
+        bitfield: &bitfield::Bitfield,
+    ) -> Result<(), ClientError> {
+        let msg = message::Message::Bitfield(bitfield.array.clone());
+        self.send_message(msg).await
     }
 
-    pub fn send_interested(&mut self) -> Option<ClientError> {
-        let msg = message::Message {
-            id: message::MSG_INTERESTED,
-            payload: vec![],
+    pub async fn send_choke(&mut self) -> Result<(), ClientError> {
+        // a choked peer's pending requests are dropped
+        self.am_choking = true;
+        self.peer_requests.clear();
+        self.send_message(message::Message::Choke).await
+    }
+
+    pub async fn send_unchoke(&mut self) -> Result<(), ClientError> {
+        self.am_choking = false;
+        self.send_message(message::Message::Unchoke).await
+    }
+
+    pub async fn send_interested(&mut self) -> Result<(), ClientError> {
+        self.send_message(message::Message::Interested).await
+    }
+
+    // whether we exchange peers with the peer: both sides have to want it
+    pub fn speaks_pex(&self) -> bool {
+        self.pex
+            && self
+                .extended_handshake
+                .as_ref()
+                .and_then(|h| h.ut_pex())
+                .is_some()
+    }
+
+    pub async fn send_pex(
+        &mut self,
+        added: &[SocketAddr],
+        dropped: &[SocketAddr],
+    ) -> Result<(), ClientError> {
+        let id = match self.extended_handshake.as_ref().and_then(|h| h.ut_pex()) {
+            Some(id) if self.pex => id,
+            _ => return Ok(()),
         };
-        match self.conn.write(&msg.serialize()) {
-            Ok(_) => None,
-            Err(e) => {
-                println!("Error on send_interested: {}", e);
-                Some(ClientError::MessageFailure)
-            }
+        let msg = message::Message::Extended {
+            id,
+            payload: extension::new_pex_message(added, dropped),
         };
-        None
+        self.send_message(msg).await
     }
 }
@@ -0,0 +1,391 @@
+use serde_bencode::ser;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::files;
+use crate::torrent::{BencodeInfo, BencodeTorrent, File, UrlList};
+
+const MIN_PIECE_LENGTH: i64 = 16 * 1024;
+const MAX_PIECE_LENGTH: i64 = 16 * 1024 * 1024;
+// automatic piece lengths aim for about this many pieces
+const TARGET_PIECES: i64 = 1500;
+
+// CreateOptions describe the torrent built from a file or directory; the
+// defaults leave out everything that changes between runs, like the date
+#[derive(Debug, Clone)]
+pub struct CreateOptions {
+    // trackers in tiers; the first one becomes `announce`, and all of them
+    // go in the announce-list when there is more than one
+    pub trackers: Vec<Vec<String>>,
+    pub web_seeds: Vec<String>,
+    // bytes per piece, a power of two; None picks one from the total size
+    pub piece_length: Option<i64>,
+    pub private: bool,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    // seconds since the epoch
+    pub creation_date: Option<i64>,
+    // files and directories whose name starts with a dot are left out unless
+    // `include_hidden` is set
+    pub include_hidden: bool,
+    // globs matched against paths inside the torrent and against names
+    pub exclude: Vec<String>,
+    // threads hashing pieces
+    pub threads: usize,
+}
+
+#[derive(Debug)]
+pub enum CreateError {
+    ReadFailure(io::Error),
+    InvalidPath,
+    InvalidPieceLength,
+    NoTrackers,
+    NoFiles,
+}
+
+impl fmt::Display for CreateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CreateError::ReadFailure(e) => write!(f, "couldn't read files: {}", e),
+            CreateError::InvalidPath => write!(f, "path has no usable UTF-8 name"),
+            CreateError::InvalidPieceLength => {
+                write!(f, "piece length must be a power of two")
+            }
+            CreateError::NoTrackers => write!(f, "no trackers given"),
+            CreateError::NoFiles => write!(f, "no files to put in the torrent"),
+        }
+    }
+}
+
+impl Error for CreateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CreateError::ReadFailure(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// a file of the torrent: its path inside the torrent and on disk, and where
+// it starts in the concatenation of all files
+struct Source {
+    components: Vec<String>,
+    path: PathBuf,
+    length: i64,
+    offset: i64,
+}
+
+impl Default for CreateOptions {
+    fn default() -> Self {
+        CreateOptions {
+            trackers: vec![],
+            web_seeds: vec![],
+            piece_length: None,
+            private: false,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            include_hidden: false,
+            exclude: vec![],
+            threads: 1,
+        }
+    }
+}
+
+// builds the bencoded .torrent for the file or directory at `path`; files of a
+// directory are taken in path order, so the same input and options always give
+// the same torrent
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> Result<Vec<u8>, CreateError> {
+    let announce = match options.trackers.iter().flatten().next() {
+        Some(announce) => announce.clone(),
+        None => return Err(CreateError::NoTrackers),
+    };
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or(CreateError::InvalidPath)?
+        .to_owned();
+    let metadata = fs::metadata(path).map_err(CreateError::ReadFailure)?;
+    let sources = if metadata.is_dir() {
+        let mut sources = vec![];
+        collect_files(path, &mut vec![], options, &mut sources)?;
+        sources
+    } else {
+        vec![Source {
+            components: vec![],
+            path: path.to_path_buf(),
+            length: metadata.len() as i64,
+            offset: 0,
+        }]
+    };
+    if sources.is_empty() {
+        return Err(CreateError::NoFiles);
+    }
+
+    let total: i64 = sources.iter().map(|s| s.length).sum();
+    let piece_length = match options.piece_length {
+        Some(length) if length > 0 && (length as u64).is_power_of_two() => length,
+        Some(_) => return Err(CreateError::InvalidPieceLength),
+        None => auto_piece_length(total),
+    };
+    let pieces = hash_pieces(&sources, total, piece_length, options.threads)?;
+
+    let (length, files) = if metadata.is_dir() {
+        let files = sources
+            .into_iter()
+            .map(|s| File {
+                path: s.components,
+                length: s.length,
+                md5sum: None,
+                attr: None,
+            })
+            .collect();
+        (None, Some(files))
+    } else {
+        (Some(total), None)
+    };
+    let announce_list = if options.trackers.iter().flatten().count() > 1 {
+        Some(options.trackers.clone())
+    } else {
+        None
+    };
+    let url_list = if options.web_seeds.is_empty() {
+        None
+    } else {
+        Some(UrlList::Many(options.web_seeds.clone()))
+    };
+
+    let torrent = BencodeTorrent {
+        info: BencodeInfo {
+            name,
+            pieces: ByteBuf::from(pieces),
+            piece_length,
+            md5sum: None,
+            length,
+            files,
+            private: if options.private { Some(1) } else { None },
+            path: None,
+            root_hash: None,
+            meta_version: None,
+            file_tree: None,
+            raw: vec![],
+        },
+        announce: Some(announce),
+        nodes: None,
+        encoding: None,
+        httpseeds: None,
+        piece_layers: None,
+        url_list,
+        announce_list,
+        creation_date: options.creation_date,
+        comment: options.comment.clone(),
+        created_by: options.created_by.clone(),
+    };
+    Ok(ser::to_bytes(&torrent).unwrap())
+}
+
+// the smallest power of two that keeps the piece count near TARGET_PIECES
+fn auto_piece_length(total: i64) -> i64 {
+    let mut length = MIN_PIECE_LENGTH;
+    while length < MAX_PIECE_LENGTH && total / length > TARGET_PIECES {
+        length *= 2;
+    }
+    length
+}
+
+fn excluded(name: &str, components: &[String], options: &CreateOptions) -> bool {
+    if !options.include_hidden && name.starts_with('.') {
+        return true;
+    }
+    let path = components.join("/");
+    options.exclude.iter().any(|glob| {
+        files::glob_match(glob.as_bytes(), name.as_bytes())
+            || files::glob_match(glob.as_bytes(), path.as_bytes())
+    })
+}
+
+fn collect_files(
+    dir: &Path,
+    prefix: &mut Vec<String>,
+    options: &CreateOptions,
+    sources: &mut Vec<Source>,
+) -> Result<(), CreateError> {
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .map_err(CreateError::ReadFailure)?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|_| CreateError::InvalidPath)?;
+        prefix.push(name);
+        if excluded(&prefix[prefix.len() - 1], prefix, options) {
+            prefix.pop();
+            continue;
+        }
+
+        let path = entry.path();
+        let metadata = fs::metadata(&path).map_err(CreateError::ReadFailure)?;
+        if metadata.is_dir() {
+            collect_files(&path, prefix, options, sources)?;
+        } else {
+            let offset = sources.last().map_or(0, |s: &Source| s.offset + s.length);
+            sources.push(Source {
+                components: prefix.clone(),
+                path,
+                length: metadata.len() as i64,
+                offset,
+            });
+        }
+        prefix.pop();
+    }
+    Ok(())
+}
+
+// hashes the concatenation of all files in pieces of `piece_length` bytes;
+// every thread hashes its own run of consecutive pieces
+fn hash_pieces(
+    sources: &[Source],
+    total: i64,
+    piece_length: i64,
+    threads: usize,
+) -> Result<Vec<u8>, CreateError> {
+    let num_pieces = ((total + piece_length - 1) / piece_length) as usize;
+    let threads = threads.clamp(1, num_pieces.max(1));
+    let hashes = Mutex::new(vec![[0u8; 20]; num_pieces]);
+    let errors = Mutex::new(vec![]);
+
+    crossbeam::scope(|scope| {
+        for k in 0..threads {
+            let (hashes, errors) = (&hashes, &errors);
+            let range = k * num_pieces / threads..(k + 1) * num_pieces / threads;
+            scope.spawn(move |_| {
+                let mut buf = vec![];
+                for index in range {
+                    let begin = index as i64 * piece_length;
+                    let end = (begin + piece_length).min(total);
+                    if let Err(e) = read_range(sources, begin, end, &mut buf) {
+                        errors.lock().unwrap().push(e);
+                        return;
+                    }
+                    let mut hash = [0u8; 20];
+                    hash.copy_from_slice(Sha1::digest(&buf).as_slice());
+                    hashes.lock().unwrap()[index] = hash;
+                }
+            });
+        }
+    })
+    .unwrap();
+
+    if let Some(e) = errors.into_inner().unwrap().pop() {
+        return Err(CreateError::ReadFailure(e));
+    }
+    Ok(hashes.into_inner().unwrap().concat())
+}
+
+// reads the bytes between the torrent-wide offsets `begin` and `end` into buf
+fn read_range(sources: &[Source], begin: i64, end: i64, buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.resize((end - begin) as usize, 0);
+    for s in sources {
+        if s.offset >= end || s.offset + s.length <= begin {
+            continue;
+        }
+        let start = begin.max(s.offset);
+        let stop = end.min(s.offset + s.length);
+
+        let mut file = fs::File::open(&s.path)?;
+        file.seek(SeekFrom::Start((start - s.offset) as u64))?;
+        file.read_exact(&mut buf[(start - begin) as usize..(stop - begin) as usize])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::torrent;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("herb-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("ds/sub")).unwrap();
+        fs::write(dir.join("ds/b.bin"), vec![1u8; 40000]).unwrap();
+        fs::write(dir.join("ds/sub/a.txt"), b"hello").unwrap();
+        dir
+    }
+
+    fn options() -> super::CreateOptions {
+        super::CreateOptions {
+            trackers: vec![vec!["http://t.example/announce".to_owned()]],
+            piece_length: Some(16384),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn created_torrent_matches_files() {
+        let dir = scratch("create");
+        let data = super::create_torrent(&dir.join("ds"), &options()).unwrap();
+
+        let t = torrent::new_torrent(&torrent::parse_torrent(&data).unwrap()).unwrap();
+        assert_eq!(t.name, "ds");
+        assert_eq!(t.length, 40005);
+        assert_eq!(t.piece_hashes.len(), 3);
+        assert_eq!(t.files[1].path, PathBuf::from("ds/sub/a.txt"));
+        assert!(crate::verify_pieces(&dir, &t).iter().all(|ok| *ok));
+
+        fs::write(dir.join("ds/sub/a.txt"), b"HELLO").unwrap();
+        assert_eq!(crate::verify_pieces(&dir, &t), vec![true, true, false]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn output_does_not_depend_on_threads() {
+        let dir = scratch("threads");
+        let one = super::create_torrent(&dir.join("ds"), &options()).unwrap();
+        let many = super::CreateOptions {
+            threads: 4,
+            ..options()
+        };
+        assert_eq!(one, super::create_torrent(&dir.join("ds"), &many).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hidden_and_excluded_paths_are_left_out() {
+        let dir = scratch("exclude");
+        fs::write(dir.join("ds/.hidden"), b"x").unwrap();
+        fs::write(dir.join("ds/sub/c.log"), b"log").unwrap();
+        let options = super::CreateOptions {
+            exclude: vec!["*.log".to_owned()],
+            private: true,
+            trackers: vec![
+                vec!["http://a.example/announce".to_owned()],
+                vec!["http://b.example/announce".to_owned()],
+            ],
+            ..options()
+        };
+
+        let data = super::create_torrent(&dir.join("ds"), &options).unwrap();
+        let b = torrent::parse_torrent(&data).unwrap();
+        assert_eq!(b.info.private, Some(1));
+        assert_eq!(b.announce_list.as_ref().map(|l| l.len()), Some(2));
+        let t = torrent::new_torrent(&b).unwrap();
+        let paths: Vec<PathBuf> = t.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![PathBuf::from("ds/b.bin"), PathBuf::from("ds/sub/a.txt")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
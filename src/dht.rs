@@ -0,0 +1,992 @@
+use crossbeam::channel::{self, Sender};
+use serde_bencode::value::Value;
+use serde_bencode::{de, ser};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::p2p;
+use crate::peer_id;
+use crate::proxy;
+
+// the nodes every bucket of the routing table holds, K in BEP 5
+const BUCKET_SIZE: usize = 8;
+
+// how long we wait for the answers to a round of queries
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// a lookup gives up after this many rounds even if it still gets closer
+const MAX_ROUNDS: usize = 10;
+
+// a node that didn't answer this many queries in a row can be replaced
+const MAX_FAILURES: u32 = 2;
+
+// tokens stay valid while the secret they were made with is the current or
+// the previous one
+const SECRET_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+// announced peers are forgotten after this long, and we keep a bounded
+// number of them so nobody can fill our memory
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+const MAX_PEERS_PER_TORRENT: usize = 100;
+const MAX_TORRENTS: usize = 1000;
+
+// how often we look up our own id to keep the routing table fresh, and how
+// soon we try again while the table is empty
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const BOOTSTRAP_RETRY: Duration = Duration::from_secs(60);
+
+// the largest datagram we read
+const MAX_PACKET_SIZE: usize = 1500;
+
+// well-known nodes a fresh node joins the DHT through
+pub const DEFAULT_ROUTERS: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+// Krpc is every message of the DHT protocol: a query, its response or an
+// error; only the fields of the kind in `y` are set
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Krpc {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    a: Option<Arguments>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e: Option<Value>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    r: Option<Reply>,
+    t: ByteBuf,
+    y: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Arguments {
+    id: ByteBuf,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    implied_port: Option<i64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<i64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    // the families of nodes the querier wants, "n4" and "n6" (BEP 32)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    want: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Reply {
+    id: ByteBuf,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nodes6: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+}
+
+// Contact is a node of the routing table
+#[derive(Debug, Clone)]
+struct Contact {
+    id: [u8; 20],
+    addr: SocketAddr,
+    failures: u32,
+}
+
+// RoutingTable sorts the nodes we know by how many leading bits their id
+// shares with ours; each bucket keeps the nodes it had for as long as they
+// answer, since long-lived nodes are the most likely to stay
+struct RoutingTable {
+    own: [u8; 20],
+    buckets: Vec<Vec<Contact>>,
+}
+
+// Secrets make the tokens of get_peers, so that only nodes that asked us can
+// announce from their address
+struct Secrets {
+    current: u64,
+    previous: u64,
+    changed: Instant,
+}
+
+// Stored is a peer that announced itself to us
+struct Stored {
+    addr: SocketAddr,
+    announced: Instant,
+}
+
+// Socket is how the node reaches other nodes: directly, or through the
+// relay of a SOCKS5 proxy, which carries both address families
+pub enum Socket {
+    Direct(UdpSocket),
+    Proxied(proxy::UdpRelay),
+}
+
+struct Node {
+    id: [u8; 20],
+    sockets: Vec<Socket>,
+    routers: Vec<String>,
+    // the IPv4 nodes we know and the IPv6 ones, which BEP 32 keeps apart
+    tables: [Mutex<RoutingTable>; 2],
+    // the queries waiting for an answer, by transaction id
+    pending: Mutex<HashMap<[u8; 2], Pending>>,
+    next_transaction: AtomicU16,
+    secrets: Mutex<Secrets>,
+    peers: Mutex<HashMap<[u8; 20], Vec<Stored>>>,
+    stopped: AtomicBool,
+}
+
+// Answer is what a node replied to one of our queries; errors have no reply
+type Answer = (SocketAddr, Option<Reply>);
+
+// Pending is a query we sent: the node it went to and where its answer goes
+struct Pending {
+    to: SocketAddr,
+    answers: Sender<Answer>,
+}
+
+// Dht is the DHT node (BEP 5, and BEP 32 for IPv6) of a session, shared by all of its torrents;
+// the node stops when it is dropped
+pub struct Dht {
+    node: Arc<Node>,
+}
+
+fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut d = [0u8; 20];
+    for i in 0..20 {
+        d[i] = a[i] ^ b[i];
+    }
+    d
+}
+
+// the routing table, and the want of a query, of an address's family
+fn family(addr: &SocketAddr) -> usize {
+    addr.is_ipv6() as usize
+}
+
+const WANTS: [&str; 2] = ["n4", "n6"];
+
+fn to_id(bytes: &[u8]) -> Option<[u8; 20]> {
+    bytes.try_into().ok()
+}
+
+fn random_id() -> [u8; 20] {
+    let mut id = [0u8; 20];
+    for chunk in id.chunks_mut(8) {
+        let random = peer_id::random_u64().to_be_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+    id
+}
+
+// the compact form of an address: the IP followed by the port, both big
+// endian
+fn compact_addr(addr: &SocketAddr) -> Vec<u8> {
+    let mut buf = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    buf
+}
+
+fn parse_addr(buf: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = buf.split_at(buf.len().checked_sub(2)?);
+    let ip = match *ip {
+        [a, b, c, d] => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+        _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?)),
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+// the nodes of a compact node list: 20 bytes of id, then a compact address of
+// `ip_len` bytes
+fn parse_nodes(buf: &[u8], ip_len: usize) -> Vec<([u8; 20], SocketAddr)> {
+    buf.chunks_exact(20 + ip_len + 2)
+        .filter_map(|node| {
+            let (id, addr) = node.split_at(20);
+            Some((to_id(id)?, parse_addr(addr)?))
+        })
+        .filter(|(_, addr)| addr.port() != 0)
+        .collect()
+}
+
+fn compact_nodes(contacts: &[Contact]) -> Vec<u8> {
+    let mut buf = vec![];
+    for c in contacts {
+        buf.extend_from_slice(&c.id);
+        buf.extend_from_slice(&compact_addr(&c.addr));
+    }
+    buf
+}
+
+fn new_routing_table(own: [u8; 20]) -> RoutingTable {
+    RoutingTable {
+        own,
+        buckets: vec![vec![]; 160],
+    }
+}
+
+impl RoutingTable {
+    // the bucket of `id`: how many leading bits it shares with our id
+    fn bucket(&self, id: &[u8; 20]) -> Option<usize> {
+        let d = distance(&self.own, id);
+        let zeros = d
+            .iter()
+            .position(|b| *b != 0)
+            .map(|i| i * 8 + d[i].leading_zeros() as usize)?;
+        Some(zeros)
+    }
+
+    // a node that answered or asked us something; it takes the place of a
+    // node that stopped answering when its bucket is full
+    fn heard_from(&mut self, id: [u8; 20], addr: SocketAddr) {
+        let index = match self.bucket(&id) {
+            Some(index) => index,
+            None => return,
+        };
+        let bucket = &mut self.buckets[index];
+        if let Some(c) = bucket.iter_mut().find(|c| c.id == id) {
+            c.addr = addr;
+            c.failures = 0;
+            return;
+        }
+        let contact = Contact {
+            id,
+            addr,
+            failures: 0,
+        };
+        if bucket.len() < BUCKET_SIZE {
+            bucket.push(contact);
+        } else if let Some(stale) = bucket.iter_mut().find(|c| c.failures >= MAX_FAILURES) {
+            *stale = contact;
+        }
+    }
+
+    fn failed(&mut self, addr: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            if let Some(c) = bucket.iter_mut().find(|c| c.addr == *addr) {
+                c.failures += 1;
+            }
+        }
+    }
+
+    // up to `n` nodes closest to `target`, leaving out those that stopped
+    // answering
+    fn closest(&self, target: &[u8; 20], n: usize) -> Vec<Contact> {
+        let mut contacts: Vec<Contact> = self
+            .buckets
+            .iter()
+            .flatten()
+            .filter(|c| c.failures < MAX_FAILURES)
+            .cloned()
+            .collect();
+        contacts.sort_by_key(|c| distance(&c.id, target));
+        contacts.truncate(n);
+        contacts
+    }
+
+    fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+}
+
+// starts a node on `sockets`, one for each address family it talks to, that
+// joins the DHT through `routers`
+pub fn start_dht(sockets: Vec<Socket>, routers: Vec<String>) -> io::Result<Dht> {
+    for socket in sockets.iter() {
+        // so the receivers notice when the node stops
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+    }
+    let id = random_id();
+    let node = Arc::new(Node {
+        id,
+        sockets,
+        routers,
+        tables: [
+            Mutex::new(new_routing_table(id)),
+            Mutex::new(new_routing_table(id)),
+        ],
+        pending: Mutex::new(HashMap::new()),
+        next_transaction: AtomicU16::new(peer_id::random_u64() as u16),
+        secrets: Mutex::new(Secrets {
+            current: peer_id::random_u64(),
+            previous: peer_id::random_u64(),
+            changed: Instant::now(),
+        }),
+        peers: Mutex::new(HashMap::new()),
+        stopped: AtomicBool::new(false),
+    });
+    for index in 0..node.sockets.len() {
+        let node = Arc::clone(&node);
+        thread::spawn(move || node.receive(index));
+    }
+    {
+        let node = Arc::clone(&node);
+        thread::spawn(move || node.refresh());
+    }
+    Ok(Dht { node })
+}
+
+impl Socket {
+    // whether `addr` is of a family the socket talks to
+    fn reaches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Socket::Direct(socket) => socket
+                .local_addr()
+                .is_ok_and(|local| local.is_ipv4() == addr.is_ipv4()),
+            Socket::Proxied(_) => true,
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        match self {
+            Socket::Direct(socket) => socket.send_to(buf, addr),
+            Socket::Proxied(relay) => relay.send_to(buf, addr),
+        }
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self {
+            Socket::Direct(socket) => socket.recv_from(buf),
+            Socket::Proxied(relay) => relay.recv_from(buf),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Socket::Direct(socket) => socket.set_read_timeout(timeout),
+            Socket::Proxied(relay) => relay.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Dht {
+    // the peers the DHT knows for `info_hash`; with `announce_port` we also
+    // tell the closest nodes that we are a peer ourselves
+    pub fn get_peers(&self, info_hash: [u8; 20], announce_port: Option<u16>) -> Vec<p2p::Peer> {
+        self.node.get_peers(info_hash, announce_port)
+    }
+
+    // how many nodes the routing tables hold
+    pub fn nodes(&self) -> usize {
+        self.node
+            .tables
+            .iter()
+            .map(|t| t.lock().unwrap().len())
+            .sum()
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        self.node.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Node {
+    fn receive(&self, index: usize) {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
+        while !self.stopped.load(Ordering::Relaxed) {
+            // timeouts and ICMP errors of earlier sends end up here too
+            if let Ok((n, from)) = self.sockets[index].recv_from(&mut buf) {
+                self.handle(&buf[..n], from);
+            }
+        }
+    }
+
+    // joins the DHT and keeps the routing tables filled, each family on its
+    // own schedule since one may have no nodes to find
+    fn refresh(&self) {
+        let mut last_refresh: [Option<Instant>; 2] = [None, None];
+        while !self.stopped.load(Ordering::Relaxed) {
+            let families: Vec<usize> = self.families().collect();
+            for f in families {
+                let interval = if self.tables[f].lock().unwrap().len() == 0 {
+                    BOOTSTRAP_RETRY
+                } else {
+                    REFRESH_INTERVAL
+                };
+                if last_refresh[f].is_none_or(|t| t.elapsed() >= interval) {
+                    self.bootstrap(f);
+                    last_refresh[f] = Some(Instant::now());
+                }
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    // looks up our own id among the nodes of family `f`, starting from the
+    // routers when we know no node, which fills the buckets close to us
+    fn bootstrap(&self, f: usize) {
+        let id = self.id;
+        self.lookup(&id, "find_node", f);
+    }
+
+    fn socket_for(&self, addr: &SocketAddr) -> Option<&Socket> {
+        self.sockets.iter().find(|s| s.reaches(addr))
+    }
+
+    // the families we have a socket for
+    fn families(&self) -> impl Iterator<Item = usize> + '_ {
+        let any = [
+            SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        ];
+        (0..2).filter(move |f| self.socket_for(&any[*f]).is_some())
+    }
+
+    fn heard_from(&self, id: [u8; 20], addr: SocketAddr) {
+        self.tables[family(&addr)]
+            .lock()
+            .unwrap()
+            .heard_from(id, addr);
+    }
+
+    fn send(&self, msg: &Krpc, to: SocketAddr) -> bool {
+        let data = match ser::to_bytes(msg) {
+            Ok(data) => data,
+            Err(_) => return false,
+        };
+        match self.socket_for(&to) {
+            Some(socket) => socket.send_to(&data, to).is_ok(),
+            None => false,
+        }
+    }
+
+    fn handle(&self, data: &[u8], from: SocketAddr) {
+        let msg: Krpc = match de::from_bytes(data) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        match msg.y.as_str() {
+            "q" => self.answer(msg, from),
+            "r" | "e" => {
+                let t = match <[u8; 2]>::try_from(&msg.t[..]) {
+                    Ok(t) => t,
+                    Err(_) => return,
+                };
+                let mut pending = self.pending.lock().unwrap();
+                // answers only count from the node we asked
+                if pending.get(&t).is_some_and(|p| p.to == from) {
+                    let p = pending.remove(&t).unwrap();
+                    let _ = p.answers.send((from, msg.r));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn token(&self, ip: &IpAddr, secret: u64) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.input(secret.to_be_bytes());
+        match ip {
+            IpAddr::V4(ip) => hasher.input(ip.octets()),
+            IpAddr::V6(ip) => hasher.input(ip.octets()),
+        }
+        hasher.result()[..8].to_vec()
+    }
+
+    fn secrets(&self) -> (u64, u64) {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.changed.elapsed() >= SECRET_LIFETIME {
+            secrets.previous = secrets.current;
+            secrets.current = peer_id::random_u64();
+            secrets.changed = Instant::now();
+        }
+        (secrets.current, secrets.previous)
+    }
+
+    fn valid_token(&self, ip: &IpAddr, token: &[u8]) -> bool {
+        let (current, previous) = self.secrets();
+        token == &self.token(ip, current)[..] || token == &self.token(ip, previous)[..]
+    }
+
+    // the peers announced to us for `info_hash` of `from`'s family, dropping
+    // the old ones
+    fn stored_peers(&self, info_hash: &[u8; 20], from: &SocketAddr) -> Vec<ByteBuf> {
+        let mut peers = self.peers.lock().unwrap();
+        let stored = match peers.get_mut(info_hash) {
+            Some(stored) => stored,
+            None => return vec![],
+        };
+        stored.retain(|p| p.announced.elapsed() < PEER_LIFETIME);
+        stored
+            .iter()
+            .filter(|p| family(&p.addr) == family(from))
+            .map(|p| ByteBuf::from(compact_addr(&p.addr)))
+            .collect()
+    }
+
+    fn store_peer(&self, info_hash: [u8; 20], addr: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        if !peers.contains_key(&info_hash) && peers.len() >= MAX_TORRENTS {
+            peers.retain(|_, stored| {
+                stored.retain(|p| p.announced.elapsed() < PEER_LIFETIME);
+                !stored.is_empty()
+            });
+            if peers.len() >= MAX_TORRENTS {
+                return;
+            }
+        }
+        let stored = peers.entry(info_hash).or_default();
+        stored.retain(|p| p.addr != addr);
+        if stored.len() >= MAX_PEERS_PER_TORRENT {
+            stored.remove(0);
+        }
+        stored.push(Stored {
+            addr,
+            announced: Instant::now(),
+        });
+    }
+
+    fn send_error(&self, t: ByteBuf, to: SocketAddr, code: i64, message: &str) {
+        let msg = Krpc {
+            t,
+            y: "e".to_owned(),
+            e: Some(Value::List(vec![
+                Value::Int(code),
+                Value::Bytes(message.as_bytes().to_vec()),
+            ])),
+            ..Default::default()
+        };
+        self.send(&msg, to);
+    }
+
+    // answers the query of another node
+    fn answer(&self, msg: Krpc, from: SocketAddr) {
+        let args = match msg.a {
+            Some(args) => args,
+            None => return self.send_error(msg.t, from, ERROR_PROTOCOL, "no arguments"),
+        };
+        let id = match to_id(&args.id) {
+            Some(id) => id,
+            None => return self.send_error(msg.t, from, ERROR_PROTOCOL, "invalid id"),
+        };
+        self.heard_from(id, from);
+
+        // without a want, nodes of the querier's family
+        let wants: Vec<usize> = match &args.want {
+            Some(want) => (0..2)
+                .filter(|f| want.iter().any(|w| w == WANTS[*f]))
+                .collect(),
+            None => vec![family(&from)],
+        };
+        let mut reply = Reply {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        let target = args
+            .target
+            .as_ref()
+            .or(args.info_hash.as_ref())
+            .and_then(|t| to_id(t));
+        match (msg.q.as_deref(), target) {
+            (Some("ping"), _) => {}
+            (Some("find_node"), Some(target)) => self.closest_nodes(&mut reply, &target, &wants),
+            (Some("get_peers"), Some(info_hash)) => {
+                let (secret, _) = self.secrets();
+                reply.token = Some(ByteBuf::from(self.token(&from.ip(), secret)));
+                let values = self.stored_peers(&info_hash, &from);
+                if !values.is_empty() {
+                    reply.values = Some(values);
+                }
+                self.closest_nodes(&mut reply, &info_hash, &wants);
+            }
+            (Some("announce_peer"), Some(info_hash)) => {
+                let token_ok = args
+                    .token
+                    .as_ref()
+                    .is_some_and(|token| self.valid_token(&from.ip(), token));
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port)) if port > 0 && port <= u16::MAX as i64 => port as u16,
+                    _ => return self.send_error(msg.t, from, ERROR_PROTOCOL, "invalid port"),
+                };
+                if !token_ok {
+                    return self.send_error(msg.t, from, ERROR_PROTOCOL, "bad token");
+                }
+                self.store_peer(info_hash, SocketAddr::new(from.ip(), port));
+            }
+            (Some("find_node"), None)
+            | (Some("get_peers"), None)
+            | (Some("announce_peer"), None) => {
+                return self.send_error(msg.t, from, ERROR_PROTOCOL, "invalid target")
+            }
+            _ => return self.send_error(msg.t, from, ERROR_METHOD_UNKNOWN, "method unknown"),
+        }
+        let msg = Krpc {
+            t: msg.t,
+            y: "r".to_owned(),
+            r: Some(reply),
+            ..Default::default()
+        };
+        self.send(&msg, from);
+    }
+
+    // puts the nodes closest to `target` of the `wants` families in `reply`
+    fn closest_nodes(&self, reply: &mut Reply, target: &[u8; 20], wants: &[usize]) {
+        for f in wants {
+            let contacts = self.tables[*f].lock().unwrap().closest(target, BUCKET_SIZE);
+            let nodes = Some(ByteBuf::from(compact_nodes(&contacts)));
+            match f {
+                0 => reply.nodes = nodes,
+                _ => reply.nodes6 = nodes,
+            }
+        }
+    }
+
+    // sends the query `q` to every node of `to` and collects the replies
+    // that come in before the timeout; nodes that don't answer count as
+    // failed
+    fn query_all(
+        &self,
+        to: &[SocketAddr],
+        q: &str,
+        args: impl Fn(&SocketAddr) -> Arguments,
+    ) -> Vec<(SocketAddr, Reply)> {
+        let (answers_snd, answers) = channel::unbounded();
+        let mut sent = vec![];
+        for addr in to {
+            let t = self
+                .next_transaction
+                .fetch_add(1, Ordering::Relaxed)
+                .to_be_bytes();
+            let query = Pending {
+                to: *addr,
+                answers: answers_snd.clone(),
+            };
+            self.pending.lock().unwrap().insert(t, query);
+            let msg = Krpc {
+                t: ByteBuf::from(t.to_vec()),
+                y: "q".to_owned(),
+                q: Some(q.to_owned()),
+                a: Some(args(addr)),
+                ..Default::default()
+            };
+            if self.send(&msg, *addr) {
+                sent.push(t);
+            } else {
+                self.pending.lock().unwrap().remove(&t);
+            }
+        }
+
+        let deadline = Instant::now() + QUERY_TIMEOUT;
+        let mut replies = vec![];
+        let mut answered = HashSet::new();
+        while answered.len() < sent.len() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match answers.recv_timeout(left) {
+                Ok((from, reply)) => {
+                    answered.insert(from);
+                    if let Some(reply) = reply {
+                        if let Some(id) = to_id(&reply.id) {
+                            self.heard_from(id, from);
+                            replies.push((from, reply));
+                        }
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+        for t in sent {
+            pending.remove(&t);
+        }
+        drop(pending);
+        for addr in to.iter().filter(|addr| !answered.contains(*addr)) {
+            self.tables[family(addr)].lock().unwrap().failed(addr);
+        }
+        replies
+    }
+
+    // the addresses of the routers of family `f`, for when we know no node
+    // of it yet
+    fn router_addrs(&self, f: usize) -> Vec<SocketAddr> {
+        self.routers
+            .iter()
+            .filter_map(|router| router.to_socket_addrs().ok())
+            .flatten()
+            .filter(|addr| family(addr) == f && self.socket_for(addr).is_some())
+            .collect()
+    }
+
+    // asks the nodes of family `f` closest to `target` with `q`, moving
+    // closer with the nodes every reply names, until no closer node is left
+    // to ask; returns the replies of the nodes that answered, closest first
+    fn lookup(&self, target: &[u8; 20], q: &str, f: usize) -> Vec<([u8; 20], SocketAddr, Reply)> {
+        let mut candidates: HashMap<SocketAddr, [u8; 20]> = self.tables[f]
+            .lock()
+            .unwrap()
+            .closest(target, BUCKET_SIZE)
+            .into_iter()
+            .map(|c| (c.addr, c.id))
+            .collect();
+        let mut queried = HashSet::new();
+        // routers have no id we know, they are asked first and then dropped
+        let mut next: Vec<SocketAddr> = if candidates.is_empty() {
+            self.router_addrs(f)
+        } else {
+            vec![]
+        };
+
+        let mut answered = vec![];
+        for _ in 0..MAX_ROUNDS {
+            let mut closest: Vec<(&SocketAddr, &[u8; 20])> = candidates.iter().collect();
+            closest.sort_by_key(|(_, id)| distance(id, target));
+            next.extend(
+                closest
+                    .iter()
+                    .take(BUCKET_SIZE)
+                    .map(|(addr, _)| **addr)
+                    .filter(|addr| !queried.contains(addr)),
+            );
+            if next.is_empty() {
+                break;
+            }
+            queried.extend(next.iter().copied());
+
+            let replies = self.query_all(&next, q, |_| Arguments {
+                id: ByteBuf::from(self.id.to_vec()),
+                target: Some(ByteBuf::from(target.to_vec())).filter(|_| q == "find_node"),
+                info_hash: Some(ByteBuf::from(target.to_vec())).filter(|_| q == "get_peers"),
+                want: Some(vec![WANTS[f].to_owned()]),
+                ..Default::default()
+            });
+            next.clear();
+            for (from, reply) in replies {
+                let nodes = match f {
+                    0 => reply.nodes.as_ref().map(|nodes| parse_nodes(nodes, 4)),
+                    _ => reply.nodes6.as_ref().map(|nodes| parse_nodes(nodes, 16)),
+                };
+                for (id, addr) in nodes.into_iter().flatten() {
+                    if id != self.id {
+                        candidates.entry(addr).or_insert(id);
+                    }
+                }
+                if let Some(id) = to_id(&reply.id) {
+                    candidates.insert(from, id);
+                    answered.push((id, from, reply));
+                }
+            }
+        }
+        answered.sort_by_key(|(id, _, _)| distance(id, target));
+        answered
+    }
+
+    // looks `info_hash` up among the nodes of each family, since IPv6 peers
+    // announce to IPv6 nodes
+    fn get_peers(&self, info_hash: [u8; 20], announce_port: Option<u16>) -> Vec<p2p::Peer> {
+        let mut found = HashSet::new();
+        for f in self.families() {
+            found.extend(self.get_peers_of(info_hash, announce_port, f));
+        }
+        found
+            .into_iter()
+            .map(|addr| p2p::Peer {
+                ip: addr.ip(),
+                port: addr.port(),
+                id: None,
+            })
+            .collect()
+    }
+
+    fn get_peers_of(
+        &self,
+        info_hash: [u8; 20],
+        announce_port: Option<u16>,
+        f: usize,
+    ) -> HashSet<SocketAddr> {
+        let answered = self.lookup(&info_hash, "get_peers", f);
+
+        let mut found = HashSet::new();
+        for (_, _, reply) in answered.iter() {
+            for value in reply.values.iter().flatten() {
+                if let Some(addr) = parse_addr(value).filter(|addr| addr.port() != 0) {
+                    found.insert(addr);
+                }
+            }
+        }
+
+        // the closest nodes that gave us a token keep us as a peer
+        if let Some(port) = announce_port {
+            let tokens: HashMap<SocketAddr, ByteBuf> = answered
+                .into_iter()
+                .filter_map(|(_, addr, reply)| Some((addr, reply.token?)))
+                .take(BUCKET_SIZE)
+                .collect();
+            let to: Vec<SocketAddr> = tokens.keys().copied().collect();
+            self.query_all(&to, "announce_peer", |addr| Arguments {
+                id: ByteBuf::from(self.id.to_vec()),
+                info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                port: Some(port as i64),
+                token: tokens.get(addr).cloned(),
+                ..Default::default()
+            });
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, UdpSocket};
+    use std::time::{Duration, Instant};
+
+    fn loopback_node(routers: &[SocketAddr]) -> (super::Dht, SocketAddr) {
+        let (dht, addrs) = dual_stack_node(&["127.0.0.1:0"], routers);
+        (dht, addrs[0])
+    }
+
+    // a node with a socket on each of `locals`
+    fn dual_stack_node(locals: &[&str], routers: &[SocketAddr]) -> (super::Dht, Vec<SocketAddr>) {
+        let sockets: Vec<UdpSocket> = locals.iter().map(|l| UdpSocket::bind(l).unwrap()).collect();
+        let addrs = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+        let sockets = sockets.into_iter().map(super::Socket::Direct).collect();
+        let routers = routers.iter().map(|r| r.to_string()).collect();
+        (super::start_dht(sockets, routers).unwrap(), addrs)
+    }
+
+    #[test]
+    fn routing_table_keeps_the_closest_nodes() {
+        let own = [0u8; 20];
+        let mut table = super::new_routing_table(own);
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let id = |first: u8, last: u8| {
+            let mut id = [0u8; 20];
+            id[0] = first;
+            id[19] = last;
+            id
+        };
+        assert_eq!(table.bucket(&own), None);
+        assert_eq!(table.bucket(&id(0x80, 0)), Some(0));
+        assert_eq!(table.bucket(&id(0, 1)), Some(159));
+
+        // the far bucket fills up and only takes nodes that stopped answering
+        for i in 0..9 {
+            table.heard_from(id(0x80, i), addr(1000 + i as u16));
+        }
+        table.heard_from(id(0, 1), addr(2000));
+        assert_eq!(table.len(), 9);
+        for _ in 0..super::MAX_FAILURES {
+            table.failed(&addr(1003));
+        }
+        table.heard_from(id(0x80, 9), addr(1009));
+        assert_eq!(table.len(), 9);
+
+        let closest = table.closest(&id(0x80, 9), 2);
+        assert_eq!(closest[0].addr, addr(1009));
+        assert_eq!(closest[1].addr, addr(1001));
+        assert!(table.closest(&own, 20).iter().all(|c| c.addr != addr(1003)));
+    }
+
+    #[test]
+    fn compact_nodes_round_trip() {
+        let contacts = vec![super::Contact {
+            id: [7u8; 20],
+            addr: SocketAddr::from(([10, 0, 0, 1], 6881)),
+            failures: 0,
+        }];
+        let buf = super::compact_nodes(&contacts);
+        assert_eq!(buf.len(), 26);
+        assert_eq!(
+            super::parse_nodes(&buf, 4),
+            vec![([7u8; 20], contacts[0].addr)]
+        );
+        // trailing garbage and port 0 are dropped
+        let mut buf = buf;
+        buf.extend_from_slice(&[1u8; 20]);
+        buf.extend_from_slice(&[10, 0, 0, 2, 0, 0, 9]);
+        assert_eq!(super::parse_nodes(&buf, 4).len(), 1);
+
+        // nodes6 entries carry 16 byte addresses
+        let contacts = vec![super::Contact {
+            id: [8u8; 20],
+            addr: "[2001:db8::1]:6881".parse().unwrap(),
+            failures: 0,
+        }];
+        let buf = super::compact_nodes(&contacts);
+        assert_eq!(buf.len(), 38);
+        assert_eq!(
+            super::parse_nodes(&buf, 16),
+            vec![([8u8; 20], contacts[0].addr)]
+        );
+    }
+
+    #[test]
+    fn announced_peers_are_found_over_loopback() {
+        let (router, router_addr) = loopback_node(&[]);
+        let (seeder, seeder_addr) = loopback_node(&[router_addr]);
+        let (leecher, _) = loopback_node(&[router_addr]);
+
+        // both join through the router
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while router.nodes() < 2 || seeder.nodes() == 0 || leecher.nodes() == 0 {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+
+        let info_hash = [9u8; 20];
+        assert!(seeder.get_peers(info_hash, Some(6881)).is_empty());
+        let peers = leecher.get_peers(info_hash, None);
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].ip, seeder_addr.ip());
+        assert_eq!(peers[0].port, 6881);
+    }
+
+    #[test]
+    fn ipv6_peers_are_found_through_ipv6_nodes() {
+        let (router, router_addrs) = dual_stack_node(&["127.0.0.1:0", "[::1]:0"], &[]);
+        let (seeder, seeder_addrs) = dual_stack_node(&["[::1]:0"], &router_addrs);
+        let (leecher, _) = dual_stack_node(&["127.0.0.1:0", "[::1]:0"], &router_addrs);
+
+        // the seeder only joins the IPv6 side, the leecher both
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while router.nodes() < 3 || seeder.nodes() == 0 || leecher.nodes() < 2 {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(router.node.tables[0].lock().unwrap().len(), 1);
+        assert_eq!(seeder.node.tables[0].lock().unwrap().len(), 0);
+
+        let info_hash = [10u8; 20];
+        assert!(seeder.get_peers(info_hash, Some(6881)).is_empty());
+        let peers = leecher.get_peers(info_hash, None);
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].ip, seeder_addrs[0].ip());
+        assert_eq!(peers[0].port, 6881);
+    }
+}
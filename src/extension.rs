@@ -0,0 +1,301 @@
+use serde_bencode::{de, ser};
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::p2p;
+use crate::torrent;
+
+// extended message id of the extension protocol handshake (BEP 10)
+pub const EXT_HANDSHAKE: u8 = 0;
+
+// the id we ask peers to use when they send us ut_metadata messages (BEP 9)
+pub const UT_METADATA_ID: u8 = 1;
+
+// the id we ask peers to use when they send us ut_pex messages (BEP 11)
+pub const UT_PEX_ID: u8 = 2;
+
+// a ut_pex message adds at most this many peers of each family
+pub const MAX_PEX_PEERS: usize = 50;
+
+// metadata is exchanged in pieces of 16 KiB
+pub const METADATA_PIECE_SIZE: usize = 16384;
+
+// how many block requests a peer may have queued with us, advertised as `reqq`
+pub const REQQ: i64 = 250;
+
+pub const METADATA_REQUEST: i64 = 0;
+pub const METADATA_DATA: i64 = 1;
+pub const METADATA_REJECT: i64 = 2;
+
+// ExtendedHandshake is the bencoded dictionary a peer sends to tell us which
+// extensions it supports and how many outstanding requests it accepts
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    #[serde(default)]
+    pub m: Option<HashMap<String, i64>>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+// MetadataMessage is the dictionary at the start of every ut_metadata message;
+// data messages carry the metadata piece right after it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetadataMessage {
+    pub msg_type: i64,
+    pub piece: i64,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<i64>,
+}
+
+// PexMessage is a ut_pex message: the compact addresses of the peers the
+// sender connected to and lost since its last one, IPv4 and IPv6 apart
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PexMessage {
+    #[serde(default)]
+    pub added: ByteBuf,
+    #[serde(default)]
+    pub added6: ByteBuf,
+    #[serde(default)]
+    pub dropped: ByteBuf,
+    #[serde(default)]
+    pub dropped6: ByteBuf,
+}
+
+// builds our extended handshake, announcing ut_metadata, ut_pex unless the
+// torrent is private, and our request queue
+pub fn new_handshake_payload(metadata_size: Option<i64>, pex: bool) -> Vec<u8> {
+    let mut m = HashMap::new();
+    m.insert("ut_metadata".to_owned(), UT_METADATA_ID as i64);
+    if pex {
+        m.insert("ut_pex".to_owned(), UT_PEX_ID as i64);
+    }
+    let hs = ExtendedHandshake {
+        m: Some(m),
+        reqq: Some(REQQ),
+        metadata_size,
+    };
+    ser::to_bytes(&hs).unwrap()
+}
+
+// answers a ut_metadata request with the piece of `metadata`, or rejects it if
+// there is no such piece
+pub fn new_metadata_reply(piece: i64, metadata: &[u8]) -> Vec<u8> {
+    let begin = piece.max(0) as usize * METADATA_PIECE_SIZE;
+    if piece < 0 || begin >= metadata.len() {
+        let msg = MetadataMessage {
+            msg_type: METADATA_REJECT,
+            piece,
+            total_size: None,
+        };
+        return ser::to_bytes(&msg).unwrap();
+    }
+    let end = metadata.len().min(begin + METADATA_PIECE_SIZE);
+    let msg = MetadataMessage {
+        msg_type: METADATA_DATA,
+        piece,
+        total_size: Some(metadata.len() as i64),
+    };
+    let mut payload = ser::to_bytes(&msg).unwrap();
+    payload.extend_from_slice(&metadata[begin..end]);
+    payload
+}
+
+// parses an extended message, returning the handshake if it is one
+pub fn parse_handshake(id: u8, payload: &[u8]) -> Option<ExtendedHandshake> {
+    if id != EXT_HANDSHAKE {
+        return None;
+    }
+    de::from_bytes::<ExtendedHandshake>(payload).ok()
+}
+
+impl ExtendedHandshake {
+    // the id the peer wants its ut_metadata messages sent with
+    pub fn ut_metadata(&self) -> Option<u8> {
+        self.extension_id("ut_metadata")
+    }
+
+    // the id the peer wants its ut_pex messages sent with
+    pub fn ut_pex(&self) -> Option<u8> {
+        self.extension_id("ut_pex")
+    }
+
+    fn extension_id(&self, name: &str) -> Option<u8> {
+        match self.m.as_ref()?.get(name) {
+            Some(id) if *id > 0 && *id < 256 => Some(*id as u8),
+            _ => None,
+        }
+    }
+}
+
+// the peers a ut_pex message adds, of both families; malformed lists and
+// anything beyond MAX_PEX_PEERS of a family are dropped
+pub fn parse_pex(payload: &[u8]) -> Vec<p2p::Peer> {
+    let msg = match de::from_bytes::<PexMessage>(payload) {
+        Ok(msg) => msg,
+        Err(_) => return vec![],
+    };
+    let mut peers = vec![];
+    for (added, ip_len) in [(&msg.added, 4), (&msg.added6, 16)].iter() {
+        let mut added = torrent::compact_peers(added, *ip_len).unwrap_or_default();
+        added.retain(|p| p.port != 0);
+        added.truncate(MAX_PEX_PEERS);
+        peers.append(&mut added);
+    }
+    peers
+}
+
+// builds a ut_pex message from the peers we connected to and lost
+pub fn new_pex_message(added: &[SocketAddr], dropped: &[SocketAddr]) -> Vec<u8> {
+    let (added, added6) = compact_addrs(added);
+    let (dropped, dropped6) = compact_addrs(dropped);
+    let msg = PexMessage {
+        added: ByteBuf::from(added),
+        added6: ByteBuf::from(added6),
+        dropped: ByteBuf::from(dropped),
+        dropped6: ByteBuf::from(dropped6),
+    };
+    ser::to_bytes(&msg).unwrap()
+}
+
+// the compact lists of the IPv4 and of the IPv6 addresses of `addrs`
+fn compact_addrs(addrs: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let (mut v4, mut v6) = (vec![], vec![]);
+    for addr in addrs {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                v4.extend_from_slice(&ip.octets());
+                v4.extend_from_slice(&addr.port().to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                v6.extend_from_slice(&ip.octets());
+                v6.extend_from_slice(&addr.port().to_be_bytes());
+            }
+        }
+    }
+    (v4, v6)
+}
+
+// splits a ut_metadata message into its dictionary and the piece data that
+// follows it
+pub fn parse_metadata_message(payload: &[u8]) -> Option<(MetadataMessage, &[u8])> {
+    let end = bencode_length(payload)?;
+    let msg = de::from_bytes::<MetadataMessage>(&payload[..end]).ok()?;
+    Some((msg, &payload[end..]))
+}
+
+// returns the length of the bencoded value at the start of data
+pub fn bencode_length(data: &[u8]) -> Option<usize> {
+    match *data.first()? {
+        b'i' => Some(data.iter().position(|b| *b == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut pos = 1;
+            while *data.get(pos)? != b'e' {
+                pos += bencode_length(&data[pos..])?;
+            }
+            Some(pos + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = data.iter().position(|b| *b == b':')?;
+            let length: usize = std::str::from_utf8(&data[..colon]).ok()?.parse().ok()?;
+            let end = colon + 1 + length;
+            if end > data.len() {
+                return None;
+            }
+            Some(end)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn parse_handshake_reads_reqq() {
+        let payload = b"d1:md11:ut_metadatai2ee4:reqqi500e1:v4:herbe";
+        let hs = super::parse_handshake(0, payload).unwrap();
+        assert_eq!(hs.reqq, Some(500));
+        assert_eq!(hs.ut_metadata(), Some(2));
+    }
+
+    #[test]
+    fn parse_handshake_ignores_other_extended_messages() {
+        assert!(super::parse_handshake(3, b"de").is_none());
+    }
+
+    #[test]
+    fn new_handshake_payload_works() {
+        assert_eq!(
+            super::new_handshake_payload(Some(31235), false),
+            b"d1:md11:ut_metadatai1ee13:metadata_sizei31235e4:reqqi250ee".to_vec()
+        );
+        assert_eq!(
+            super::new_handshake_payload(None, true),
+            b"d1:md11:ut_metadatai1e6:ut_pexi2ee4:reqqi250ee".to_vec()
+        );
+    }
+
+    #[test]
+    fn pex_messages_carry_both_families() {
+        let added = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[2001:db8::1]:51413".parse().unwrap(),
+        ];
+        let dropped = vec!["10.0.0.2:6881".parse().unwrap()];
+        let payload = super::new_pex_message(&added, &dropped);
+        let msg: super::PexMessage = serde_bencode::de::from_bytes(&payload).unwrap();
+        assert_eq!(msg.added.len(), 6);
+        assert_eq!(msg.added6.len(), 18);
+        assert_eq!(msg.dropped.len(), 6);
+        assert!(msg.dropped6.is_empty());
+
+        let peers = super::parse_pex(&payload);
+        let found: Vec<std::net::SocketAddr> = peers
+            .iter()
+            .map(|p| std::net::SocketAddr::new(p.ip, p.port))
+            .collect();
+        assert_eq!(found, added);
+
+        // a broken IPv6 list doesn't cost the IPv4 peers
+        let broken = b"d5:added6:\x0a\x00\x00\x01\x1a\xe16:added65:\x00\x00\x00\x00\x00e";
+        assert_eq!(super::parse_pex(broken).len(), 1);
+        assert!(super::parse_pex(b"not bencode").is_empty());
+    }
+
+    #[test]
+    fn parse_metadata_message_splits_data() {
+        let payload = b"d8:msg_typei1e5:piecei0e10:total_sizei3eeabc";
+        let (msg, data) = super::parse_metadata_message(payload).unwrap();
+        assert_eq!(msg.msg_type, super::METADATA_DATA);
+        assert_eq!(msg.total_size, Some(3));
+        assert_eq!(data, b"abc");
+    }
+
+    #[test]
+    fn metadata_reply_sends_piece_or_rejects() {
+        let metadata = vec![7u8; super::METADATA_PIECE_SIZE + 10];
+        let reply = super::new_metadata_reply(1, &metadata);
+        let (msg, data) = super::parse_metadata_message(&reply).unwrap();
+        assert_eq!(msg.msg_type, super::METADATA_DATA);
+        assert_eq!(msg.total_size, Some(metadata.len() as i64));
+        assert_eq!(data, &[7u8; 10][..]);
+
+        let reply = super::new_metadata_reply(2, &metadata);
+        let (msg, _) = super::parse_metadata_message(&reply).unwrap();
+        assert_eq!(msg.msg_type, super::METADATA_REJECT);
+    }
+
+    #[test]
+    fn bencode_length_works() {
+        assert_eq!(super::bencode_length(b"i42eXX"), Some(4));
+        assert_eq!(super::bencode_length(b"4:spamXX"), Some(6));
+        assert_eq!(super::bencode_length(b"d3:keyl1:a1:beeXX"), Some(15));
+        assert_eq!(super::bencode_length(b"5:spam"), None);
+        assert_eq!(super::bencode_length(b"d3:key"), None);
+    }
+}
@@ -0,0 +1,271 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::torrent;
+
+// FilePriority is how much we want a file; pieces inherit the highest
+// priority of the files they overlap
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd)]
+pub enum FilePriority {
+    Skip = 0,
+    Low = 1,
+    Normal = 2,
+    High = 3,
+}
+
+#[derive(Debug, Clone)]
+pub enum SelectionError {
+    InvalidPriority,
+    InvalidFileIndex,
+}
+
+impl fmt::Display for SelectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelectionError::InvalidPriority => {
+                write!(f, "priority must be skip, low, normal or high")
+            }
+            SelectionError::InvalidFileIndex => write!(f, "no file with that index"),
+        }
+    }
+}
+
+impl Error for SelectionError {}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    Index(usize),
+    Glob(String),
+}
+
+// FileSelector picks files by index or glob and gives them a priority
+#[derive(Debug, Clone)]
+pub struct FileSelector {
+    pattern: Pattern,
+    priority: FilePriority,
+}
+
+impl FilePriority {
+    pub fn from_name(name: &str) -> Option<FilePriority> {
+        match name {
+            "skip" => Some(FilePriority::Skip),
+            "low" => Some(FilePriority::Low),
+            "normal" => Some(FilePriority::Normal),
+            "high" => Some(FilePriority::High),
+            _ => None,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<FilePriority> {
+        match value {
+            0 => Some(FilePriority::Skip),
+            1 => Some(FilePriority::Low),
+            2 => Some(FilePriority::Normal),
+            3 => Some(FilePriority::High),
+            _ => None,
+        }
+    }
+}
+
+// parses a selector of the form <index|glob>[:<priority>], e.g. `3`,
+// `*.csv:high` or `docs/*:skip`; the priority defaults to normal
+pub fn parse_selector(spec: &str) -> Result<FileSelector, SelectionError> {
+    let (pattern, priority) = match spec.rfind(':') {
+        Some(pos) => match FilePriority::from_name(&spec[pos + 1..]) {
+            Some(priority) => (&spec[..pos], priority),
+            None => return Err(SelectionError::InvalidPriority),
+        },
+        None => (spec, FilePriority::Normal),
+    };
+
+    let pattern = match pattern.parse::<usize>() {
+        Ok(index) => Pattern::Index(index),
+        Err(_) => Pattern::Glob(pattern.to_owned()),
+    };
+
+    Ok(FileSelector { pattern, priority })
+}
+
+// resolves the priority of every file in the torrent; without selectors every
+// file is wanted, otherwise only the selected ones are, and later selectors
+// override earlier ones
+pub fn resolve_priorities(
+    torrent: &torrent::Torrent,
+    selectors: &[FileSelector],
+) -> Result<Vec<FilePriority>, SelectionError> {
+    let mut priorities = vec![FilePriority::Skip; torrent.files.len()];
+    if selectors.is_empty() {
+        priorities = vec![FilePriority::Normal; torrent.files.len()];
+    }
+    for selector in selectors {
+        match &selector.pattern {
+            Pattern::Index(index) => {
+                if *index >= priorities.len() {
+                    return Err(SelectionError::InvalidFileIndex);
+                }
+                priorities[*index] = selector.priority;
+            }
+            Pattern::Glob(glob) => {
+                for (index, f) in torrent.files.iter().enumerate() {
+                    let path = f.path.to_string_lossy().replace('\\', "/");
+                    if glob_match(glob.as_bytes(), path.as_bytes()) {
+                        priorities[index] = selector.priority;
+                    }
+                }
+            }
+        }
+    }
+    // padding files are never written
+    for (index, f) in torrent.files.iter().enumerate() {
+        if f.padding {
+            priorities[index] = FilePriority::Skip;
+        }
+    }
+    Ok(priorities)
+}
+
+// maps file priorities onto pieces; a piece shared by several files gets the
+// highest priority among them, so a piece is only skipped if every file it
+// touches is skipped
+pub fn piece_priorities(
+    torrent: &torrent::Torrent,
+    file_priorities: &[FilePriority],
+) -> Vec<FilePriority> {
+    let mut priorities = vec![FilePriority::Skip; torrent.piece_hashes.len()];
+    for (file_index, file_priority) in file_priorities.iter().enumerate() {
+        for piece in torrent.pieces_for_file(file_index) {
+            let p = &mut priorities[piece as usize];
+            if *file_priority > *p {
+                *p = *file_priority;
+            }
+        }
+    }
+    priorities
+}
+
+// matches `*` (any run of characters) and `?` (any single character)
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    while p < pattern.len() && pattern[p] == b'*' {
+        p += 1;
+    }
+    p == pattern.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use super::FilePriority;
+    use crate::torrent;
+
+    fn test_torrent() -> torrent::Torrent {
+        // three files over four pieces of 10 bytes:
+        // bytes 0..12 are a, 12..30 are b and 30..32 are c
+        let files = vec![
+            torrent::TorrentFile {
+                path: PathBuf::from("data/a.bin"),
+                length: 12,
+                offset: 0,
+                padding: false,
+            },
+            torrent::TorrentFile {
+                path: PathBuf::from("data/b.csv"),
+                length: 18,
+                offset: 12,
+                padding: false,
+            },
+            torrent::TorrentFile {
+                path: PathBuf::from("data/c.csv"),
+                length: 2,
+                offset: 30,
+                padding: false,
+            },
+        ];
+        torrent::Torrent {
+            announce: String::new(),
+            trackers: vec![],
+            name: "data".to_owned(),
+            length: 32,
+            info_hash: [0u8; 20],
+            info_hash_v2: None,
+            version: torrent::MetaVersion::V1,
+            piece_length: 10,
+            piece_hashes: vec![torrent::PieceHash::V1([0u8; 20]); 4],
+            files,
+            info_bytes: vec![],
+            piece_layers: HashMap::new(),
+            web_seeds: vec![],
+            http_seeds: vec![],
+            private: false,
+        }
+    }
+
+    #[test]
+    fn glob_match_works() {
+        assert!(super::glob_match(b"*.csv", b"data/b.csv"));
+        assert!(super::glob_match(b"data/?.bin", b"data/a.bin"));
+        assert!(super::glob_match(b"*", b""));
+        assert!(!super::glob_match(b"*.csv", b"data/a.bin"));
+        assert!(!super::glob_match(b"data/?", b"data/ab"));
+    }
+
+    #[test]
+    fn parse_selector_rejects_unknown_priority() {
+        assert!(super::parse_selector("1:urgent").is_err());
+    }
+
+    #[test]
+    fn resolve_priorities_without_selectors_wants_everything() {
+        let t = test_torrent();
+        let priorities = super::resolve_priorities(&t, &[]).unwrap();
+        assert_eq!(priorities, vec![FilePriority::Normal; 3]);
+    }
+
+    #[test]
+    fn resolve_priorities_later_selectors_override() {
+        let t = test_torrent();
+        let selectors = vec![
+            super::parse_selector("*.csv:low").unwrap(),
+            super::parse_selector("2:high").unwrap(),
+        ];
+        let priorities = super::resolve_priorities(&t, &selectors).unwrap();
+        assert_eq!(
+            priorities,
+            vec![FilePriority::Skip, FilePriority::Low, FilePriority::High]
+        );
+    }
+
+    #[test]
+    fn piece_priorities_keeps_shared_pieces() {
+        let t = test_torrent();
+        let file_priorities = vec![FilePriority::High, FilePriority::Skip, FilePriority::Low];
+        let priorities = super::piece_priorities(&t, &file_priorities);
+        assert_eq!(
+            priorities,
+            vec![
+                FilePriority::High, // only a
+                FilePriority::High, // shared by a and skipped b
+                FilePriority::Skip, // only skipped b
+                FilePriority::Low,  // shared by skipped b and c
+            ]
+        );
+    }
+}
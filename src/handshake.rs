@@ -1,21 +1,66 @@
+use std::error::Error;
+use std::fmt;
 use std::str;
 
+// the protocol string every BitTorrent handshake starts with
+const PSTR: &str = "BitTorrent protocol";
+
+// a handshake is the length of the protocol string, the string itself, the
+// reserved bytes, the info hash and the peer id
+pub const HANDSHAKE_LENGTH: usize = 1 + 19 + 8 + 20 + 20;
+
 #[derive(Debug)]
 pub struct Handshake {
     pub pstr: String,
+    pub extensions: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
 
+// reserved byte and bit announcing the extension protocol (BEP 10)
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+// reserved byte and bit announcing support for v2 torrents (BEP 52)
+const V2_BYTE: usize = 7;
+const V2_BIT: u8 = 0x10;
+
 pub fn new_handshake(info_hash: [u8; 20], peer_id: [u8; 20]) -> Handshake {
+    let mut extensions = [0u8; 8];
+    extensions[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+    extensions[V2_BYTE] |= V2_BIT;
     Handshake {
-        pstr: "BitTorrent protocol".to_owned(),
+        pstr: PSTR.to_owned(),
+        extensions,
         info_hash,
         peer_id,
     }
 }
 
 impl Handshake {
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.extensions[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
+    // checks a peer's handshake: it must be for `info_hash`, must not come
+    // from ourselves, and must carry `expected_peer_id` when we know it
+    pub fn validate(
+        &self,
+        info_hash: &[u8; 20],
+        own_peer_id: &[u8; 20],
+        expected_peer_id: Option<&[u8; 20]>,
+    ) -> Result<(), HandshakeError> {
+        if self.info_hash != *info_hash {
+            return Err(HandshakeError::InfoHashMismatch);
+        }
+        if self.peer_id == *own_peer_id {
+            return Err(HandshakeError::OwnPeerId);
+        }
+        match expected_peer_id {
+            Some(id) if *id != self.peer_id => Err(HandshakeError::PeerIdMismatch),
+            _ => Ok(()),
+        }
+    }
+
     pub fn serialize(&self) -> Vec<u8> {
         let mut buffer: Vec<u8> = Vec::new();
 
@@ -27,10 +72,8 @@ impl Handshake {
             buffer.push(*b);
         }
 
-        // extension bytes - all 0 in our case
-        for _ in 0..8 {
-            buffer.push(0u8);
-        }
+        // extension bytes
+        buffer.extend_from_slice(&self.extensions);
 
         // infohash
         for b in self.info_hash.iter() {
@@ -46,52 +89,110 @@ impl Handshake {
     }
 }
 
-pub fn read_handshake(data: &Vec<u8>) -> Handshake {
-    // println!("peer handshake response");
-    // println!("{:?}", data);
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HandshakeError {
+    InvalidLength,
+    InvalidProtocolLength(u8),
+    InvalidProtocol,
+    InfoHashMismatch,
+    OwnPeerId,
+    PeerIdMismatch,
+}
 
-    // println!();
-    // println!("handshake response length: {}", data.len());
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandshakeError::InvalidLength => write!(f, "handshake is too short"),
+            HandshakeError::InvalidProtocolLength(n) => {
+                write!(f, "protocol string is {} bytes instead of 19", n)
+            }
+            HandshakeError::InvalidProtocol => write!(f, "not the BitTorrent protocol"),
+            HandshakeError::InfoHashMismatch => write!(f, "handshake is for another torrent"),
+            HandshakeError::OwnPeerId => write!(f, "connected to ourselves"),
+            HandshakeError::PeerIdMismatch => {
+                write!(f, "peer id differs from the one the tracker gave")
+            }
+        }
+    }
+}
 
-    // let pstr_length = data[0];
-    // println!("pst length is {}", pstr_length);
+impl Error for HandshakeError {}
 
-    let mut pstr: [u8; 19] = [0; 19];
-    for i in 0..19 {
-        pstr[i] = data[i + 1]
+// parses the handshake at the start of data, which must be the BitTorrent
+// protocol's
+pub fn read_handshake(data: &[u8]) -> Result<Handshake, HandshakeError> {
+    if data.len() < HANDSHAKE_LENGTH {
+        return Err(HandshakeError::InvalidLength);
+    }
+    if data[0] as usize != PSTR.len() {
+        return Err(HandshakeError::InvalidProtocolLength(data[0]));
+    }
+    if &data[1..20] != PSTR.as_bytes() {
+        return Err(HandshakeError::InvalidProtocol);
     }
-    // println!("pstr: {:?}", pstr);
 
     let mut extensions: [u8; 8] = [0; 8];
-    for i in 0..8 {
-        extensions[i] = data[i + 20];
-    }
+    extensions.copy_from_slice(&data[20..28]);
     // println!("extensions: {:?}", extensions);
 
     let mut info_hash: [u8; 20] = [0; 20];
-    for i in 0..20 {
-        info_hash[i] = data[i + 28];
-    }
+    info_hash.copy_from_slice(&data[28..48]);
     // println!("info_hash: {:?}", info_hash);
 
     let mut peer_id: [u8; 20] = [0; 20];
-    for i in 0..20 {
-        peer_id[i] = data[i + 48];
-    }
+    peer_id.copy_from_slice(&data[48..68]);
     // println!("peer_id: {:?}", peer_id);
 
-    Handshake {
-        pstr: str::from_utf8(&pstr).unwrap().to_owned(),
+    Ok(Handshake {
+        pstr: PSTR.to_owned(),
+        extensions,
         info_hash,
         peer_id,
-    }
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use std::str;
 
-    use super::read_handshake;
+    use super::{read_handshake, HandshakeError};
+
+    #[test]
+    fn invalid_handshakes_are_rejected() {
+        let ours = super::new_handshake([1u8; 20], [2u8; 20]);
+        let data = ours.serialize();
+        assert_eq!(
+            read_handshake(&data[..67]).err(),
+            Some(HandshakeError::InvalidLength)
+        );
+        let mut other = data.clone();
+        other[0] = 18;
+        assert_eq!(
+            read_handshake(&other).err(),
+            Some(HandshakeError::InvalidProtocolLength(18))
+        );
+        let mut other = data.clone();
+        other[1] = 0xff;
+        assert_eq!(
+            read_handshake(&other).err(),
+            Some(HandshakeError::InvalidProtocol)
+        );
+
+        let theirs = read_handshake(&data).unwrap();
+        assert_eq!(theirs.validate(&[1u8; 20], &[3u8; 20], None), Ok(()));
+        assert_eq!(
+            theirs.validate(&[9u8; 20], &[3u8; 20], None),
+            Err(HandshakeError::InfoHashMismatch)
+        );
+        assert_eq!(
+            theirs.validate(&[1u8; 20], &[2u8; 20], None),
+            Err(HandshakeError::OwnPeerId)
+        );
+        assert_eq!(
+            theirs.validate(&[1u8; 20], &[3u8; 20], Some(&[4u8; 20])),
+            Err(HandshakeError::PeerIdMismatch)
+        );
+    }
 
     #[test]
     fn read_handshake_works() {
@@ -112,9 +213,10 @@ mod tests {
             255, 255, 255, 255, 255, 255, 255, 255, 240, 0, 0, 0, 1, 1,
         ];
 
-        let handshake = read_handshake(&handshake_response);
+        let handshake = read_handshake(&handshake_response).unwrap();
 
         assert_eq!(handshake.pstr, "BitTorrent protocol");
+        assert!(handshake.supports_extension_protocol());
         assert_eq!(
             handshake.info_hash,
             [
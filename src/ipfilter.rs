@@ -0,0 +1,224 @@
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// eMule DAT entries with an access level above this one are allowed
+const DAT_MAX_BLOCKED_LEVEL: u32 = 127;
+
+// IpFilter is a set of blocked address ranges, kept sorted and merged so a
+// lookup is a binary search
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FilterError {
+    // the 1-based line of the blocklist that isn't an address range
+    InvalidLine(usize),
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilterError::InvalidLine(line) => write!(f, "line {}: not an IP range", line),
+        }
+    }
+}
+
+impl Error for FilterError {}
+
+pub fn new_ip_filter() -> IpFilter {
+    IpFilter::default()
+}
+
+// an address, allowing the zero padded octets of eMule DAT files
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    if s.contains(':') {
+        return s.parse::<Ipv6Addr>().ok().map(IpAddr::V6);
+    }
+    let octets: Vec<u8> = s
+        .split('.')
+        .map(|octet| octet.parse().ok())
+        .collect::<Option<_>>()?;
+    match octets[..] {
+        [a, b, c, d] => Some(IpAddr::V4(Ipv4Addr::new(a, b, c, d))),
+        _ => None,
+    }
+}
+
+// `a-b`, `net/bits` or a single address
+fn parse_range(s: &str) -> Option<(IpAddr, IpAddr)> {
+    if let Some((start, end)) = s.split_once('-') {
+        return Some((parse_ip(start)?, parse_ip(end)?));
+    }
+    if let Some((net, bits)) = s.split_once('/') {
+        let bits: u32 = bits.trim().parse().ok()?;
+        return match parse_ip(net)? {
+            IpAddr::V4(net) if bits <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+                let start = u32::from(net) & mask;
+                Some((
+                    Ipv4Addr::from(start).into(),
+                    Ipv4Addr::from(start | !mask).into(),
+                ))
+            }
+            IpAddr::V6(net) if bits <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+                let start = u128::from(net) & mask;
+                Some((
+                    Ipv6Addr::from(start).into(),
+                    Ipv6Addr::from(start | !mask).into(),
+                ))
+            }
+            _ => None,
+        };
+    }
+    parse_ip(s).map(|ip| (ip, ip))
+}
+
+// the blocked range of one blocklist line, or None for a line that blocks
+// nothing; understands eMule DAT (`a - b , level , description`),
+// PeerGuardian P2P (`description:a-b`) and CIDR lines
+fn parse_line(line: &str) -> Result<Option<(IpAddr, IpAddr)>, ()> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+        return Ok(None);
+    }
+    if let Some((range, rest)) = line.split_once(',') {
+        if let Some(range) = parse_range(range) {
+            let level = rest.split(',').next().unwrap_or_default();
+            let level: u32 = level.trim().parse().map_err(|_| ())?;
+            return Ok(Some(range).filter(|_| level <= DAT_MAX_BLOCKED_LEVEL));
+        }
+    }
+    if let Some(range) = parse_range(line) {
+        return Ok(Some(range));
+    }
+    // the description of a P2P line may have colons of its own
+    let (_, range) = line.rsplit_once(':').ok_or(())?;
+    parse_range(range).map(Some).ok_or(())
+}
+
+// sorts ranges and merges the ones that overlap or touch
+fn merge<T: Ord + Copy>(ranges: &mut Vec<(T, T)>, next: impl Fn(T) -> Option<T>) {
+    ranges.sort();
+    let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+    for &(start, end) in ranges.iter() {
+        match merged.last_mut() {
+            Some(last) if next(last.1).is_none_or(|after| start <= after) => {
+                last.1 = last.1.max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    *ranges = merged;
+}
+
+fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+    let after = ranges.partition_point(|r| r.0 <= ip);
+    after > 0 && ranges[after - 1].1 >= ip
+}
+
+impl IpFilter {
+    // blocks every range of a blocklist; a line that doesn't parse fails the
+    // whole list, which then blocks nothing, so a broken file is noticed
+    pub fn add_list(&mut self, text: &str) -> Result<(), FilterError> {
+        let (mut v4, mut v6) = (vec![], vec![]);
+        for (n, line) in text.lines().enumerate() {
+            match parse_line(line).map_err(|_| FilterError::InvalidLine(n + 1))? {
+                Some((IpAddr::V4(start), IpAddr::V4(end))) if start <= end => {
+                    v4.push((start.into(), end.into()))
+                }
+                Some((IpAddr::V6(start), IpAddr::V6(end))) if start <= end => {
+                    v6.push((start.into(), end.into()))
+                }
+                Some(_) => return Err(FilterError::InvalidLine(n + 1)),
+                None => {}
+            }
+        }
+        self.v4.append(&mut v4);
+        self.v6.append(&mut v6);
+        merge(&mut self.v4, |ip| ip.checked_add(1));
+        merge(&mut self.v6, |ip| ip.checked_add(1));
+        Ok(())
+    }
+
+    // the number of distinct ranges
+    pub fn len(&self) -> usize {
+        self.v4.len() + self.v6.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_blocked(&self, ip: &IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => contains(&self.v4, u32::from(*ip)),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => contains(&self.v4, u32::from(ip)),
+                None => contains(&self.v6, u128::from(*ip)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    fn blocked(filter: &super::IpFilter, ip: &str) -> bool {
+        filter.is_blocked(&ip.parse::<IpAddr>().unwrap())
+    }
+
+    #[test]
+    fn blocklist_formats_are_understood() {
+        let list = "# comment\n\
+            001.002.003.000 - 001.002.003.255 , 000 , eMule entry\n\
+            005.000.000.000 - 005.255.255.255 , 200 , allowed by its level\n\
+            Some: corp, Inc.:10.0.0.0-10.0.0.9\n\
+            192.168.0.0/16\n\
+            2001:db8::/32\n\
+            8.8.8.8\n";
+        let mut filter = super::new_ip_filter();
+        filter.add_list(list).unwrap();
+        assert_eq!(filter.len(), 5);
+
+        assert!(blocked(&filter, "1.2.3.4"));
+        assert!(!blocked(&filter, "1.2.4.0"));
+        assert!(!blocked(&filter, "5.1.1.1"));
+        assert!(blocked(&filter, "10.0.0.9"));
+        assert!(!blocked(&filter, "10.0.0.10"));
+        assert!(blocked(&filter, "192.168.255.255"));
+        assert!(blocked(&filter, "2001:db8:ffff::1"));
+        assert!(!blocked(&filter, "2001:db9::1"));
+        assert!(blocked(&filter, "8.8.8.8"));
+        assert!(blocked(&filter, "::ffff:8.8.8.8"));
+        assert!(!blocked(&filter, "8.8.4.4"));
+    }
+
+    #[test]
+    fn ranges_are_merged_and_bad_lines_rejected() {
+        let mut filter = super::new_ip_filter();
+        filter
+            .add_list("10.0.0.0-10.0.0.5\n10.0.0.6-10.0.0.9\n10.0.0.2/31\n0.0.0.0/0")
+            .unwrap();
+        assert_eq!(filter.len(), 1);
+        assert!(blocked(&filter, "255.255.255.255"));
+
+        let mut filter = super::new_ip_filter();
+        let bad = "10.0.0.0/8\n10.0.0.9-10.0.0.1\n";
+        assert_eq!(
+            filter.add_list(bad),
+            Err(super::FilterError::InvalidLine(2))
+        );
+        assert_eq!(
+            filter.add_list("hello"),
+            Err(super::FilterError::InvalidLine(1))
+        );
+        assert!(filter.is_empty());
+    }
+}
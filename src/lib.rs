@@ -0,0 +1,41 @@
+extern crate serde;
+extern crate serde_bencode;
+#[macro_use]
+extern crate serde_derive;
+
+pub mod bind;
+mod bitfield;
+mod choker;
+mod client;
+pub mod create;
+mod dht;
+mod extension;
+pub mod files;
+mod handshake;
+pub mod ipfilter;
+pub mod magnet;
+mod merkle;
+mod message;
+mod metadata;
+mod p2p;
+pub mod peer_id;
+mod peers;
+mod pipeline;
+mod portmap;
+pub mod proxy;
+mod ratelimit;
+mod resume;
+pub mod session;
+mod smartban;
+mod storage;
+pub mod torrent;
+pub mod tracker;
+mod webseed;
+
+pub use files::{FilePriority, FileSelector};
+pub use ratelimit::{parse_window, RateWindow};
+pub use session::{
+    new_session, AddTorrentOptions, Progress, Session, SessionError, SessionOptions, TorrentError,
+    TorrentHandle, TorrentState,
+};
+pub use storage::verify_pieces;
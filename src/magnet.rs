@@ -0,0 +1,140 @@
+use std::error::Error;
+use std::fmt;
+use url::Url;
+
+#[derive(Debug, Clone)]
+pub enum MagnetError {
+    InvalidUri,
+    MissingInfoHash,
+    InvalidInfoHash,
+}
+
+impl fmt::Display for MagnetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MagnetError::InvalidUri => write!(f, "not a magnet link"),
+            MagnetError::MissingInfoHash => write!(f, "magnet link has no btih info hash"),
+            MagnetError::InvalidInfoHash => write!(f, "magnet link has an invalid info hash"),
+        }
+    }
+}
+
+impl Error for MagnetError {}
+
+// Magnet is what a magnet link tells us: the info hash, and optionally a
+// display name and trackers to find peers with
+#[derive(Debug, Clone)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+pub fn parse_magnet(uri: &str) -> Result<Magnet, MagnetError> {
+    let url = Url::parse(uri).map_err(|_| MagnetError::InvalidUri)?;
+    if url.scheme() != "magnet" {
+        return Err(MagnetError::InvalidUri);
+    }
+
+    let mut info_hash = None;
+    let mut name = None;
+    let mut trackers = vec![];
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "xt" => {
+                if let Some(hash) = value.strip_prefix("urn:btih:") {
+                    info_hash = Some(decode_info_hash(hash)?);
+                }
+            }
+            "dn" => name = Some(value.into_owned()),
+            "tr" => trackers.push(value.into_owned()),
+            _ => {}
+        }
+    }
+
+    match info_hash {
+        Some(info_hash) => Ok(Magnet {
+            info_hash,
+            name,
+            trackers,
+        }),
+        None => Err(MagnetError::MissingInfoHash),
+    }
+}
+
+// info hashes come either hex encoded (40 characters) or base32 encoded (32
+// characters)
+fn decode_info_hash(hash: &str) -> Result<[u8; 20], MagnetError> {
+    let mut info_hash = [0u8; 20];
+    match hash.len() {
+        40 => {
+            for (i, byte) in info_hash.iter_mut().enumerate() {
+                *byte = u8::from_str_radix(&hash[i * 2..i * 2 + 2], 16)
+                    .map_err(|_| MagnetError::InvalidInfoHash)?;
+            }
+        }
+        32 => {
+            let mut bits: u64 = 0;
+            let mut nbits = 0;
+            let mut i = 0;
+            for c in hash.bytes() {
+                let value = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => return Err(MagnetError::InvalidInfoHash),
+                };
+                bits = (bits << 5) | value as u64;
+                nbits += 5;
+                if nbits >= 8 {
+                    nbits -= 8;
+                    info_hash[i] = (bits >> nbits) as u8;
+                    i += 1;
+                }
+            }
+        }
+        _ => return Err(MagnetError::InvalidInfoHash),
+    }
+    Ok(info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn parse_magnet_works() {
+        let magnet = super::parse_magnet(
+            "magnet:?xt=urn:btih:5a8062c076fa85e8056451c0d9aa04349ae27909&dn=debian.iso&tr=http%3A%2F%2Ftracker.example%2Fannounce&tr=udp%3A%2F%2Fother.example%3A80",
+        )
+        .unwrap();
+        assert_eq!(
+            magnet.info_hash,
+            [
+                90, 128, 98, 192, 118, 250, 133, 232, 5, 100, 81, 192, 217, 170, 4, 52, 154, 226,
+                121, 9
+            ]
+        );
+        assert_eq!(magnet.name.as_deref(), Some("debian.iso"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "http://tracker.example/announce".to_owned(),
+                "udp://other.example:80".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_magnet_base32_works() {
+        let hex =
+            super::parse_magnet("magnet:?xt=urn:btih:5a8062c076fa85e8056451c0d9aa04349ae27909")
+                .unwrap();
+        let base32 =
+            super::parse_magnet("magnet:?xt=urn:btih:LKAGFQDW7KC6QBLEKHANTKQEGSNOE6IJ").unwrap();
+        assert_eq!(hex.info_hash, base32.info_hash);
+    }
+
+    #[test]
+    fn parse_magnet_requires_info_hash() {
+        assert!(super::parse_magnet("magnet:?dn=nothing").is_err());
+        assert!(super::parse_magnet("http://example.com/?xt=urn:btih:00").is_err());
+    }
+}
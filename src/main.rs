@@ -1,172 +1,516 @@
-extern crate serde;
-extern crate serde_bencode;
-#[macro_use]
-extern crate serde_derive;
-
-use serde_bencode::de;
-use std::fs::File;
-use std::io::prelude::*;
+use std::env;
+use std::error::Error;
+use std::fs;
 use std::io::{self, Read};
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-mod bitfield;
-mod client;
-mod handshake;
-mod message;
-mod p2p;
-mod torrent;
+use herb::bind;
+use herb::create;
+use herb::files;
+use herb::ipfilter;
+use herb::proxy;
+use herb::torrent;
+use herb::tracker;
+use herb::{AddTorrentOptions, Session, SessionOptions, TorrentState};
 
-pub static PEER_ID: &str = "kjh29409k8hj0wgej6c1";
+const USAGE: &str = "usage: herb <command> [options]
 
-fn main() {
-    let stdin = io::stdin();
-    let mut buffer = Vec::new();
-    let mut handle = stdin.lock();
-    let bencode_torrent;
-    match handle.read_to_end(&mut buffer) {
-        Ok(_) => match de::from_bytes::<torrent::BencodeTorrent>(&buffer) {
-            Ok(t) => {
-                bencode_torrent = t;
-                torrent::render_bencode_torrent(&bencode_torrent);
+commands:
+  download <file.torrent | magnet link | ->   download a torrent into --output-dir
+  info <file.torrent | ->                     show the name, size and files of a torrent
+  verify <file.torrent | ->                   check downloaded files against the piece hashes
+  create <file or directory>                  make a .torrent out of local files
+  scrape <file.torrent | ->                   ask the tracker how many peers share a torrent
+
+download options:
+  --output-dir <dir>             where the files are written (default: .)
+  --port <port>                  port peers connect to (default: 6881)
+  --max-peers <n>                open peer connections at most (default: 200)
+  --target-peers <n>             peers we try to stay connected to (default: 50)
+  --seed-ratio <ratio>           keep seeding until uploaded/size reaches ratio
+  --file <index|glob>[:skip|low|normal|high]
+                                 download only some files, or some first
+  --upload-slots <n>             peers we upload to at once
+  --download-limit <bytes/s>     limit the download rate; at least 1
+  --upload-limit <bytes/s>       limit the upload rate; at least 1
+  --schedule <HH:MM>-<HH:MM>[=<down>[/<up>]]
+                                 other limits during this time of day, in UTC;
+                                 a rate left out is unlimited; repeatable
+  --blocklist <file>             never talk to the IP ranges in a P2P, eMule DAT
+                                 or CIDR list; repeatable, reread when changed
+  --proxy <url>                  reach peers and trackers through
+                                 socks5://[user:pass@]host:port or http://...
+  --proxy-only                   never connect directly, and accept no peers
+  --bind <ip|interface>          listen on and connect from this address only;
+                                 repeatable, e.g. once for IPv4 and once for IPv6
+  --no-port-mapping              don't ask the router to forward --port
+  --no-dht                       find peers through the trackers only
+
+scrape options:
+  --proxy <url>                  ask the tracker through a proxy
+  --bind <ip|interface>          ask the tracker from this address
+
+verify options:
+  --output-dir <dir>             where the files were written (default: .)
+
+create options:
+  --announce <url>[,<url>]...    a tier of trackers; repeat for more tiers (required)
+  --web-seed <url>               a server that has the files too; repeatable
+  --piece-length <bytes>         a power of two; picked from the size if left out
+  --private                      only get peers from the trackers
+  --comment <text>
+  --created-by <text>            (default: herb/<version>)
+  --creation-date <seconds>      seconds since the epoch (default: now)
+  --no-creation-date             leave the date out, for reproducible torrents
+  --include-hidden               keep files and directories starting with a dot
+  --exclude <glob>               leave out matching paths or names; repeatable
+  --threads <n>                  threads hashing pieces (default: one per CPU)
+  --output <file.torrent>        where to write it (default: <name>.torrent)
+
+exit codes: 0 on success, 1 when the command failed, 2 on bad usage,
+130 when a download was interrupted";
+
+// the exit code of a download stopped with Ctrl-C
+const INTERRUPTED: i32 = 130;
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+    process::exit(error(message));
+}
+
+// reports an error and returns the exit code that goes with it
+fn error(message: String) -> i32 {
+    eprintln!("herb: {}", message);
+    1
+}
+
+// the first Ctrl-C asks the download to stop, so that the session is dropped
+// and the router and the trackers learn we left; a second one exits at once
+fn watch_interrupts() -> Arc<AtomicBool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap_or_else(|e| fail(format!("couldn't start runtime: {}", e)));
+    let flag = Arc::clone(&interrupted);
+    thread::spawn(move || {
+        runtime.block_on(async {
+            if tokio::signal::ctrl_c().await.is_err() {
+                return;
             }
-            Err(e) => panic!("ERROR: {:?}", e),
-        },
-        Err(e) => panic!("ERROR: {:?}", e),
+            eprintln!("herb: stopping, press Ctrl-C again to quit right away");
+            flag.store(true, Ordering::Relaxed);
+            let _ = tokio::signal::ctrl_c().await;
+            process::exit(INTERRUPTED);
+        })
+    });
+    interrupted
+}
+
+// an error followed by the errors that caused it, leaving out causes whose
+// message it already includes
+fn describe(e: &dyn Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        let cause_message = cause.to_string();
+        if !message.contains(&cause_message) {
+            message.push_str(&format!(": {}", cause_message));
+        }
+        source = cause.source();
     }
+    message
+}
 
-    let our_torrent = torrent::new_torrent(&bencode_torrent);
+// parses the value that follows `flag`
+fn value<T: FromStr>(flag: &str, args: &mut impl Iterator<Item = String>) -> T {
+    match args.next().map(|v| v.parse::<T>()) {
+        Some(Ok(v)) => v,
+        _ => {
+            eprintln!("herb: {} needs a valid value", flag);
+            usage();
+        }
+    }
+}
 
-    // println!("\nTorrent struct:");
-    our_torrent.render_torrent();
+// parses the rate that follows `flag`; 0 is refused rather than taken as no
+// limit, which is what the session makes of a zero rate
+fn limit(flag: &str, args: &mut impl Iterator<Item = String>) -> u64 {
+    value::<NonZeroU64>(flag, args).get()
+}
 
-    let url = our_torrent.build_tracker_url().unwrap();
-    // println!("\nURL: {}", url);
+// reads a .torrent from a path, or from stdin for `-`
+fn read_torrent(source: &str) -> Vec<u8> {
+    let data = if source == "-" {
+        let mut buffer = Vec::new();
+        io::stdin().lock().read_to_end(&mut buffer).map(|_| buffer)
+    } else {
+        fs::read(source)
+    };
+    data.unwrap_or_else(|e| fail(format!("couldn't read {}: {}", source, e)))
+}
+
+fn parse_proxy(url: &str) -> proxy::Proxy {
+    proxy::parse_proxy(url).unwrap_or_else(|e| {
+        eprintln!("herb: invalid --proxy: {}", e);
+        usage();
+    })
+}
 
-    // get tracker response (http get)
-    let mut res = reqwest::blocking::get(&url).unwrap();
-    // println!("{:#?}", res);
+fn parse_bind(spec: &str) -> Vec<IpAddr> {
+    bind::parse_bind(spec).unwrap_or_else(|e| {
+        eprintln!("herb: invalid --bind: {}", e);
+        usage();
+    })
+}
 
-    // extract response body into resp_buffer
-    let mut resp_buffer = Vec::new();
-    let copy_result = res.copy_to(&mut resp_buffer);
-    match copy_result {
-        Ok(_) => (),
-        Err(e) => panic!(e),
+// the IP filter made of every --blocklist file
+fn load_blocklists(paths: &[PathBuf]) -> Result<ipfilter::IpFilter, String> {
+    let mut filter = ipfilter::new_ip_filter();
+    for path in paths {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+        filter
+            .add_list(&text)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
     }
+    Ok(filter)
+}
 
-    // deserialize tracker response into bencode struct
-    let bencode_tracker_resp;
-    match de::from_bytes::<torrent::BencodeTrackerResp>(&resp_buffer) {
-        Ok(t) => {
-            bencode_tracker_resp = t;
-            println!("{:?}", bencode_tracker_resp);
-            println!();
+// when the --blocklist files last changed
+fn blocklists_modified(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+// gives the session the blocklists again once one of them changed; a list
+// that doesn't load leaves the old filter in place
+fn reload_blocklists(session: &Session, paths: &[PathBuf], modified: &mut Vec<Option<SystemTime>>) {
+    let now = blocklists_modified(paths);
+    if now == *modified {
+        return;
+    }
+    *modified = now;
+    match load_blocklists(paths) {
+        Ok(filter) => {
+            println!("reloaded blocklists, {} ranges", filter.len());
+            session.set_ip_filter(filter);
         }
-        Err(e) => panic!("ERROR: {:?}", e),
-    }
-
-    // load peers into a vec of Peer structs
-    let peers = bencode_tracker_resp.get_peers().unwrap();
-    let number_of_peers = Arc::new(Mutex::new(peers.len()));
-    println!(
-        "Number of peers found: {}",
-        *number_of_peers.lock().unwrap()
-    );
-    println!();
-
-    let (work_snd, work_rcv) = crossbeam::unbounded();
-    for (index, piece_hash) in our_torrent.piece_hashes.iter().enumerate() {
-        let length = our_torrent.calculate_piece_size(index as i64);
-        let piece_work = p2p::PieceWork {
-            index: index as i64,
-            hash: *piece_hash,
-            length,
-        };
-        work_snd.send(piece_work).unwrap();
-    }
-
-    let (result_snd, result_rcv) = crossbeam::unbounded::<p2p::PieceResult>();
-
-    let mut counter = 0;
-
-    // thread handles
-    let mut handles = vec![];
-
-    for p in peers {
-        let (work_snd_peer, work_rcv_peer) = (work_snd.clone(), work_rcv.clone());
-        let result_snd_peer = result_snd.clone();
-        let info_hash = our_torrent.info_hash;
-
-        let number_of_peers = Arc::clone(&number_of_peers);
-        let handle = thread::spawn(move || {
-            let ip = p.ip.clone();
-            println!("{}: main thread: connecting to peer", ip);
-            p2p::start_download_worker(
-                p,
-                &info_hash,
-                work_snd_peer,
-                work_rcv_peer,
-                result_snd_peer,
-                counter,
-            );
-            println!("{}: main thread: peer exited {}", ip, counter);
+        Err(e) => eprintln!("herb: keeping the old blocklists: {}", e),
+    }
+}
+
+fn parse_torrent(data: &[u8]) -> torrent::Torrent {
+    torrent::parse_torrent(data)
+        .and_then(|b| torrent::new_torrent(&b))
+        .unwrap_or_else(|e| fail(format!("invalid torrent: {}", e)))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
 
-            let mut new_n = number_of_peers.lock().unwrap();
-            *new_n -= 1;
-        });
-        handles.push(handle);
-        counter += 1;
+fn main() {
+    let mut args = env::args().skip(1);
+    let command = args.next().unwrap_or_else(|| usage());
+    match command.as_str() {
+        "download" => process::exit(download(args)),
+        "info" => info(args),
+        "verify" => verify(args),
+        "create" => create(args),
+        "scrape" => scrape(args),
+        "help" | "--help" | "-h" => println!("{}", USAGE),
+        _ => usage(),
     }
+}
 
-    // Collect results into a buffer until full
-    let mut buf = vec![0u8; our_torrent.length as usize];
-    let mut done_pieces = 0;
-    while done_pieces < our_torrent.piece_hashes.len() {
-        // println!("scanning for results");
-        let res = result_rcv.recv().unwrap();
+// the single positional argument of a command; `--help` anywhere prints usage
+fn positional(arg: String, target: &mut Option<String>) {
+    if arg == "--help" || arg == "-h" {
+        println!("{}", USAGE);
+        process::exit(0);
+    }
+    if arg.starts_with("--") || target.is_some() {
+        usage();
+    }
+    *target = Some(arg);
+}
 
-        // println!("main thread: received result!");
-        let (begin, end) = our_torrent.calculate_bounds_for_piece(res.index as i64);
+// returns the exit code once the session is gone, so that it can remove its
+// port mappings and say goodbye to the trackers
+fn download(mut args: impl Iterator<Item = String>) -> i32 {
+    let mut options = AddTorrentOptions::default();
+    let mut session_options = SessionOptions::default();
+    let mut seed_ratio: Option<f64> = None;
+    let mut blocklists: Vec<PathBuf> = vec![];
+    let mut proxy_only = false;
+    let mut target = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output-dir" => options.output_dir = value::<PathBuf>(&arg, &mut args),
+            "--port" => session_options.listen_port = value(&arg, &mut args),
+            "--max-peers" => session_options.max_connections = value(&arg, &mut args),
+            "--target-peers" => options.target_peers = value(&arg, &mut args),
+            "--seed-ratio" => seed_ratio = Some(value(&arg, &mut args)),
+            "--file" => match files::parse_selector(&value::<String>(&arg, &mut args)) {
+                Ok(selector) => options.file_selectors.push(selector),
+                Err(e) => {
+                    eprintln!("herb: invalid --file: {}", e);
+                    usage();
+                }
+            },
+            "--upload-slots" => options.upload_slots = value(&arg, &mut args),
+            "--download-limit" => session_options.download_limit = Some(limit(&arg, &mut args)),
+            "--upload-limit" => session_options.upload_limit = Some(limit(&arg, &mut args)),
+            "--schedule" => match herb::parse_window(&value::<String>(&arg, &mut args)) {
+                Some(window) => session_options.rate_schedule.push(window),
+                None => {
+                    eprintln!("herb: invalid --schedule");
+                    usage();
+                }
+            },
+            "--blocklist" => blocklists.push(value(&arg, &mut args)),
+            "--proxy" => {
+                session_options.proxy = Some(parse_proxy(&value::<String>(&arg, &mut args)))
+            }
+            "--proxy-only" => proxy_only = true,
+            "--no-port-mapping" => session_options.port_mapping = false,
+            "--no-dht" => session_options.dht = false,
+            "--bind" => session_options
+                .bind
+                .append(&mut parse_bind(&value::<String>(&arg, &mut args))),
+            _ => positional(arg, &mut target),
+        }
+    }
+    let target = target.unwrap_or_else(|| usage());
+    match (&mut session_options.proxy, proxy_only) {
+        (Some(proxy), only) => proxy.only = only,
+        (None, true) => {
+            eprintln!("herb: --proxy-only needs a --proxy");
+            usage();
+        }
+        (None, false) => {}
+    }
+    let mut blocklists_changed = blocklists_modified(&blocklists);
+    session_options.ip_filter = load_blocklists(&blocklists).unwrap_or_else(|e| fail(e));
 
-        // copy data from res.buf to buf[begin:end]
-        let mut counter = begin as usize;
-        for item in res.buf {
-            if counter >= end as usize {
+    let data = if target.starts_with("magnet:") {
+        None
+    } else {
+        Some(read_torrent(&target))
+    };
+
+    let interrupted = watch_interrupts();
+    let session = herb::new_session(session_options)
+        .unwrap_or_else(|e| fail(format!("couldn't start session: {}", describe(&e))));
+    let added = match &data {
+        None => session.add_magnet(&target, options),
+        Some(data) => session.add_torrent(data, options),
+    };
+    let handle = match added {
+        Ok(handle) => handle,
+        Err(e) => return error(format!("couldn't add torrent: {}", describe(&e))),
+    };
+
+    // report progress until the download is over
+    let mut last_done = None;
+    loop {
+        if interrupted.load(Ordering::Relaxed) {
+            return INTERRUPTED;
+        }
+        reload_blocklists(&session, &blocklists, &mut blocklists_changed);
+        let progress = handle.progress();
+        match progress.state {
+            TorrentState::Seeding | TorrentState::Finished => {
+                println!("successfully wrote {}", progress.name.unwrap_or_default());
+                break;
+            }
+            TorrentState::Failed | TorrentState::Removed => {
+                return match progress.error {
+                    Some(e) => error(format!("download failed: {}", describe(&*e))),
+                    None => error("download failed".to_owned()),
+                };
+            }
+            _ => {}
+        }
+        if progress.total_pieces > 0 && last_done != Some(progress.done_pieces) {
+            let percent = progress.done_pieces as f32 / progress.total_pieces as f32 * 100f32;
+            println!(
+                "({:.2}%) [{}/{}] Downloaded from {} peers",
+                percent, progress.done_pieces, progress.total_pieces, progress.peers,
+            );
+            last_done = Some(progress.done_pieces);
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+
+    // seed until we gave back `ratio` times what we downloaded
+    if let Some(ratio) = seed_ratio {
+        println!("seeding until ratio {}", ratio);
+        loop {
+            if interrupted.load(Ordering::Relaxed) {
+                return INTERRUPTED;
+            }
+            reload_blocklists(&session, &blocklists, &mut blocklists_changed);
+            let progress = handle.progress();
+            if progress.state != TorrentState::Seeding {
+                break;
+            }
+            if progress.uploaded as f64 >= ratio * progress.total_bytes as f64 {
+                println!("uploaded {} bytes", progress.uploaded);
                 break;
             }
-            buf[counter] = item;
-            counter += 1;
+            thread::sleep(Duration::from_millis(500));
         }
+    }
 
-        done_pieces += 1;
+    let blocked = session.blocked_peers();
+    if blocked > 0 {
+        println!("the blocklists turned away {} peers", blocked);
+    }
+    0
+}
 
-        let percent = done_pieces as f32 / our_torrent.piece_hashes.len() as f32 * 100f32;
+fn info(args: impl Iterator<Item = String>) {
+    let mut target = None;
+    for arg in args {
+        positional(arg, &mut target);
+    }
+    let t = parse_torrent(&read_torrent(&target.unwrap_or_else(|| usage())));
 
-        println!(
-            "({:.2}%) [{}/{}] Downloaded piece #{} from {} peers",
-            percent,
-            done_pieces,
-            our_torrent.piece_hashes.len(),
-            res.index,
-            *number_of_peers.lock().unwrap(),
-        );
+    println!("name:\t\t{}", t.name);
+    println!("info hash:\t{}", hex(&t.info_hash));
+    if let Some(hash) = t.info_hash_v2 {
+        println!("info hash v2:\t{}", hex(&hash));
+    }
+    println!("version:\t{:?}", t.version);
+    for (index, tier) in t.trackers.iter().enumerate() {
+        println!("tier {}:\t\t{}", index, tier.join(", "));
     }
+    println!("private:\t{}", if t.private { "yes" } else { "no" });
+    println!("size:\t\t{}", t.length);
+    println!("piece length:\t{}", t.piece_length);
+    println!("pieces:\t\t{}", t.piece_hashes.len());
+    for (index, f) in t.files.iter().enumerate().filter(|(_, f)| !f.padding) {
+        println!("file {}:\t\t{} ({})", index, f.path.display(), f.length);
+    }
+}
 
-    // write buf to file
-    let path = Path::new("downloaded.iso");
-    let display = path.display();
+fn verify(mut args: impl Iterator<Item = String>) {
+    let mut output_dir = PathBuf::from(".");
+    let mut target = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--output-dir" => output_dir = value(&arg, &mut args),
+            _ => positional(arg, &mut target),
+        }
+    }
+    let t = parse_torrent(&read_torrent(&target.unwrap_or_else(|| usage())));
 
-    let mut file = match File::create(&path) {
-        Err(why) => panic!("couldn't create {}: {}", display, why),
-        Ok(file) => file,
+    let pieces = herb::verify_pieces(&output_dir, &t);
+    let good = pieces.iter().filter(|ok| **ok).count();
+    for (index, f) in t.files.iter().enumerate().filter(|(_, f)| !f.padding) {
+        let ok = t.pieces_for_file(index).all(|p| pieces[p as usize]);
+        println!("{}\t{}", if ok { "ok" } else { "BAD" }, f.path.display());
+    }
+    println!("{}/{} pieces ok", good, pieces.len());
+    if good < pieces.len() {
+        process::exit(1);
+    }
+}
+
+fn create(mut args: impl Iterator<Item = String>) {
+    let mut options = create::CreateOptions {
+        created_by: Some(format!("herb/{}", env!("CARGO_PKG_VERSION"))),
+        creation_date: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs() as i64),
+        threads: thread::available_parallelism().map_or(1, |n| n.get()),
+        ..Default::default()
     };
+    let mut output = None;
+    let mut target = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--announce" => {
+                let tier = value::<String>(&arg, &mut args);
+                options
+                    .trackers
+                    .push(tier.split(',').map(str::to_owned).collect());
+            }
+            "--web-seed" => options.web_seeds.push(value(&arg, &mut args)),
+            "--piece-length" => options.piece_length = Some(value(&arg, &mut args)),
+            "--private" => options.private = true,
+            "--comment" => options.comment = Some(value(&arg, &mut args)),
+            "--created-by" => options.created_by = Some(value(&arg, &mut args)),
+            "--creation-date" => options.creation_date = Some(value(&arg, &mut args)),
+            "--no-creation-date" => options.creation_date = None,
+            "--include-hidden" => options.include_hidden = true,
+            "--exclude" => options.exclude.push(value(&arg, &mut args)),
+            "--threads" => options.threads = value(&arg, &mut args),
+            "--output" => output = Some(value::<PathBuf>(&arg, &mut args)),
+            _ => positional(arg, &mut target),
+        }
+    }
+    let target = target.unwrap_or_else(|| usage());
+    if options.trackers.is_empty() {
+        eprintln!("herb: create needs --announce");
+        usage();
+    }
 
-    match file.write_all(&buf) {
-        Err(why) => panic!("couldn't write to {}: {}", display, why),
-        Ok(_) => println!("successfully wrote to {}", display),
+    let path = Path::new(&target);
+    let data = create::create_torrent(path, &options)
+        .unwrap_or_else(|e| fail(format!("couldn't create torrent: {}", e)));
+    let output = output.unwrap_or_else(|| {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        PathBuf::from(format!("{}.torrent", name))
+    });
+    fs::write(&output, &data)
+        .unwrap_or_else(|e| fail(format!("couldn't write {}: {}", output.display(), e)));
+    println!("wrote {}", output.display());
+}
+
+fn scrape(mut args: impl Iterator<Item = String>) {
+    let mut proxy = None;
+    let mut bind = vec![];
+    let mut target = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--proxy" => proxy = Some(parse_proxy(&value::<String>(&arg, &mut args))),
+            "--bind" => bind.append(&mut parse_bind(&value::<String>(&arg, &mut args))),
+            _ => positional(arg, &mut target),
+        }
     }
+    let t = parse_torrent(&read_torrent(&target.unwrap_or_else(|| usage())));
 
-    println!("exit program");
+    let url = t
+        .build_scrape_url()
+        .unwrap_or_else(|e| fail(format!("can't scrape {}: {}", t.announce, e)));
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap_or_else(|e| fail(format!("couldn't start runtime: {}", e)));
+    let stats = runtime
+        .block_on(tracker::scrape(&url, &t.info_hash, proxy.as_ref(), &bind))
+        .unwrap_or_else(|e| fail(format!("scrape failed: {}", e)));
+    println!("seeders:\t{}", stats.complete);
+    println!("leechers:\t{}", stats.incomplete);
+    println!("downloaded:\t{}", stats.downloaded);
 }
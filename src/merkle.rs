@@ -0,0 +1,140 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// v2 torrents hash every file in blocks of 16 KiB, the leaves of its tree
+pub const BLOCK_SIZE: usize = 16384;
+
+pub type Hash = [u8; 32];
+
+// the piece layers of a torrent's files, keyed by their pieces roots
+pub type PieceLayers = HashMap<Hash, Vec<Hash>>;
+
+pub fn sha256(data: &[u8]) -> Hash {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(Sha256::digest(data).as_slice());
+    hash
+}
+
+fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.input(left);
+    hasher.input(right);
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(hasher.result().as_slice());
+    hash
+}
+
+// the hashes of the 16 KiB blocks of data; the last block may be shorter
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE).map(sha256).collect()
+}
+
+// the root of a tree over `width` leaves, a power of two, whose first leaves
+// are `hashes` and the rest are `pad`
+pub fn root(hashes: &[Hash], width: usize, pad: Hash) -> Hash {
+    layers(hashes, width, pad).pop().unwrap()[0]
+}
+
+// the root of a tree over `leaves` zeroed leaf hashes; the piece layer is
+// padded with it past the end of a file
+pub fn pad_hash(leaves: usize) -> Hash {
+    let mut hash = [0u8; 32];
+    let mut width = leaves;
+    while width > 1 {
+        hash = hash_pair(&hash, &hash);
+        width /= 2;
+    }
+    hash
+}
+
+// every layer of the tree, from the leaves up to the root
+pub fn layers(hashes: &[Hash], width: usize, pad: Hash) -> Vec<Vec<Hash>> {
+    let mut layer = hashes.to_vec();
+    layer.resize(width.max(hashes.len()).max(1), pad);
+    let mut layers = vec![layer];
+    while layers[layers.len() - 1].len() > 1 {
+        let next = layers[layers.len() - 1]
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        layers.push(next);
+    }
+    layers
+}
+
+// `length` hashes of the bottom layer from `index`, followed by the uncles of
+// their subtree for up to `proof_layers` layers, as a hashes message carries
+// them (BEP 52)
+pub fn proof(
+    layers: &[Vec<Hash>],
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Option<Vec<Hash>> {
+    let base = layers.first()?;
+    if !length.is_power_of_two() || !index.is_multiple_of(length) || index + length > base.len() {
+        return None;
+    }
+    let mut hashes = base[index..index + length].to_vec();
+    let mut node = index / length;
+    let first = length.trailing_zeros() as usize;
+    for layer in &layers[first..(first + proof_layers).min(layers.len() - 1)] {
+        hashes.push(layer[node ^ 1]);
+        node /= 2;
+    }
+    Some(hashes)
+}
+
+// answers a hash request from the piece layer of a file, the only layer we
+// keep; requests for any other base layer get None
+pub fn piece_layer_hashes(
+    layer: &[Hash],
+    piece_length: usize,
+    base_layer: usize,
+    index: usize,
+    length: usize,
+    proof_layers: usize,
+) -> Option<Vec<Hash>> {
+    let leaves = piece_length / BLOCK_SIZE;
+    if base_layer != leaves.trailing_zeros() as usize {
+        return None;
+    }
+    let layers = layers(layer, layer.len().next_power_of_two(), pad_hash(leaves));
+    proof(&layers, index, length, proof_layers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hash;
+
+    #[test]
+    fn root_pads_with_zero_leaves() {
+        let data = vec![5u8; super::BLOCK_SIZE + 1];
+        let leaves = super::block_hashes(&data);
+        assert_eq!(leaves.len(), 2);
+
+        let root = super::root(&leaves, 4, [0u8; 32]);
+        let left = super::hash_pair(&leaves[0], &leaves[1]);
+        let right = super::hash_pair(&[0u8; 32], &[0u8; 32]);
+        assert_eq!(root, super::hash_pair(&left, &right));
+        assert_eq!(right, super::pad_hash(2));
+    }
+
+    #[test]
+    fn proof_climbs_to_the_root() {
+        let leaves: Vec<Hash> = (0..4u8).map(|i| super::sha256(&[i])).collect();
+        let layers = super::layers(&leaves, 4, [0u8; 32]);
+
+        let hashes = super::proof(&layers, 2, 1, 8).unwrap();
+        assert_eq!(hashes, vec![leaves[2], leaves[3], layers[1][0]]);
+        let hashes = super::proof(&layers, 0, 2, 1).unwrap();
+        assert_eq!(hashes, vec![leaves[0], leaves[1], layers[1][1]]);
+        assert!(super::proof(&layers, 1, 2, 0).is_none());
+
+        let piece_length = 2 * super::BLOCK_SIZE;
+        let hashes = super::piece_layer_hashes(&leaves[..3], piece_length, 1, 2, 1, 1).unwrap();
+        assert_eq!(hashes[0], leaves[2]);
+        assert_eq!(hashes[1], super::pad_hash(2));
+        assert!(super::piece_layer_hashes(&leaves, piece_length, 0, 0, 1, 0).is_none());
+    }
+}
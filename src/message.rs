@@ -1,240 +1,439 @@
 use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fmt;
 
 pub type MessageId = u8;
 
 pub const MSG_CHOKE: MessageId = 0;
 pub const MSG_UNCHOKE: MessageId = 1;
 pub const MSG_INTERESTED: MessageId = 2;
-#[allow(dead_code)]
 pub const MSG_NOT_INTERESTED: MessageId = 3;
 pub const MSG_HAVE: MessageId = 4;
 pub const MSG_BITFIELD: MessageId = 5;
 pub const MSG_REQUEST: MessageId = 6;
 pub const MSG_PIECE: MessageId = 7;
-#[allow(dead_code)]
 pub const MSG_CANCEL: MessageId = 8;
-
-// Message stores ID and payload of a message
-#[derive(Debug, Eq, PartialEq)]
-pub struct Message {
-    pub id: MessageId,
-    pub payload: Vec<u8>,
+pub const MSG_PORT: MessageId = 9;
+pub const MSG_EXTENDED: MessageId = 20;
+pub const MSG_HASH_REQUEST: MessageId = 21;
+pub const MSG_HASHES: MessageId = 22;
+pub const MSG_HASH_REJECT: MessageId = 23;
+
+// the longest frame we accept from a peer; a 16 KiB block needs a little over
+// 16 KiB and the bitfield of a torrent with eight million pieces fits in 1 MiB
+pub const MAX_FRAME_LENGTH: u32 = 1 << 20;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MessageError {
+    FrameTooLong,
+    InvalidPayload,
 }
 
-impl Message {
-    // Serialize serializes a message into a buffer of the form
-    // <length prefix><message ID><payload>
-    // Interprets `nil` as a keep-alive message
-    pub fn serialize(&self) -> Vec<u8> {
-        let length: u32 = u32::try_from(self.payload.len()).unwrap() + 1; // +1 for id
-
-        let mut buf: Vec<u8> = Vec::new();
-
-        // transforms length to big endian bytes
-        let length_be: [u8; 4] = length.to_be_bytes();
-        for byte in length_be.iter() {
-            // add length as 4 bytes into buf
-            buf.push(*byte);
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageError::FrameTooLong => write!(f, "message is too long"),
+            MessageError::InvalidPayload => write!(f, "message has an invalid payload"),
         }
+    }
+}
 
-        // 5th byte in buf is the msg id
-        buf.push(self.id);
+impl Error for MessageError {}
+
+// HashRange names hashes of a v2 file's merkle tree: `length` hashes of the
+// layer `base_layer` above the 16 KiB leaves starting at `index`, plus uncle
+// hashes for `proof_layers` layers up (BEP 52)
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HashRange {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
 
-        // 6th onwards is the optional payload
-        for part in self.payload.iter() {
-            buf.push(*part);
-        }
+// Message is one frame of the peer wire protocol
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    HashRequest(HashRange),
+    Hashes(HashRange, Vec<[u8; 32]>),
+    HashReject(HashRange),
+    // a message we don't speak, which is ignored like BEP 3 asks
+    Unknown(MessageId),
+}
 
-        buf
-    }
+// Decoder turns a stream of bytes into messages; bytes can be fed in any
+// chunks and a message is returned once its whole frame has arrived
+pub struct Decoder {
+    buf: Vec<u8>,
+    max_frame_length: u32,
 }
 
-pub fn parse_piece(index: u32, buf: &mut Vec<u8>, msg: Message) -> u32 {
-    if msg.payload.len() < 8 {
-        panic!("Payload too short. {} < 8", msg.payload.len());
-    }
-
-    let parsed_index = u32::from_be_bytes([
-        msg.payload[0],
-        msg.payload[1],
-        msg.payload[2],
-        msg.payload[3],
-    ]);
-    if parsed_index != index {
-        panic!("Expected index {}, got {}", index, parsed_index);
-    }
-
-    let begin = u32::from_be_bytes([
-        msg.payload[4],
-        msg.payload[5],
-        msg.payload[6],
-        msg.payload[7],
-    ]);
-    if begin as usize >= buf.len() {
-        panic!("Begin offset too high. {} >= {}", begin, buf.len());
-    }
-
-    if begin + msg.payload.len() as u32 - 8 > buf.len() as u32 {
-        panic!(
-            "Data too long [{}] for offset {} with length {}",
-            msg.payload.len() - 8,
-            begin,
-            buf.len()
-        );
-    }
-    for (index, _) in msg.payload.iter().enumerate() {
-        if index + 8 == msg.payload.len() {
-            break;
-        }
-        buf[begin as usize + index] = msg.payload[index + 8];
+pub fn new_decoder() -> Decoder {
+    Decoder {
+        buf: Vec::new(),
+        max_frame_length: MAX_FRAME_LENGTH,
     }
-    return msg.payload.len() as u32 - 8;
 }
 
-pub fn parse_have(msg: Message) -> u32 {
-    if msg.payload.len() != 4 {
-        panic!(
-            "Expected payload length 4, got length {}",
-            msg.payload.len()
-        );
-    }
-    let index = u32::from_be_bytes([
-        msg.payload[0],
-        msg.payload[1],
-        msg.payload[2],
-        msg.payload[3],
-    ]);
-    return index;
+fn read_u32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
 }
 
-pub fn format_have(index: i64) -> Message {
-    let mut payload = vec![];
+impl Message {
+    // Serialize serializes a message into a buffer of the form
+    // <length prefix><message ID><payload>
+    // A keep-alive is just a zero length prefix
+    pub fn serialize(&self) -> Vec<u8> {
+        let (id, payload) = match self {
+            Message::KeepAlive => return vec![0, 0, 0, 0],
+            Message::Choke => (MSG_CHOKE, vec![]),
+            Message::Unchoke => (MSG_UNCHOKE, vec![]),
+            Message::Interested => (MSG_INTERESTED, vec![]),
+            Message::NotInterested => (MSG_NOT_INTERESTED, vec![]),
+            Message::Have(index) => (MSG_HAVE, index.to_be_bytes().to_vec()),
+            Message::Bitfield(bitfield) => (MSG_BITFIELD, bitfield.clone()),
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => (MSG_REQUEST, triple(*index, *begin, *length)),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let mut payload = Vec::with_capacity(8 + block.len());
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(block);
+                (MSG_PIECE, payload)
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => (MSG_CANCEL, triple(*index, *begin, *length)),
+            Message::Port(port) => (MSG_PORT, port.to_be_bytes().to_vec()),
+            Message::Extended { id, payload } => {
+                let mut p = Vec::with_capacity(1 + payload.len());
+                p.push(*id);
+                p.extend_from_slice(payload);
+                (MSG_EXTENDED, p)
+            }
+            Message::HashRequest(range) => (MSG_HASH_REQUEST, range.serialize()),
+            Message::Hashes(range, hashes) => {
+                let mut payload = range.serialize();
+                for hash in hashes {
+                    payload.extend_from_slice(hash);
+                }
+                (MSG_HASHES, payload)
+            }
+            Message::HashReject(range) => (MSG_HASH_REJECT, range.serialize()),
+            Message::Unknown(id) => (*id, vec![]),
+        };
 
-    let index_be = (index as u32).to_be_bytes();
-    assert!(index_be.len() == 4);
-    for byte in index_be.iter() {
-        payload.push(*byte);
-    }
-    assert!(payload.len() == 4);
+        let length: u32 = u32::try_from(payload.len()).unwrap() + 1; // +1 for id
 
-    Message {
-        id: MSG_HAVE,
-        payload,
+        let mut buf: Vec<u8> = Vec::with_capacity(4 + length as usize);
+        buf.extend_from_slice(&length.to_be_bytes());
+        buf.push(id);
+        buf.extend_from_slice(&payload);
+        buf
     }
-}
 
-pub fn format_request(index: i64, begin: i64, length: i64) -> Message {
-    let mut payload = vec![];
+    // parses the body of a frame, i.e. everything after the length prefix
+    pub fn parse(frame: &[u8]) -> Result<Message, MessageError> {
+        let (id, payload) = match frame.split_first() {
+            None => return Ok(Message::KeepAlive),
+            Some((id, payload)) => (*id, payload),
+        };
 
-    let index_be = (index as u32).to_be_bytes();
-    if index_be.len() != 4 {
-        println!("index_be.len() is {}", index_be.len());
-    }
-    for byte in index_be.iter() {
-        payload.push(*byte);
-    }
+        let expect_length = |length: usize| {
+            if payload.len() == length {
+                Ok(())
+            } else {
+                Err(MessageError::InvalidPayload)
+            }
+        };
 
-    let begin_be = (begin as u32).to_be_bytes();
-    if begin_be.len() != 4 {
-        println!("begin_be.len() is {}", begin_be.len());
-    }
-    for byte in begin_be.iter() {
-        payload.push(*byte);
+        match id {
+            MSG_CHOKE => expect_length(0).map(|_| Message::Choke),
+            MSG_UNCHOKE => expect_length(0).map(|_| Message::Unchoke),
+            MSG_INTERESTED => expect_length(0).map(|_| Message::Interested),
+            MSG_NOT_INTERESTED => expect_length(0).map(|_| Message::NotInterested),
+            MSG_HAVE => expect_length(4).map(|_| Message::Have(read_u32(payload))),
+            MSG_BITFIELD => Ok(Message::Bitfield(payload.to_vec())),
+            MSG_REQUEST => expect_length(12).map(|_| Message::Request {
+                index: read_u32(&payload[0..4]),
+                begin: read_u32(&payload[4..8]),
+                length: read_u32(&payload[8..12]),
+            }),
+            MSG_PIECE => {
+                if payload.len() < 8 {
+                    return Err(MessageError::InvalidPayload);
+                }
+                Ok(Message::Piece {
+                    index: read_u32(&payload[0..4]),
+                    begin: read_u32(&payload[4..8]),
+                    block: payload[8..].to_vec(),
+                })
+            }
+            MSG_CANCEL => expect_length(12).map(|_| Message::Cancel {
+                index: read_u32(&payload[0..4]),
+                begin: read_u32(&payload[4..8]),
+                length: read_u32(&payload[8..12]),
+            }),
+            MSG_PORT => expect_length(2)
+                .map(|_| Message::Port(u16::from_be_bytes([payload[0], payload[1]]))),
+            MSG_EXTENDED => match payload.split_first() {
+                Some((ext_id, rest)) => Ok(Message::Extended {
+                    id: *ext_id,
+                    payload: rest.to_vec(),
+                }),
+                None => Err(MessageError::InvalidPayload),
+            },
+            MSG_HASH_REQUEST => expect_length(HASH_RANGE_LENGTH)
+                .map(|_| Message::HashRequest(HashRange::parse(payload))),
+            MSG_HASHES => {
+                if payload.len() < HASH_RANGE_LENGTH
+                    || !(payload.len() - HASH_RANGE_LENGTH).is_multiple_of(32)
+                {
+                    return Err(MessageError::InvalidPayload);
+                }
+                let hashes = payload[HASH_RANGE_LENGTH..]
+                    .chunks(32)
+                    .map(|c| c.try_into().unwrap())
+                    .collect();
+                Ok(Message::Hashes(HashRange::parse(payload), hashes))
+            }
+            MSG_HASH_REJECT => expect_length(HASH_RANGE_LENGTH)
+                .map(|_| Message::HashReject(HashRange::parse(payload))),
+            _ => Ok(Message::Unknown(id)),
+        }
     }
+}
 
-    let length_be = (length as u32).to_be_bytes();
-    if length_be.len() != 4 {
-        println!("length_be.len() is {}", length_be.len());
-    }
-    for byte in length_be.iter() {
-        payload.push(*byte);
+// a hash range is the pieces root followed by four integers
+const HASH_RANGE_LENGTH: usize = 48;
+
+impl HashRange {
+    fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(HASH_RANGE_LENGTH);
+        payload.extend_from_slice(&self.pieces_root);
+        payload.extend_from_slice(&self.base_layer.to_be_bytes());
+        payload.extend_from_slice(&self.index.to_be_bytes());
+        payload.extend_from_slice(&self.length.to_be_bytes());
+        payload.extend_from_slice(&self.proof_layers.to_be_bytes());
+        payload
     }
 
-    assert_eq!(payload.len(), 12);
-    Message {
-        id: MSG_REQUEST,
-        payload,
+    fn parse(payload: &[u8]) -> HashRange {
+        HashRange {
+            pieces_root: payload[0..32].try_into().unwrap(),
+            base_layer: read_u32(&payload[32..36]),
+            index: read_u32(&payload[36..40]),
+            length: read_u32(&payload[40..44]),
+            proof_layers: read_u32(&payload[44..48]),
+        }
     }
 }
 
-pub fn new_message(data: Vec<u8>) -> Message {
-    let length_u32: u32 = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
-    let length: usize = length_u32.try_into().unwrap();
+fn triple(index: u32, begin: u32, length: u32) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(12);
+    payload.extend_from_slice(&index.to_be_bytes());
+    payload.extend_from_slice(&begin.to_be_bytes());
+    payload.extend_from_slice(&length.to_be_bytes());
+    payload
+}
 
-    let msg_id: u8 = data[4];
+impl Decoder {
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
 
-    let mut payload: Vec<u8> = Vec::new();
-    // check if we have a payload
-    if length > 1 {
-        // payload starts after 4 items of length and 1 of id = 5
-        // end is length+4 because `length` variable does not count itself (4 items)
-        for index in 5..length + 4 {
-            payload.push(data[index]);
+    // returns the next complete message, or None if more bytes are needed
+    pub fn decode(&mut self) -> Result<Option<Message>, MessageError> {
+        if self.buf.len() < 4 {
+            return Ok(None);
+        }
+        let length = read_u32(&self.buf);
+        if length > self.max_frame_length {
+            return Err(MessageError::FrameTooLong);
         }
+        let end = 4 + length as usize;
+        if self.buf.len() < end {
+            return Ok(None);
+        }
+        let msg = Message::parse(&self.buf[4..end]);
+        self.buf.drain(..end);
+        msg.map(Some)
     }
+}
 
-    // println!("NEW_MESSAGE: id: {:?}", msg_id);
-    // println!("NEW_MESSAGE: payload: {:?}", payload);
-
-    Message {
-        id: msg_id,
-        payload,
+// copies a block into the buffer of its piece, returning the number of bytes
+// copied; the block must fit entirely inside the piece
+pub fn copy_block(buf: &mut [u8], begin: u32, block: &[u8]) -> Result<usize, MessageError> {
+    let begin = begin as usize;
+    if begin >= buf.len() || block.len() > buf.len() - begin {
+        return Err(MessageError::InvalidPayload);
     }
+    buf[begin..begin + block.len()].copy_from_slice(block);
+    Ok(block.len())
 }
 
 #[cfg(test)]
 mod tests {
+    use super::Message;
 
     #[test]
     fn message_serialize_works() {
-        let msg = super::Message {
-            id: super::MSG_CANCEL,
-            payload: vec![1, 2, 3],
-        };
+        let msg = Message::Have(7);
         let s = msg.serialize();
-        assert_eq!(s, [0, 0, 0, 4, 8, 1, 2, 3]);
+        assert_eq!(s, [0, 0, 0, 5, 4, 0, 0, 0, 7]);
     }
 
     #[test]
     fn message_read_works() {
-        let serialized: Vec<u8> = vec![0, 0, 0, 4, 8, 1, 2, 3];
-        let de = super::new_message(serialized);
+        let mut decoder = super::new_decoder();
+        decoder.extend(&[0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
 
-        let msg = super::Message {
-            id: super::MSG_CANCEL,
-            payload: vec![1, 2, 3],
+        let msg = Message::Cancel {
+            index: 1,
+            begin: 2,
+            length: 3,
         };
-        assert_eq!(de, msg);
+        assert_eq!(decoder.decode(), Ok(Some(msg)));
     }
 
     #[test]
     fn message_read_no_payload() {
-        let serialized: Vec<u8> = vec![0, 0, 0, 1, 2];
-        let de = super::new_message(serialized);
+        let mut decoder = super::new_decoder();
+        decoder.extend(&[0, 0, 0, 1, 2]);
+        assert_eq!(decoder.decode(), Ok(Some(Message::Interested)));
+    }
 
-        let msg = super::Message {
-            id: super::MSG_INTERESTED,
-            payload: vec![],
+    #[test]
+    fn message_roundtrip_works() {
+        let range = super::HashRange {
+            pieces_root: [9u8; 32],
+            base_layer: 2,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
         };
-        assert_eq!(de, msg);
+        let messages = vec![
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::NotInterested,
+            Message::Bitfield(vec![0b1010_0000]),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 4,
+                begin: 2,
+                block: vec![0xaa, 0xbb],
+            },
+            Message::Port(6881),
+            Message::Extended {
+                id: 0,
+                payload: b"de".to_vec(),
+            },
+            Message::HashRequest(range.clone()),
+            Message::Hashes(range.clone(), vec![[1u8; 32], [2u8; 32]]),
+            Message::HashReject(range),
+        ];
+        let mut decoder = super::new_decoder();
+        for msg in messages.iter() {
+            decoder.extend(&msg.serialize());
+        }
+        for msg in messages {
+            assert_eq!(decoder.decode(), Ok(Some(msg)));
+        }
+        assert_eq!(decoder.decode(), Ok(None));
     }
 
     #[test]
-    fn parse_piece_works() {
-        let index = 4;
+    fn decoder_waits_for_whole_frame() {
+        let bytes = Message::Have(3).serialize();
+        let mut decoder = super::new_decoder();
+        for byte in &bytes[..bytes.len() - 1] {
+            decoder.extend(&[*byte]);
+            assert_eq!(decoder.decode(), Ok(None));
+        }
+        decoder.extend(&bytes[bytes.len() - 1..]);
+        assert_eq!(decoder.decode(), Ok(Some(Message::Have(3))));
+    }
+
+    #[test]
+    fn decoder_rejects_bad_frames() {
+        let mut decoder = super::new_decoder();
+        decoder.extend(&[0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(decoder.decode(), Err(super::MessageError::FrameTooLong));
+
+        // a have with a short payload
+        let mut decoder = super::new_decoder();
+        decoder.extend(&[0, 0, 0, 3, 4, 0, 0]);
+        assert_eq!(decoder.decode(), Err(super::MessageError::InvalidPayload));
+
+        let mut decoder = super::new_decoder();
+        decoder.extend(&[0, 0, 0, 1, 99]);
+        assert_eq!(decoder.decode(), Ok(Some(super::Message::Unknown(99))));
+
+        // a fast extension HAVE ALL is skipped, and the next message still
+        // decodes
+        let mut decoder = super::new_decoder();
+        decoder.extend(&[0, 0, 0, 1, 14, 0, 0, 0, 1, 1]);
+        assert_eq!(decoder.decode(), Ok(Some(super::Message::Unknown(14))));
+        assert_eq!(decoder.decode(), Ok(Some(super::Message::Unchoke)));
+    }
+
+    #[test]
+    fn copy_block_works() {
         let mut buf = vec![0u8; 10];
-        let msg = super::Message {
-            id: super::MSG_PIECE,
-            payload: vec![
-                0x00, 0x00, 0x00, 0x04, // Index
-                0x00, 0x00, 0x00, 0x02, // Begin
-                0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff, // Block]
-            ],
-        };
+        let block = vec![0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
         let expected_buf = vec![0x00, 0x00, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff, 0x00, 0x00];
         let expected_length = 6;
-        let test_length = super::parse_piece(index, &mut buf, msg);
+        let test_length = super::copy_block(&mut buf, 2, &block).unwrap();
         assert_eq!(test_length, expected_length);
         assert_eq!(buf, expected_buf);
     }
+
+    #[test]
+    fn copy_block_rejects_overflow() {
+        let mut buf = vec![0u8; 10];
+        assert!(super::copy_block(&mut buf, 8, &[1, 2, 3]).is_err());
+        assert!(super::copy_block(&mut buf, 10, &[]).is_err());
+    }
 }
@@ -0,0 +1,152 @@
+use serde_bencode::ser;
+use sha1::{Digest, Sha1};
+use std::error::Error;
+use std::fmt;
+use std::net::IpAddr;
+
+use crate::client;
+use crate::extension;
+use crate::message;
+use crate::p2p;
+use crate::proxy;
+
+// the largest info dictionary we are willing to download
+const MAX_METADATA_SIZE: i64 = 16 * 1024 * 1024;
+
+// how many messages we read from a peer before giving up on it
+const MAX_MESSAGES: usize = 1000;
+
+#[derive(Debug)]
+pub enum MetadataError {
+    NoPeers,
+    Peer(client::ClientError),
+    NotSupported,
+    Rejected,
+    InvalidMetadata,
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetadataError::NoPeers => write!(f, "no peers to ask"),
+            MetadataError::Peer(e) => write!(f, "{}", e),
+            MetadataError::NotSupported => write!(f, "peer doesn't share metadata"),
+            MetadataError::Rejected => write!(f, "peer rejected the request"),
+            MetadataError::InvalidMetadata => write!(f, "metadata doesn't match the info hash"),
+        }
+    }
+}
+
+impl Error for MetadataError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MetadataError::Peer(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// downloads the info dictionary of a torrent we only know the info hash of,
+// asking one peer after the other until one of them delivers (BEP 9)
+pub async fn fetch_metadata(
+    peers: Vec<p2p::Peer>,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    proxy: Option<&proxy::Proxy>,
+    bind: &[IpAddr],
+) -> Result<Vec<u8>, MetadataError> {
+    let mut last_error = MetadataError::NoPeers;
+    for p in peers {
+        let peer_ip = p.ip;
+        let mut c = match client::new(p, peer_id, info_hash, None, false, proxy, bind).await {
+            Ok(c) => c,
+            Err(e) => {
+                println!("{}: metadata: DROPPED, with error: {}", peer_ip, e);
+                last_error = MetadataError::Peer(e);
+                continue;
+            }
+        };
+        match fetch_from_peer(&mut c, &info_hash).await {
+            Ok(info) => return Ok(info),
+            Err(e) => {
+                println!("{}: metadata: failed with error: {}", peer_ip, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+async fn fetch_from_peer(
+    c: &mut client::Client,
+    info_hash: &[u8; 20],
+) -> Result<Vec<u8>, MetadataError> {
+    let mut buf: Vec<u8> = vec![];
+    let mut received: Vec<bool> = vec![];
+    for _ in 0..MAX_MESSAGES {
+        // once we know the size, ask for every piece of the metadata
+        if received.is_empty() {
+            if let Some(hs) = &c.extended_handshake {
+                let (id, size) = match (hs.ut_metadata(), hs.metadata_size) {
+                    (Some(id), Some(size)) if size > 0 && size <= MAX_METADATA_SIZE => (id, size),
+                    _ => return Err(MetadataError::NotSupported),
+                };
+                buf = vec![0u8; size as usize];
+                let num_pieces = (size as usize).div_ceil(extension::METADATA_PIECE_SIZE);
+                received = vec![false; num_pieces];
+                for piece in 0..num_pieces {
+                    let req = extension::MetadataMessage {
+                        msg_type: extension::METADATA_REQUEST,
+                        piece: piece as i64,
+                        total_size: None,
+                    };
+                    let msg = message::Message::Extended {
+                        id,
+                        payload: ser::to_bytes(&req).unwrap(),
+                    };
+                    c.send_message(msg).await.map_err(MetadataError::Peer)?;
+                }
+            }
+        }
+
+        let (id, payload) = match c.read_client().await {
+            Ok(message::Message::Extended { id, payload }) => (id, payload),
+            Ok(_) => continue,
+            Err(e) => return Err(MetadataError::Peer(e)),
+        };
+        if id == extension::EXT_HANDSHAKE {
+            c.handle_extended_handshake(id, &payload);
+            continue;
+        }
+        if id != extension::UT_METADATA_ID || received.is_empty() {
+            continue;
+        }
+
+        let (msg, data) = match extension::parse_metadata_message(&payload) {
+            Some(parsed) => parsed,
+            None => return Err(MetadataError::InvalidMetadata),
+        };
+        if msg.msg_type == extension::METADATA_REJECT {
+            return Err(MetadataError::Rejected);
+        }
+        if msg.msg_type != extension::METADATA_DATA {
+            continue;
+        }
+        let piece = msg.piece as usize;
+        let begin = piece * extension::METADATA_PIECE_SIZE;
+        if piece >= received.len() || message::copy_block(&mut buf, begin as u32, data).is_err() {
+            return Err(MetadataError::InvalidMetadata);
+        }
+        received[piece] = true;
+
+        if received.iter().all(|r| *r) {
+            let mut hasher = Sha1::new();
+            hasher.input(&buf);
+            if hasher.result().as_slice() != info_hash {
+                return Err(MetadataError::InvalidMetadata);
+            }
+            return Ok(buf);
+        }
+    }
+    Err(MetadataError::NotSupported)
+}
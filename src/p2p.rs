@@ -1,30 +1,75 @@
 use crossbeam::channel::{Receiver, Sender};
-use sha1::{Digest, Sha1};
-use std::convert::TryInto;
-use std::net::IpAddr;
-use std::time::Duration;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
 
+use crate::bitfield;
+use crate::choker;
 use crate::client;
+use crate::extension;
+use crate::handshake;
+use crate::merkle;
 use crate::message;
-use crate::p2p;
+use crate::peer_id;
+use crate::pipeline;
+use crate::proxy;
+use crate::ratelimit;
+use crate::smartban;
+use crate::storage;
+use crate::torrent;
 
-pub static PEER_ID_STRING: &str = "kjh29409k8hj0wgej6c1";
+// how long a connection stays open when neither side wants anything, so the
+// peer gets a chance to tell us it is interested
+const IDLE_GRACE: Duration = Duration::from_secs(10);
 
-#[derive(Debug, Clone)]
+// how often we tell a peer about the peers we connected to and lost (BEP 11)
+const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
 pub enum PieceError {
-    MessageParsingFailure,
+    Peer(client::ClientError),
+    // a block that doesn't fit into the piece it claims to belong to
+    InvalidBlock(SocketAddr, u32),
+}
+
+impl fmt::Display for PieceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PieceError::Peer(e) => write!(f, "{}", e),
+            PieceError::InvalidBlock(addr, index) => {
+                write!(f, "{}: invalid block for piece #{}", addr, index)
+            }
+        }
+    }
+}
+
+impl Error for PieceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PieceError::Peer(e) => Some(e),
+            PieceError::InvalidBlock(..) => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct Peer {
     pub ip: IpAddr,
     pub port: u16,
+    // the peer id the tracker told us, which the peer's handshake must carry
+    pub id: Option<[u8; 20]>,
 }
 
 #[derive(Copy, Clone)]
 pub struct PieceWork {
     pub index: i64,
-    pub hash: [u8; 20],
+    pub hash: torrent::PieceHash,
     pub length: i64,
 }
 
@@ -33,230 +78,977 @@ pub struct PieceResult {
     pub buf: Vec<u8>,
 }
 
-pub struct PieceProgress<'a> {
-    pub index: i64,
-    pub client: &'a mut client::Client,
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum BlockState {
+    Missing,
+    Requested,
+    Received,
+}
+
+// PieceProgress tracks the blocks of one piece that is being downloaded
+pub struct PieceProgress {
+    pub work: PieceWork,
+    pub buf: Vec<u8>,
+    blocks: Vec<BlockState>,
+    // who sent each block
+    pub sources: Vec<Option<SocketAddr>>,
+}
+
+// Swarm is everything a download worker shares with the other workers of its
+// torrent; once `stop` turns true they hand back their work and disconnect
+#[derive(Clone)]
+pub struct Swarm {
+    pub info_hash: [u8; 20],
+    // the session's peer id
+    pub peer_id: [u8; 20],
+    pub work_snd: Sender<PieceWork>,
+    pub work_rcv: Receiver<PieceWork>,
+    pub hasher: Hasher,
+    pub peers: choker::Peers,
+    pub stop: watch::Receiver<bool>,
+    pub have: Arc<Mutex<bitfield::Bitfield>>,
+    pub storage: Arc<storage::Storage>,
+    // the torrent's and the session's buckets; each peer adds its own
+    pub download_buckets: Vec<ratelimit::SharedBucket>,
+    pub upload_buckets: Vec<ratelimit::SharedBucket>,
+    pub peer_limits: ratelimit::Limits,
+    // payload bytes sent to all peers of the torrent
+    pub uploaded: Arc<AtomicI64>,
+    // the torrent's info dictionary, for peers that ask for it
+    pub metadata: Arc<Vec<u8>>,
+    // piece layers of v2 files, for peers that ask for hashes
+    pub piece_layers: Arc<merkle::PieceLayers>,
+    pub smart_ban: Arc<Mutex<smartban::SmartBan>>,
+    // what outgoing connections go through, if anything
+    pub proxy: Option<Arc<proxy::Proxy>>,
+    // the local addresses outgoing connections are made from; empty for any
+    pub bind: Arc<Vec<IpAddr>>,
+    // None for private torrents, whose peers only come from their trackers
+    pub pex: Option<Pex>,
+}
+
+// Pex is the peer exchange (BEP 11) of a torrent: the peers we connected to,
+// which every peer hears about, and where the peers they name go
+#[derive(Clone)]
+pub struct Pex {
+    pub connected: Arc<Mutex<HashSet<SocketAddr>>>,
+    pub found: PexSink,
+}
+
+// PexSink takes the peers of a swarm hash that another peer named
+pub type PexSink = Arc<dyn Fn([u8; 20], Vec<Peer>) + Send + Sync>;
+
+// HashJob is a fully downloaded piece waiting to be verified, with the
+// sender of each of its blocks and the torrent it belongs to
+pub struct HashJob {
+    pub work: PieceWork,
     pub buf: Vec<u8>,
-    pub downloaded: i64,
-    pub requested: i64,
-    pub backlog: i64,
+    pub sources: Vec<Option<SocketAddr>>,
+    pub torrent: Arc<Verdicts>,
+    // told whether the piece passed, for a sender that wants to know
+    pub report: Option<Sender<bool>>,
 }
 
-impl PieceProgress<'_> {
-    pub fn read_message_pp(&mut self) -> Option<PieceError> {
-        match self.client.read_client() {
-            None => Some(PieceError::MessageParsingFailure),
-            Some(msg) => {
-                if msg.id == message::MSG_UNCHOKE {
-                    self.client.choked = false;
-                } else if msg.id == message::MSG_CHOKE {
-                    self.client.choked = true;
-                } else if msg.id == message::MSG_HAVE {
-                    let index = message::parse_have(msg);
-                    self.client.bitfield.set_piece(index as i64);
-                } else if msg.id == message::MSG_PIECE {
-                    let n = message::parse_piece(self.index as u32, &mut self.buf, msg);
-                    self.downloaded += n as i64;
-                    self.backlog -= 1;
+// Verdicts is where the session's hash workers send what they found out
+// about the pieces of one torrent: failed pieces go back on its work queue
+// and good ones to its controller
+pub struct Verdicts {
+    pub work_snd: Sender<PieceWork>,
+    pub result_snd: Sender<PieceResult>,
+    pub smart_ban: Arc<Mutex<smartban::SmartBan>>,
+}
+
+// Hasher hands the finished pieces of one torrent to the hash workers of the
+// session
+#[derive(Clone)]
+pub struct Hasher {
+    pub jobs: Sender<HashJob>,
+    pub torrent: Arc<Verdicts>,
+}
+
+// Scheduler keeps requests outstanding across several pieces on one
+// connection, so the pipe doesn't drain at piece boundaries
+pub struct Scheduler {
+    pub active: Vec<PieceProgress>,
+}
+
+pub fn new_piece_progress(work: PieceWork) -> PieceProgress {
+    let num_blocks = (work.length + pipeline::BLOCK_SIZE - 1) / pipeline::BLOCK_SIZE;
+    PieceProgress {
+        work,
+        buf: vec![0u8; work.length as usize],
+        blocks: vec![BlockState::Missing; num_blocks as usize],
+        sources: vec![None; num_blocks as usize],
+    }
+}
+
+pub fn new_scheduler() -> Scheduler {
+    Scheduler { active: vec![] }
+}
+
+impl PieceProgress {
+    // returns the offset and length of a block; the last block might be
+    // shorter than the typical block
+    fn block_bounds(&self, block: usize) -> (i64, i64) {
+        let begin = block as i64 * pipeline::BLOCK_SIZE;
+        let length = pipeline::BLOCK_SIZE.min(self.work.length - begin);
+        (begin, length)
+    }
+
+    fn has_missing(&self) -> bool {
+        self.blocks.contains(&BlockState::Missing)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.blocks.iter().all(|b| *b == BlockState::Received)
+    }
+}
+
+impl Scheduler {
+    // sends requests until the pipeline is full, taking on new pieces from the
+    // work queue once every block of the active ones has been requested
+    pub async fn fill_requests(
+        &mut self,
+        c: &mut client::Client,
+        work_snd: &Sender<PieceWork>,
+        work_rcv: &Receiver<PieceWork>,
+    ) -> Result<(), PieceError> {
+        loop {
+            if !c.choked {
+                let max_backlog = c.pipeline.queue_depth();
+                for piece in self.active.iter_mut() {
+                    for block in 0..piece.blocks.len() {
+                        if c.pipeline.backlog() >= max_backlog {
+                            return Ok(());
+                        }
+                        if piece.blocks[block] != BlockState::Missing {
+                            continue;
+                        }
+                        let (begin, length) = piece.block_bounds(block);
+                        c.send_request(piece.work.index, begin, length)
+                            .await
+                            .map_err(PieceError::Peer)?;
+                        c.pipeline
+                            .request_sent(piece.work.index, begin, Instant::now());
+                        piece.blocks[block] = BlockState::Requested;
+                    }
                 }
-                None
             }
+
+            // while choked, hold on to a single piece to wait for the unchoke
+            if self.active.iter().any(|p| p.has_missing()) || (c.choked && !self.active.is_empty())
+            {
+                return Ok(());
+            }
+            match take_work(&c.bitfield, work_snd, work_rcv) {
+                Some(work) => self.active.push(new_piece_progress(work)),
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // reads one message from the peer and returns the pieces it completed
+    pub async fn read_message(
+        &mut self,
+        c: &mut client::Client,
+    ) -> Result<Vec<PieceProgress>, PieceError> {
+        let msg = c.read_client().await.map_err(PieceError::Peer)?;
+
+        match msg {
+            message::Message::Unchoke => c.choked = false,
+            message::Message::Choke => {
+                // the peer drops our queued requests, ask for them again once
+                // we are unchoked
+                c.choked = true;
+                c.pipeline.clear();
+                for piece in self.active.iter_mut() {
+                    for block in piece.blocks.iter_mut() {
+                        if *block == BlockState::Requested {
+                            *block = BlockState::Missing;
+                        }
+                    }
+                }
+            }
+            message::Message::Interested => c.peer_interested = true,
+            message::Message::NotInterested => c.peer_interested = false,
+            message::Message::Extended { id, payload } => {
+                c.handle_extended(id, &payload)
+                    .await
+                    .map_err(PieceError::Peer)?;
+            }
+            message::Message::Have(index) => c.bitfield.set_piece(index as i64),
+            message::Message::Bitfield(array) => c.bitfield = bitfield::Bitfield { array },
+            // requests of choked peers are ignored, and so are oversized ones
+            // and those beyond the queue we advertised
+            message::Message::Request {
+                index,
+                begin,
+                length,
+            } if !c.am_choking
+                && length as i64 <= pipeline::BLOCK_SIZE
+                && c.peer_requests.len() < extension::REQQ as usize =>
+            {
+                c.peer_requests.push_back((index, begin, length));
+            }
+            message::Message::HashRequest(range)
+                if c.peer_hash_requests.len() < extension::REQQ as usize =>
+            {
+                c.peer_hash_requests.push_back(range)
+            }
+            message::Message::Cancel {
+                index,
+                begin,
+                length,
+            } => c.peer_requests.retain(|r| *r != (index, begin, length)),
+            message::Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                // blocks of pieces we no longer work on are dropped
+                let addr = c.addr();
+                let piece_index = index;
+                let index = index as i64;
+                let position = match self.active.iter().position(|p| p.work.index == index) {
+                    Some(position) => position,
+                    None => return Ok(vec![]),
+                };
+                let piece = &mut self.active[position];
+                // only a whole block we asked for counts; short, unaligned
+                // and unrequested ones are dropped
+                let block_index = (begin as i64 / pipeline::BLOCK_SIZE) as usize;
+                let requested = block_index < piece.blocks.len()
+                    && piece.blocks[block_index] == BlockState::Requested
+                    && piece.block_bounds(block_index) == (begin as i64, block.len() as i64);
+                if !requested {
+                    return Ok(vec![]);
+                }
+                let n = message::copy_block(&mut piece.buf, begin, &block)
+                    .map_err(|_| PieceError::InvalidBlock(addr, piece_index))?;
+                c.downloaded += n as i64;
+                c.pipeline
+                    .block_received(index, begin as i64, n as i64, Instant::now());
+                piece.blocks[block_index] = BlockState::Received;
+                piece.sources[block_index] = Some(addr);
+
+                if piece.is_complete() {
+                    return Ok(vec![self.active.remove(position)]);
+                }
+            }
+            // extensions we don't speak, like the fast extension, are skipped
+            message::Message::Unknown(_) => {}
+            _ => {}
+        }
+        Ok(vec![])
+    }
+
+    // puts every unfinished piece back on the work queue
+    pub fn abandon(&mut self, work_snd: &Sender<PieceWork>) {
+        for piece in self.active.drain(..) {
+            work_snd.send(piece.work).unwrap();
         }
     }
 }
 
-pub fn check_integrity(pw: &PieceWork, buf: &Vec<u8>) -> bool {
-    // println!("CHECK: buf: {:?}", buf);
-    let mut hasher = Sha1::new();
-    hasher.input(buf);
-    let sum_hex = hasher.result();
-
-    let mut sum_bytes = [0u8; 20];
-    sum_bytes.copy_from_slice(sum_hex.as_slice());
-    // println!("CHECK: sum_bytes: {:?}", sum_bytes);
-    // println!("CHECK: pw.hash: {:?}", pw.hash);
-
-    for (index, item) in sum_bytes.iter().enumerate() {
-        if pw.hash[index] != *item {
-            println!("CHECK: FALSE");
-            return false;
+// takes the next piece from the queue that the peer has, putting back the ones
+// it doesn't
+pub fn take_work(
+    bitfield: &bitfield::Bitfield,
+    work_snd: &Sender<PieceWork>,
+    work_rcv: &Receiver<PieceWork>,
+) -> Option<PieceWork> {
+    for _ in 0..work_rcv.len() {
+        let piece = match work_rcv.try_recv() {
+            Ok(piece) => piece,
+            Err(_) => return None,
+        };
+        if bitfield.has_piece(piece.index) {
+            return Some(piece);
         }
+        work_snd.send(piece).unwrap();
     }
+    None
+}
 
-    // println!("CHECK: TRUE");
+pub fn check_integrity(pw: &PieceWork, buf: &[u8]) -> bool {
+    if !pw.hash.matches(buf) {
+        println!("CHECK: FALSE");
+        return false;
+    }
     true
 }
 
-pub fn attempt_download_piece(
-    c: &mut client::Client,
-    pw: PieceWork,
-) -> Result<Vec<u8>, PieceError> {
-    let mut state = PieceProgress {
-        index: pw.index,
-        client: c,
-        buf: vec![0u8; pw.length as usize],
-        downloaded: 0,
-        requested: 0,
-        backlog: 0,
+impl Hasher {
+    pub fn hash(
+        &self,
+        work: PieceWork,
+        buf: Vec<u8>,
+        sources: Vec<Option<SocketAddr>>,
+        report: Option<Sender<bool>>,
+    ) {
+        let job = HashJob {
+            work,
+            buf,
+            sources,
+            torrent: Arc::clone(&self.torrent),
+            report,
+        };
+        self.jobs.send(job).unwrap();
+    }
+}
+
+// verifies the pieces of every torrent of the session until the session
+// is gone
+pub fn start_hash_worker(hash_rcv: Receiver<HashJob>) {
+    for job in hash_rcv.iter() {
+        let torrent = &job.torrent;
+        if !check_integrity(&job.work, &job.buf) {
+            println!("piece #{} failed integrity check", job.work.index);
+            torrent
+                .smart_ban
+                .lock()
+                .unwrap()
+                .piece_failed(job.work.index, &job.buf, &job.sources);
+            if let Some(report) = &job.report {
+                let _ = report.send(false);
+            }
+            // the queues are gone once the torrent stopped for good
+            if torrent.work_snd.send(job.work).is_ok() {
+                println!("putting back work, piece: #{}", job.work.index);
+            }
+            continue;
+        }
+        torrent
+            .smart_ban
+            .lock()
+            .unwrap()
+            .piece_passed(job.work.index, &job.buf);
+        if let Some(report) = &job.report {
+            let _ = report.send(true);
+        }
+
+        let piece_result = PieceResult {
+            index: job.work.index,
+            buf: job.buf,
+        };
+        let _ = torrent.result_snd.send(piece_result);
+    }
+}
+
+// resolves once the swarm is told to stop, or its controller went away
+pub async fn stopped(stop: &mut watch::Receiver<bool>) {
+    while let Some(stop) = stop.recv().await {
+        if stop {
+            return;
+        }
+    }
+}
+
+// connects to a peer and trades pieces with it, handing the id it sent in
+// its handshake to `identified`; returns whether the connection was
+// established
+pub async fn start_download_worker(
+    p: Peer,
+    swarm: Swarm,
+    counter: i32,
+    identified: impl FnOnce([u8; 20]),
+) -> bool {
+    let peer_ip = p.ip;
+    let metadata = Some(Arc::clone(&swarm.metadata));
+    let proxy = swarm.proxy.as_deref();
+    let bind = &swarm.bind;
+    let pex = swarm.pex.clone();
+    let connected = client::new(
+        p,
+        swarm.peer_id,
+        swarm.info_hash,
+        metadata,
+        pex.is_some(),
+        proxy,
+        bind,
+    )
+    .await;
+    let established = match connected {
+        Ok(c) => {
+            identified(c.remote_peer_id);
+            // we reached the peer on its listen port, so others can too
+            let addr = c.addr();
+            if let Some(pex) = &pex {
+                pex.connected.lock().unwrap().insert(addr);
+            }
+            run_worker(c, swarm, counter).await;
+            if let Some(pex) = &pex {
+                pex.connected.lock().unwrap().remove(&addr);
+            }
+            true
+        }
+        Err(e) => {
+            println!("{}: #{}: DROPPED, with error: {}", peer_ip, counter, e);
+            false
+        }
     };
 
-    state
-        .client
-        .conn
-        .set_read_timeout(Some(Duration::new(5, 0)))
-        .unwrap();
-
-    state
-        .client
-        .conn
-        .set_write_timeout(Some(Duration::new(5, 0)))
-        .unwrap();
-
-    let peer_ip = state.client.peer.ip.clone();
-    // println!(
-    //     "{}: DOWNLOAD: state.downloaded: {}", peer_ip,
-    //     state.downloaded
-    // );
-    // println!("{}: DOWNLOAD: pw.length: {}", peer_ip, pw.length);
-    while state.downloaded < pw.length {
-        // println!(
-        //     "{}: DOWNLOAD: is client chocked?: {}",
-        //     peer_ip, state.client.choked
-        // );
-        // If unchoked, send requests until we have enough unfulfilled requests
-        if !state.client.choked {
-            // the largest number of bytes a request can ask for
-            let max_block_size = 16384;
-
-            // the number of unfulfilled requests a client can have in its pipeline
-            let max_backlog = 5;
-
-            while state.backlog < max_backlog && state.requested < pw.length {
-                let mut block_size = max_block_size;
-
-                // Last block might be shorter than the typical block
-                if pw.length - state.requested < block_size {
-                    block_size = pw.length - state.requested;
-                }
+    println!("{}: #{}: end", peer_ip, counter);
+    established
+}
+
+// takes over a connection the peer opened to us
+pub async fn start_incoming_worker(
+    conn: TcpStream,
+    handshake: handshake::Handshake,
+    swarm: Swarm,
+    counter: i32,
+) {
+    let num_pieces = swarm.have.lock().unwrap().array.len() as i64 * 8;
+    let metadata = Some(Arc::clone(&swarm.metadata));
+    let pex = swarm.pex.is_some();
+    match client::accept(conn, &handshake, swarm.peer_id, num_pieces, metadata, pex).await {
+        Ok(c) => {
+            let peer_ip = c.peer.ip;
+            run_worker(c, swarm, counter).await;
+            println!("{}: #{}: end", peer_ip, counter);
+        }
+        Err(e) => println!("incoming #{}: DROPPED, with error: {}", counter, e),
+    }
+}
+
+async fn run_worker(mut c: client::Client, swarm: Swarm, counter: i32) {
+    let Swarm {
+        info_hash,
+        work_snd,
+        work_rcv,
+        hasher,
+        peers,
+        mut stop,
+        have,
+        storage,
+        download_buckets,
+        upload_buckets,
+        peer_limits,
+        uploaded,
+        piece_layers,
+        smart_ban,
+        pex,
+        ..
+    } = swarm;
+    let peer_ip = c.peer.ip;
+    let client = peer_id::client_name(&c.remote_peer_id);
+    println!(
+        "{}: #{}: connected, client: {}",
+        peer_ip,
+        counter,
+        client.as_deref().unwrap_or("unknown")
+    );
+
+    let mut buckets = vec![ratelimit::new_bucket(peer_limits.download)];
+    buckets.extend(download_buckets);
+    c.download_throttle = ratelimit::new_throttle(buckets);
+    let mut buckets = vec![ratelimit::new_bucket(peer_limits.upload)];
+    buckets.extend(upload_buckets);
+    c.upload_throttle = ratelimit::new_throttle(buckets);
 
-                // println!(
-                //     "{}: DOWNLOAD: send_request for index: {}",
-                //     peer_ip, pw.index
-                // );
-                state
-                    .client
-                    .send_request(pw.index, state.requested, block_size);
-                state.backlog += 1;
-                state.requested += block_size;
+    // tell the peer what we can upload; pieces we get later are announced
+    // one by one
+    let mut announced = have.lock().unwrap().clone();
+    let mut greeting = Ok(());
+    if announced.array.iter().any(|b| *b != 0) {
+        greeting = c.send_bitfield(&announced).await;
+    }
+
+    // we start out choking the peer until the choker gives it a slot
+    if let Err(e) = greeting.and(c.send_interested().await) {
+        println!("{}: #{}: DROPPED, with error: {}", peer_ip, counter, e);
+        return;
+    }
+    peers
+        .lock()
+        .unwrap()
+        .insert(counter as usize, choker::new_peer_state());
+
+    // println!("{}: #{}: ready for pieces of work", peer_ip, counter);
+    let mut scheduler = new_scheduler();
+    let mut reported = (0, 0);
+    let connected = Instant::now();
+    let mut told = HashSet::new();
+    let mut last_pex = None;
+    loop {
+        if *stop.borrow() {
+            scheduler.abandon(&work_snd);
+            break;
+        }
+        if smart_ban.lock().unwrap().is_banned(&peer_ip) {
+            println!("{}: #{}: disconnecting banned peer", peer_ip, counter);
+            scheduler.abandon(&work_snd);
+            break;
+        }
+
+        // report what the peer did for us and apply the choker's latest
+        // decision
+        let choke = {
+            let mut peers = peers.lock().unwrap();
+            let state = peers.get_mut(&(counter as usize)).unwrap();
+            state.interested = c.peer_interested;
+            state.downloaded += c.downloaded - reported.0;
+            state.uploaded += c.uploaded - reported.1;
+            reported = (c.downloaded, c.uploaded);
+            state.choked
+        };
+        if let Some(pex) = &pex {
+            if !c.pex_peers.is_empty() {
+                (pex.found)(info_hash, c.pex_peers.drain(..).collect());
             }
         }
+        let sent = if choke && !c.am_choking {
+            c.send_choke().await
+        } else if !choke && c.am_choking {
+            c.send_unchoke().await
+        } else {
+            Ok(())
+        };
+
+        let sent = match sent {
+            Ok(()) => announce_pieces(&mut c, &have, &mut announced).await,
+            Err(e) => Err(e),
+        };
+        let sent = match (sent, &pex) {
+            (Ok(()), Some(pex)) => exchange_peers(&mut c, pex, &mut told, &mut last_pex).await,
+            (sent, _) => sent,
+        };
+
+        // answer what the peer asked for, then ask for what we need
+        let sent = match sent {
+            Ok(()) => serve_requests(&mut c, &have, &storage, &uploaded, &piece_layers, counter)
+                .await
+                .map_err(PieceError::Peer),
+            Err(e) => Err(PieceError::Peer(e)),
+        };
+        let sent = match sent {
+            Ok(()) => scheduler.fill_requests(&mut c, &work_snd, &work_rcv).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = sent {
+            println!("{}: #{}: failed to send, error: {}", peer_ip, counter, e);
+            scheduler.abandon(&work_snd);
+            break;
+        }
+        if scheduler.active.is_empty() && !c.peer_interested && connected.elapsed() >= IDLE_GRACE {
+            // nothing left to trade with this peer
+            break;
+        }
 
-        // println!("{}: DOWNLOAD: read_message_pp", peer_ip);
-        match state.read_message_pp() {
+        // a stop interrupts a read that is waiting on the peer
+        let read = tokio::select! {
+            read = scheduler.read_message(&mut c) => Some(read),
+            _ = stopped(&mut stop) => None,
+        };
+        match read {
             None => {
-                continue;
+                scheduler.abandon(&work_snd);
+                break;
             }
-            Some(error) => {
+            Some(Ok(finished)) => {
+                for piece in finished {
+                    hasher.hash(piece.work, piece.buf, piece.sources, None);
+                }
+            }
+            // a quiet peer is fine as long as we don't wait on it for blocks;
+            // a piece held while choked goes back so others can fetch it
+            Some(Err(PieceError::Peer(client::ClientError::Timeout(_))))
+                if c.pipeline.backlog() == 0 =>
+            {
+                scheduler.abandon(&work_snd);
+            }
+            Some(Err(e)) => {
                 println!(
-                    "{}: Failed to read new message, error: {:?}",
-                    peer_ip, error
+                    "{}: #{}: failed to read new message, error: {}",
+                    peer_ip, counter, e
                 );
-                return Err(error);
+                scheduler.abandon(&work_snd); // put pieces back on the queue
+                println!("{}: #{}: putting back work", peer_ip, counter);
+                break;
             }
         }
     }
+    peers.lock().unwrap().remove(&(counter as usize));
+}
 
-    Ok(state.buf)
+// sends a HAVE for every piece written since the peer last heard from us
+async fn announce_pieces(
+    c: &mut client::Client,
+    have: &Mutex<bitfield::Bitfield>,
+    announced: &mut bitfield::Bitfield,
+) -> Result<(), client::ClientError> {
+    let new: Vec<i64> = {
+        let have = have.lock().unwrap();
+        let changed = have
+            .array
+            .iter()
+            .zip(announced.array.iter())
+            .enumerate()
+            .filter(|(_, (now, before))| now != before)
+            .map(|(byte, _)| byte as i64);
+        changed
+            .flat_map(|byte| byte * 8..byte * 8 + 8)
+            .filter(|i| have.has_piece(*i) && !announced.has_piece(*i))
+            .collect()
+    };
+    for index in new {
+        c.send_have(index).await?;
+        announced.set_piece(index);
+    }
+    Ok(())
 }
 
-pub fn start_download_worker(
-    p: Peer,
-    info_hash: &[u8; 20],
-    work_snd: Sender<p2p::PieceWork>,
-    work_rcv: Receiver<p2p::PieceWork>,
-    result_snd: Sender<p2p::PieceResult>,
-    counter: i32,
-) {
-    // println!("I am thread {}", counter);
-    let mut this_thread_client: client::Client;
-    let peer_id: [u8; 20] = PEER_ID_STRING.as_bytes().try_into().unwrap();
-    let peer_ip = p.ip;
-    match client::new(p, peer_id, *info_hash) {
-        Ok(client) => {
-            this_thread_client = client;
-            this_thread_client.send_unchoke();
-            this_thread_client.send_interested();
-
-            // println!("{}: #{}: ready for pieces of work", peer_ip, counter);
-            while !work_rcv.is_empty() {
-                let piece = work_rcv.recv().unwrap();
-                // println!("{}: #{} received piece for work", peer_ip, piece.index);
-                // println!("WORK_PIECES left: {}", work_rcv.len());
-
-                if !this_thread_client.bitfield.has_piece(piece.index) {
-                    work_snd.send(piece).unwrap();
-                    println!(
-                        "{}: #{}: bitfield not existent on peer",
-                        peer_ip, piece.index
-                    );
-                    continue;
-                }
+// tells the peer which peers we connected to and lost since we last told
+// it, at most once every PEX_INTERVAL
+async fn exchange_peers(
+    c: &mut client::Client,
+    pex: &Pex,
+    told: &mut HashSet<SocketAddr>,
+    last_pex: &mut Option<Instant>,
+) -> Result<(), client::ClientError> {
+    if !c.speaks_pex() || last_pex.is_some_and(|t| t.elapsed() < PEX_INTERVAL) {
+        return Ok(());
+    }
+    *last_pex = Some(Instant::now());
+    let own = c.addr();
+    let connected: HashSet<SocketAddr> = pex
+        .connected
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|addr| **addr != own)
+        .copied()
+        .collect();
+    let added: Vec<SocketAddr> = connected
+        .difference(told)
+        .copied()
+        .take(extension::MAX_PEX_PEERS)
+        .collect();
+    let dropped: Vec<SocketAddr> = told
+        .difference(&connected)
+        .copied()
+        .take(extension::MAX_PEX_PEERS)
+        .collect();
+    if added.is_empty() && dropped.is_empty() {
+        return Ok(());
+    }
+    told.extend(added.iter().copied());
+    for addr in dropped.iter() {
+        told.remove(addr);
+    }
+    c.send_pex(&added, &dropped).await
+}
 
-                // println!(
-                //     "{}: #{}: bitfield success, piece found, attempt piece: {}",
-                //     peer_ip, counter, piece.index
-                // );
-                match attempt_download_piece(&mut this_thread_client, piece) {
-                    Ok(buf) => {
-                        if !check_integrity(&piece, &buf) {
-                            println!(
-                                "{}: #{}: piece #{} failed integrity check",
-                                peer_ip, counter, piece.index
-                            );
-                            work_snd.send(piece).unwrap();
-                            println!(
-                                "{}: #{}: putting back work, piece: #{}",
-                                peer_ip, counter, piece.index
-                            );
-                            continue;
-                        }
-                        // println!(
-                        //     "{}: #{}: piece #{} integrity check success!",
-                        //     peer_ip, counter, piece.index
-                        // );
-
-                        this_thread_client.send_have(piece.index);
-                        // println!("{}: #{}: Piece {} send have", peer_ip, counter, piece.index);
-                        let piece_result = PieceResult {
-                            index: piece.index,
-                            buf: buf.clone(),
-                        };
-                        result_snd.send(piece_result).unwrap();
-                        // println!(
-                        //     "{}: #{}: piece {} send as result",
-                        //     peer_ip, counter, piece.index
-                        // );
-                    }
-                    Err(e) => {
-                        println!(
-                            "{}: {}: attempt of piece #{} at download failed: {:?}",
-                            peer_ip, counter, piece.index, e
-                        );
-                        work_snd.send(piece).unwrap(); // put piece back on the queue
-                        println!(
-                            "{}: {}: putting back work, piece: #{}",
-                            peer_ip, counter, piece.index
-                        );
-                        return;
-                    }
-                }
-                // println!("{}: #{}: blocking for new work", peer_ip, counter);
-            }
+// sends the blocks and hashes the peer asked for
+async fn serve_requests(
+    c: &mut client::Client,
+    have: &Mutex<bitfield::Bitfield>,
+    storage: &storage::Storage,
+    uploaded: &AtomicI64,
+    piece_layers: &merkle::PieceLayers,
+    counter: i32,
+) -> Result<(), client::ClientError> {
+    while let Some((index, begin, length)) = c.peer_requests.pop_front() {
+        // blocks have to lie within a piece we have
+        let in_piece = storage
+            .piece_size(index as i64)
+            .is_some_and(|size| begin as i64 + length as i64 <= size);
+        if !in_piece || !have.lock().unwrap().has_piece(index as i64) {
+            continue;
         }
-        Err(e) => {
-            println!("{}: #{}: DROPPED, with error: {:?}", peer_ip, counter, e);
+        match storage.read_block(index as i64, begin as i64, length as i64) {
+            Ok(block) => {
+                c.send_piece(index, begin, block).await?;
+                uploaded.fetch_add(length as i64, Ordering::Relaxed);
+            }
+            Err(e) => println!(
+                "{}: #{}: can't serve piece #{}: {}",
+                c.peer.ip, counter, index, e
+            ),
         }
     }
 
-    println!("{}: #{}: end", peer_ip, counter);
+    while let Some(range) = c.peer_hash_requests.pop_front() {
+        let hashes = piece_layers.get(&range.pieces_root).and_then(|layer| {
+            merkle::piece_layer_hashes(
+                layer,
+                storage.piece_length() as usize,
+                range.base_layer as usize,
+                range.index as usize,
+                range.length as usize,
+                range.proof_layers as usize,
+            )
+        });
+        c.send_hashes(range, hashes).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockState;
+    use crate::pipeline::BLOCK_SIZE;
+    use crate::torrent;
+
+    #[test]
+    fn piece_progress_splits_blocks() {
+        let work = super::PieceWork {
+            index: 0,
+            hash: torrent::PieceHash::V1([0u8; 20]),
+            length: BLOCK_SIZE * 2 + 100,
+        };
+        let mut piece = super::new_piece_progress(work);
+        assert_eq!(piece.blocks.len(), 3);
+        assert_eq!(piece.block_bounds(1), (BLOCK_SIZE, BLOCK_SIZE));
+        assert_eq!(piece.block_bounds(2), (BLOCK_SIZE * 2, 100));
+
+        piece.blocks = vec![
+            BlockState::Received,
+            BlockState::Requested,
+            BlockState::Received,
+        ];
+        assert!(!piece.has_missing());
+        assert!(!piece.is_complete());
+        piece.blocks[1] = BlockState::Received;
+        assert!(piece.is_complete());
+    }
+
+    #[test]
+    fn only_requested_blocks_are_taken() {
+        use crate::{client, handshake, message};
+        use std::io::Write;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let length = BLOCK_SIZE * 2 + 100;
+        let peer = std::thread::spawn(move || {
+            let (mut peer, _) = listener.accept().unwrap();
+            let piece = |begin: i64, len: i64| message::Message::Piece {
+                index: 0,
+                begin: begin as u32,
+                block: vec![7u8; len as usize],
+            };
+            // unaligned, short, unrequested, and then the one we asked for
+            for msg in [
+                piece(1, 10),
+                piece(0, 100),
+                piece(BLOCK_SIZE, BLOCK_SIZE),
+                piece(BLOCK_SIZE * 2, 100),
+            ]
+            .iter()
+            {
+                peer.write_all(&msg.serialize()).unwrap();
+            }
+            peer
+        });
+
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut scheduler = super::new_scheduler();
+        let mut piece = super::new_piece_progress(super::PieceWork {
+            index: 0,
+            hash: torrent::PieceHash::V1([0u8; 20]),
+            length,
+        });
+        piece.blocks = vec![
+            BlockState::Requested,
+            BlockState::Missing,
+            BlockState::Requested,
+        ];
+        scheduler.active.push(piece);
+        runtime.block_on(async {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut request = handshake::new_handshake([1u8; 20], [2u8; 20]);
+            request.extensions = [0u8; 8];
+            let mut c = client::accept(stream, &request, [3u8; 20], 1, None, false)
+                .await
+                .unwrap();
+            for _ in 0..4 {
+                assert!(scheduler.read_message(&mut c).await.unwrap().is_empty());
+            }
+            assert_eq!(c.downloaded, 100);
+        });
+        let _peer = peer.join().unwrap();
+
+        let piece = &scheduler.active[0];
+        assert_eq!(
+            piece.blocks,
+            [
+                BlockState::Requested,
+                BlockState::Missing,
+                BlockState::Received
+            ]
+        );
+        assert!(piece.buf[..BLOCK_SIZE as usize * 2].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn written_pieces_are_announced() {
+        use crate::{bitfield, client, handshake};
+        use std::io::Read;
+        use std::sync::Mutex;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let have = Mutex::new(bitfield::new_bitfield(20));
+        have.lock().unwrap().set_piece(3);
+        let mut announced = have.lock().unwrap().clone();
+        runtime.block_on(async {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut request = handshake::new_handshake([1u8; 20], [2u8; 20]);
+            request.extensions = [0u8; 8];
+            let mut c = client::accept(stream, &request, [3u8; 20], 20, None, false)
+                .await
+                .unwrap();
+            have.lock().unwrap().set_piece(0);
+            have.lock().unwrap().set_piece(17);
+            super::announce_pieces(&mut c, &have, &mut announced)
+                .await
+                .unwrap();
+            // nothing new, nothing sent
+            super::announce_pieces(&mut c, &have, &mut announced)
+                .await
+                .unwrap();
+        });
+        assert_eq!(announced, *have.lock().unwrap());
+
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut sent = vec![];
+        peer.read_to_end(&mut sent).unwrap();
+        assert_eq!(sent.len(), 68 + 18);
+        assert_eq!(
+            sent[68..],
+            [0, 0, 0, 5, 4, 0, 0, 0, 0, 0, 0, 0, 5, 4, 0, 0, 0, 17]
+        );
+    }
+
+    #[test]
+    fn requests_outside_the_piece_are_not_served() {
+        use crate::{bitfield, client, files, handshake, merkle, storage};
+        use std::io::Read;
+        use std::sync::atomic::{AtomicI64, Ordering};
+        use std::sync::Mutex;
+
+        let dir = std::env::temp_dir().join(format!("herb-serve-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("hello.txt"), b"hello").unwrap();
+        let mut data =
+            b"d8:announce0:4:infod6:lengthi5e4:name9:hello.txt12:piece lengthi16384e6:pieces20:"
+                .to_vec();
+        data.extend_from_slice(&[0u8; 20]);
+        data.extend_from_slice(b"ee");
+        let t = torrent::new_torrent(&torrent::parse_torrent(&data).unwrap()).unwrap();
+        let storage = storage::new(dir.clone(), &t, vec![files::FilePriority::Normal]);
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let have = Mutex::new(bitfield::new_bitfield(1));
+        have.lock().unwrap().set_piece(0);
+        let uploaded = AtomicI64::new(0);
+        runtime.block_on(async {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut request = handshake::new_handshake([1u8; 20], [2u8; 20]);
+            request.extensions = [0u8; 8];
+            let mut c = client::accept(stream, &request, [3u8; 20], 1, None, false)
+                .await
+                .unwrap();
+            // past the end of the piece, and a piece that doesn't exist
+            c.peer_requests
+                .extend(vec![(0, 2, 5), (1, 0, 1), (0, 1, 4)]);
+            super::serve_requests(
+                &mut c,
+                &have,
+                &storage,
+                &uploaded,
+                &merkle::PieceLayers::new(),
+                0,
+            )
+            .await
+            .unwrap();
+        });
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(uploaded.load(Ordering::Relaxed), 4);
+
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut sent = vec![];
+        peer.read_to_end(&mut sent).unwrap();
+        assert_eq!(
+            sent[68..],
+            [0, 0, 0, 13, 7, 0, 0, 0, 0, 0, 0, 0, 1, b'e', b'l', b'l', b'o']
+        );
+    }
+
+    #[test]
+    fn connected_peers_are_exchanged() {
+        use crate::{client, extension, handshake};
+        use std::collections::HashSet;
+        use std::io::Read;
+        use std::sync::{Arc, Mutex};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let other: std::net::SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+        let found: super::PexSink = Arc::new(|_, _| {});
+        let pex = super::Pex {
+            connected: Arc::new(Mutex::new(HashSet::new())),
+            found,
+        };
+        runtime.block_on(async {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut request = handshake::new_handshake([1u8; 20], [2u8; 20]);
+            request.extensions = [0u8; 8];
+            let mut c = client::accept(stream, &request, [3u8; 20], 1, None, true)
+                .await
+                .unwrap();
+            pex.connected.lock().unwrap().insert(c.addr());
+            pex.connected.lock().unwrap().insert(other);
+            let (mut told, mut last_pex) = (HashSet::new(), None);
+
+            // nothing goes to a peer that didn't ask for ut_pex
+            super::exchange_peers(&mut c, &pex, &mut told, &mut last_pex)
+                .await
+                .unwrap();
+            assert!(told.is_empty());
+            c.handle_extended_handshake(0, b"d1:md6:ut_pexi5eee");
+            super::exchange_peers(&mut c, &pex, &mut told, &mut last_pex)
+                .await
+                .unwrap();
+            // and not more than once every interval
+            pex.connected.lock().unwrap().clear();
+            super::exchange_peers(&mut c, &pex, &mut told, &mut last_pex)
+                .await
+                .unwrap();
+            assert_eq!(told, vec![other].into_iter().collect());
+        });
+
+        // the peer only hears about the others, not about itself
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut sent = vec![];
+        peer.read_to_end(&mut sent).unwrap();
+        assert_eq!(sent[72..74], [20, 5]);
+        let peers = extension::parse_pex(&sent[74..]);
+        assert_eq!(peers.len(), 1);
+        assert_eq!((peers[0].ip, peers[0].port), (other.ip(), other.port()));
+    }
 }
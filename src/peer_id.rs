@@ -0,0 +1,109 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+// Azureus-style prefix of our peer ids: client code HB, version 0.1.0.0
+pub const PEER_ID_PREFIX: &[u8; 8] = b"-HB0100-";
+
+// Azureus-style client codes of clients seen in the wild
+const CLIENTS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("HB", "herb"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("TR", "Transmission"),
+    ("UM", "\u{b5}Torrent Mac"),
+    ("UT", "\u{b5}Torrent"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+// a random number without a dependency on a random number generator: the
+// standard library seeds every RandomState from the OS
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+// a fresh peer id: our prefix followed by random alphanumerics, which keep it
+// readable in tracker logs
+pub fn new_peer_id() -> [u8; 20] {
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut id = [0u8; 20];
+    id[..8].copy_from_slice(PEER_ID_PREFIX);
+    for b in id[8..].iter_mut() {
+        *b = ALPHABET[(random_u64() % ALPHABET.len() as u64) as usize];
+    }
+    id
+}
+
+// the name and version of the client behind a peer id, for the Azureus
+// (`-XX1234-`) and Mainline (`M1-2-3--`) conventions
+pub fn client_name(peer_id: &[u8; 20]) -> Option<String> {
+    if peer_id[0] == b'-' && peer_id[7] == b'-' {
+        let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+        let name = match CLIENTS.iter().find(|(c, _)| *c == code) {
+            Some((_, name)) => (*name).to_owned(),
+            None if code.chars().all(|c| c.is_ascii_alphanumeric()) => code.to_owned(),
+            None => return None,
+        };
+        let mut version: Vec<String> = peer_id[3..7]
+            .iter()
+            .map(|b| match b {
+                b'0'..=b'9' => Some((b - b'0').to_string()),
+                b'A'..=b'Z' => Some((b - b'A' + 10).to_string()),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        while version.len() > 2 && version[version.len() - 1] == "0" {
+            version.pop();
+        }
+        return Some(format!("{} {}", name, version.join(".")));
+    }
+
+    if peer_id[0] == b'M' {
+        // M, then numbers separated by dashes, padded with dashes
+        let rest = std::str::from_utf8(&peer_id[1..8]).ok()?;
+        let version: Vec<&str> = rest.split('-').filter(|part| !part.is_empty()).collect();
+        if !version.is_empty()
+            && version
+                .iter()
+                .all(|v| v.bytes().all(|b| b.is_ascii_digit()))
+        {
+            return Some(format!("Mainline {}", version.join(".")));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    #[test]
+    fn peer_ids_are_ours_and_unique() {
+        let a = super::new_peer_id();
+        let b = super::new_peer_id();
+        assert_eq!(&a[..8], super::PEER_ID_PREFIX);
+        assert_ne!(a, b);
+        assert_eq!(super::client_name(&a).unwrap(), "herb 0.1");
+    }
+
+    #[test]
+    fn client_names_are_decoded() {
+        let name = |id: &[u8]| super::client_name(id.try_into().unwrap());
+        assert_eq!(name(b"-qB4350-abcdefghijkl").unwrap(), "qBittorrent 4.3.5");
+        assert_eq!(name(b"-TR2940-abcdefghijkl").unwrap(), "Transmission 2.9.4");
+        assert_eq!(name(b"-ZZ1200-abcdefghijkl").unwrap(), "ZZ 1.2");
+        assert_eq!(name(b"M7-10-3-abcdefghijkl").unwrap(), "Mainline 7.10.3");
+        assert_eq!(name(b"kjh29409k8hj0wgej6c1"), None);
+    }
+}
@@ -0,0 +1,297 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::p2p;
+
+// a peer we couldn't connect to is tried again after a delay that doubles
+// with every failure, and forgotten after MAX_FAILURES attempts in a row
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+const MAX_FAILURES: u32 = 6;
+
+// how many connections a torrent tries to keep open
+pub const DEFAULT_TARGET_PEERS: usize = 50;
+
+// how long a peer we were connected to waits before we connect again
+const RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+// where we heard of a peer
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+}
+
+// Candidate is a peer we may connect to, under the info hash it was
+// announced for
+struct Candidate {
+    info_hash: [u8; 20],
+    id: Option<[u8; 20]>,
+    // the id the peer sent when we last got through, which tells us the
+    // addresses of a peer we know under both families
+    remote_id: Option<[u8; 20]>,
+    source: PeerSource,
+    failures: u32,
+    last_seen: Instant,
+    next_attempt: Instant,
+    connected: bool,
+}
+
+// PeerManager keeps every peer of a torrent we know of and picks the ones to
+// connect to next; the controller asks it whenever it is short of peers
+pub struct PeerManager {
+    candidates: HashMap<SocketAddr, Candidate>,
+    // whether we got connections through over IPv4 and over IPv6; None
+    // until we tried
+    reachable: [Option<bool>; 2],
+}
+
+pub fn new_peer_manager() -> PeerManager {
+    PeerManager {
+        candidates: HashMap::new(),
+        reachable: [None; 2],
+    }
+}
+
+fn family(addr: &SocketAddr) -> usize {
+    addr.is_ipv6() as usize
+}
+
+fn backoff(failures: u32) -> Duration {
+    let delay = MIN_BACKOFF * 2u32.saturating_pow(failures.saturating_sub(1));
+    delay.min(MAX_BACKOFF)
+}
+
+impl PeerManager {
+    // adds the peers a source told us about; peers we know already only
+    // count as seen again
+    pub fn add(
+        &mut self,
+        info_hash: [u8; 20],
+        peers: Vec<p2p::Peer>,
+        source: PeerSource,
+        now: Instant,
+    ) {
+        for p in peers {
+            let candidate = self
+                .candidates
+                .entry(SocketAddr::new(p.ip, p.port))
+                .or_insert(Candidate {
+                    info_hash,
+                    id: p.id,
+                    remote_id: None,
+                    source,
+                    failures: 0,
+                    last_seen: now,
+                    next_attempt: now,
+                    connected: false,
+                });
+            candidate.last_seen = now;
+        }
+    }
+
+    // how many peers we could connect to right now
+    pub fn ready(&self, now: Instant) -> usize {
+        self.candidates
+            .values()
+            .filter(|c| !c.connected && c.next_attempt <= now)
+            .count()
+    }
+
+    // the best peer to connect to now, which counts as connected until
+    // `disconnected` is called: one of an address family that works, then
+    // the one that failed least, then the one seen last; a peer we know
+    // under both families is skipped while its other address is connected
+    pub fn next_peer(&mut self, now: Instant) -> Option<([u8; 20], p2p::Peer)> {
+        let reachable = self.reachable;
+        let busy: HashSet<[u8; 20]> = self
+            .candidates
+            .values()
+            .filter(|c| c.connected)
+            .filter_map(|c| c.remote_id.or(c.id))
+            .collect();
+        let (addr, candidate) = self
+            .candidates
+            .iter_mut()
+            .filter(|(_, c)| !c.connected && c.next_attempt <= now)
+            .filter(|(_, c)| c.remote_id.or(c.id).is_none_or(|id| !busy.contains(&id)))
+            .min_by_key(|(addr, c)| {
+                let unreachable = reachable[family(addr)] == Some(false);
+                (unreachable, c.failures, Reverse(c.last_seen))
+            })?;
+        candidate.connected = true;
+        let peer = p2p::Peer {
+            ip: addr.ip(),
+            port: addr.port(),
+            id: candidate.id,
+        };
+        Some((candidate.info_hash, peer))
+    }
+
+    // records the id the peer at `addr` sent in its handshake
+    pub fn identified(&mut self, addr: SocketAddr, id: [u8; 20]) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.remote_id = Some(id);
+        }
+    }
+
+    // drops a peer we must not connect to again
+    pub fn forget(&mut self, addr: SocketAddr) {
+        self.candidates.remove(&addr);
+    }
+
+    // records how a connection ended; `established` is false when we never
+    // got past the handshake
+    pub fn disconnected(&mut self, addr: SocketAddr, established: bool, now: Instant) {
+        let candidate = match self.candidates.get_mut(&addr) {
+            Some(candidate) => candidate,
+            None => return,
+        };
+        candidate.connected = false;
+        let reachable = &mut self.reachable[family(&addr)];
+        if established {
+            *reachable = Some(true);
+            candidate.failures = 0;
+            candidate.next_attempt = now + RECONNECT_DELAY;
+            return;
+        }
+        reachable.get_or_insert(false);
+        candidate.failures += 1;
+        if candidate.failures >= MAX_FAILURES {
+            println!(
+                "{}: giving up on peer from {:?} after {} attempts",
+                addr, candidate.source, candidate.failures
+            );
+            self.candidates.remove(&addr);
+            return;
+        }
+        candidate.next_attempt = now + backoff(candidate.failures);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::{Duration, Instant};
+
+    use super::PeerSource;
+    use crate::p2p;
+
+    fn peer(port: u16) -> p2p::Peer {
+        p2p::Peer {
+            ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+            id: None,
+        }
+    }
+
+    #[test]
+    fn failed_peers_back_off_and_are_forgotten() {
+        let mut m = super::new_peer_manager();
+        let now = Instant::now();
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+        m.add([1; 20], vec![peer(1)], PeerSource::Tracker, now);
+
+        let (info_hash, p) = m.next_peer(now).unwrap();
+        assert_eq!((info_hash, p.port), ([1; 20], 1));
+        assert!(m.next_peer(now).is_none());
+
+        m.disconnected(addr, false, now);
+        assert_eq!(m.ready(now), 0);
+        assert_eq!(m.ready(now + Duration::from_secs(5)), 1);
+
+        // the delay doubles with every failure
+        let mut at = now;
+        for failures in 2..super::MAX_FAILURES {
+            at += super::backoff(failures - 1);
+            assert!(m.next_peer(at).is_some());
+            m.disconnected(addr, false, at);
+            assert_eq!(
+                super::backoff(failures),
+                super::MIN_BACKOFF * (1 << (failures - 1))
+            );
+        }
+        at += super::backoff(super::MAX_FAILURES - 1);
+        assert!(m.next_peer(at).is_some());
+        m.disconnected(addr, false, at);
+        assert!(m.candidates.is_empty());
+    }
+
+    #[test]
+    fn peers_that_fail_least_go_first() {
+        let mut m = super::new_peer_manager();
+        let now = Instant::now();
+        m.add([1; 20], vec![peer(1), peer(2)], PeerSource::Tracker, now);
+        let first = m.next_peer(now).unwrap().1;
+        let addr = SocketAddr::new(first.ip, first.port);
+        m.disconnected(addr, false, now);
+
+        // the other peer goes first, and a working one is reconnected later
+        let later = now + Duration::from_secs(10);
+        let second = m.next_peer(later).unwrap().1;
+        assert_ne!(second.port, first.port);
+        m.disconnected(SocketAddr::new(second.ip, second.port), true, later);
+        assert_eq!(m.next_peer(later).unwrap().1.port, first.port);
+        assert!(m.next_peer(later).is_none());
+        assert!(m.next_peer(later + super::RECONNECT_DELAY).is_some());
+    }
+
+    #[test]
+    fn the_family_that_works_goes_first() {
+        let mut m = super::new_peer_manager();
+        let now = Instant::now();
+        let v6 = p2p::Peer {
+            ip: "2001:db8::1".parse().unwrap(),
+            port: 1,
+            id: None,
+        };
+        m.add([1; 20], vec![peer(1), peer(2)], PeerSource::Tracker, now);
+        m.add(
+            [1; 20],
+            vec![v6],
+            PeerSource::Tracker,
+            now - Duration::from_secs(1),
+        );
+
+        // IPv4 peers were seen last, but once one fails the IPv6 one goes
+        // ahead of the other
+        let first = m.next_peer(now).unwrap().1;
+        assert!(first.ip.is_ipv4());
+        m.disconnected(SocketAddr::new(first.ip, first.port), false, now);
+        assert!(m.next_peer(now).unwrap().1.ip.is_ipv6());
+        assert!(m.next_peer(now).unwrap().1.ip.is_ipv4());
+    }
+
+    #[test]
+    fn peers_on_both_families_get_one_connection() {
+        let mut m = super::new_peer_manager();
+        let now = Instant::now();
+        let v6 = p2p::Peer {
+            ip: "2001:db8::1".parse().unwrap(),
+            port: 1,
+            id: None,
+        };
+        let v6_addr = SocketAddr::new(v6.ip, v6.port);
+        m.add([1; 20], vec![peer(1)], PeerSource::Tracker, now);
+        m.add([1; 20], vec![v6], PeerSource::Pex, now);
+        let v4_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+
+        // the IPv6 address goes first but fails, the IPv4 one gets through
+        m.candidates.get_mut(&v4_addr).unwrap().last_seen -= Duration::from_secs(1);
+        assert_eq!(m.next_peer(now).unwrap().1.ip, v6_addr.ip());
+        let later = now + Duration::from_secs(10);
+        m.disconnected(v6_addr, false, now);
+        assert_eq!(m.next_peer(later).unwrap().1.ip, v4_addr.ip());
+        m.identified(v4_addr, [7; 20]);
+
+        // the IPv6 address turns out to be the same peer, and is left alone
+        // while the IPv4 one is connected
+        m.identified(v6_addr, [7; 20]);
+        assert!(m.next_peer(later).is_none());
+        m.disconnected(v4_addr, true, later);
+        assert!(m.next_peer(later).unwrap().1.ip.is_ipv6());
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// the largest number of bytes a request can ask for
+pub const BLOCK_SIZE: i64 = 16384;
+
+// queue depth before we know anything about the peer
+const INITIAL_DEPTH: i64 = 5;
+
+// never let the queue drain completely between round trips
+const MIN_DEPTH: i64 = 2;
+
+// what we assume a peer accepts when it doesn't advertise `reqq`
+pub const DEFAULT_REQQ: i64 = 250;
+
+// how often the download rate is sampled
+const RATE_WINDOW: Duration = Duration::from_millis(500);
+
+// Pipeline sizes the number of outstanding requests to a peer from its
+// measured download rate and round trip time. Keeping twice the
+// bandwidth-delay product in flight lets the queue grow quickly while the link
+// is idle and settle once the peer is saturated.
+#[derive(Debug)]
+pub struct Pipeline {
+    reqq: i64,
+    rate: f64,
+    min_rtt: Option<Duration>,
+    outstanding: HashMap<(i64, i64), Instant>,
+    window_start: Option<Instant>,
+    window_bytes: i64,
+}
+
+pub fn new_pipeline() -> Pipeline {
+    Pipeline {
+        reqq: DEFAULT_REQQ,
+        rate: 0.0,
+        min_rtt: None,
+        outstanding: HashMap::new(),
+        window_start: None,
+        window_bytes: 0,
+    }
+}
+
+impl Pipeline {
+    // caps the queue at the number of requests the peer says it accepts
+    pub fn set_reqq(&mut self, reqq: i64) {
+        self.reqq = reqq.max(1);
+    }
+
+    // the number of requests we want outstanding right now
+    pub fn queue_depth(&self) -> i64 {
+        let depth = match self.min_rtt {
+            Some(rtt) if self.rate > 0.0 => {
+                let bdp = self.rate * rtt.as_secs_f64() / BLOCK_SIZE as f64;
+                (2.0 * bdp).ceil() as i64
+            }
+            _ => INITIAL_DEPTH,
+        };
+        depth.max(MIN_DEPTH).min(self.reqq)
+    }
+
+    pub fn backlog(&self) -> i64 {
+        self.outstanding.len() as i64
+    }
+
+    pub fn request_sent(&mut self, index: i64, begin: i64, now: Instant) {
+        self.outstanding.insert((index, begin), now);
+        if self.window_start.is_none() {
+            self.window_start = Some(now);
+        }
+    }
+
+    pub fn block_received(&mut self, index: i64, begin: i64, length: i64, now: Instant) {
+        if let Some(sent) = self.outstanding.remove(&(index, begin)) {
+            // the fastest answer is the one that didn't wait behind other
+            // blocks, so it is the closest we get to the real round trip
+            let rtt = now.duration_since(sent);
+            self.min_rtt = Some(match self.min_rtt {
+                Some(min) if min < rtt => min,
+                _ => rtt,
+            });
+        }
+
+        self.window_bytes += length;
+        let start = *self.window_start.get_or_insert(now);
+        let elapsed = now.duration_since(start);
+        if elapsed >= RATE_WINDOW {
+            let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+            self.rate = if self.rate == 0.0 {
+                sample
+            } else {
+                0.7 * self.rate + 0.3 * sample
+            };
+            self.window_start = Some(now);
+            self.window_bytes = 0;
+        }
+    }
+
+    // a choking peer discards all requests we had queued with it
+    pub fn clear(&mut self) {
+        self.outstanding.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::BLOCK_SIZE;
+
+    #[test]
+    fn queue_depth_starts_small() {
+        let p = super::new_pipeline();
+        assert_eq!(p.queue_depth(), 5);
+    }
+
+    #[test]
+    fn queue_depth_follows_bandwidth_delay_product() {
+        let mut p = super::new_pipeline();
+        let start = Instant::now();
+
+        // 200ms round trip, 64 blocks a second = 1 MiB/s, for ten seconds
+        for i in 0..640 {
+            let sent = start + Duration::from_millis(i * 1000 / 64);
+            p.request_sent(0, i as i64 * BLOCK_SIZE, sent);
+            p.block_received(
+                0,
+                i as i64 * BLOCK_SIZE,
+                BLOCK_SIZE,
+                sent + Duration::from_millis(200),
+            );
+        }
+
+        // bdp is about 1 MiB/s * 0.2s / 16 KiB = 13 blocks, doubled
+        let depth = p.queue_depth();
+        assert!((24..=28).contains(&depth), "depth was {}", depth);
+    }
+
+    #[test]
+    fn queue_depth_is_capped_by_reqq() {
+        let mut p = super::new_pipeline();
+        let start = Instant::now();
+        for i in 0..100 {
+            let sent = start + Duration::from_millis(i * 10);
+            p.request_sent(0, i as i64, sent);
+            p.block_received(0, i as i64, BLOCK_SIZE * 10, sent + Duration::from_secs(1));
+        }
+        p.set_reqq(16);
+        assert_eq!(p.queue_depth(), 16);
+    }
+}
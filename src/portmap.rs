@@ -0,0 +1,973 @@
+use crossbeam::channel::{self, RecvTimeoutError, Sender, TryRecvError};
+use net2::UdpSocketExt;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use url::{Position, Url};
+
+use crate::bind;
+use crate::peer_id;
+
+// how long mappings are asked for; they are renewed at half time
+const LEASE: Duration = Duration::from_secs(3600);
+// how long we wait before looking for a gateway again
+const RETRY_DELAY: Duration = Duration::from_secs(300);
+
+const NATPMP_PORT: u16 = 5351;
+const NATPMP_VERSION: u8 = 0;
+const NATPMP_EXTERNAL_ADDRESS: u8 = 0;
+const NATPMP_MAP_UDP: u8 = 1;
+const NATPMP_MAP_TCP: u8 = 2;
+const PCP_VERSION: u8 = 2;
+const PCP_MAP: u8 = 1;
+const PCP_RESPONSE: u8 = 0x80;
+const PROTOCOL_TCP: u8 = 6;
+const PROTOCOL_UDP: u8 = 17;
+// the first request waits this long, and every retry twice as long
+const UDP_TIMEOUT: Duration = Duration::from_millis(250);
+const UDP_ATTEMPTS: u32 = 4;
+
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const SSDP_WAIT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+const WAN_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+// the UPnP error of gateways that only keep mappings without a lease
+const ONLY_PERMANENT_LEASES: u16 = 725;
+
+#[derive(Debug)]
+pub enum MapError {
+    NoGateway,
+    ConnectionFailure(io::Error),
+    // the gateway answered with an error code
+    Rejected(u16),
+    InvalidResponse,
+    // the gateway doesn't speak this version of the protocol
+    Unsupported,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::NoGateway => write!(f, "no gateway found"),
+            MapError::ConnectionFailure(e) => write!(f, "can't talk to the gateway: {}", e),
+            MapError::Rejected(code) => write!(f, "the gateway refused with error {}", code),
+            MapError::InvalidResponse => write!(f, "invalid response from the gateway"),
+            MapError::Unsupported => write!(f, "the gateway doesn't support the protocol"),
+        }
+    }
+}
+
+impl Error for MapError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MapError::ConnectionFailure(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Gateway is a router that forwards ports for us, and how to ask it
+#[derive(Debug, Clone)]
+enum Gateway {
+    // RFC 6887; the nonce identifies our mappings to it
+    Pcp {
+        addr: SocketAddr,
+        client: Ipv4Addr,
+        nonce: [u8; 12],
+    },
+    // RFC 6886, which PCP replaced
+    NatPmp(SocketAddr),
+    // the WAN connection service of a UPnP Internet Gateway Device
+    Upnp {
+        control_url: Url,
+        service: String,
+        client: IpAddr,
+    },
+}
+
+// Protocol is what a mapping forwards: TCP for peers, UDP for the DHT
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Protocol {
+    Tcp,
+    Udp,
+}
+
+// Mapping is a port of the gateway forwarded to our listen port, over TCP
+// and, if the gateway agreed, over UDP
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    gateway: Gateway,
+    port: u16,
+    // the address peers outside reach us at
+    pub external: SocketAddr,
+    pub lifetime: Duration,
+    pub udp: bool,
+    // the addresses we talk to the gateway from
+    bind: Vec<IpAddr>,
+}
+
+impl fmt::Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Gateway::Pcp { addr, .. } => write!(f, "PCP at {}", addr.ip()),
+            Gateway::NatPmp(addr) => write!(f, "NAT-PMP at {}", addr.ip()),
+            Gateway::Upnp { control_url, .. } => write!(f, "UPnP at {}", control_url),
+        }
+    }
+}
+
+impl Protocol {
+    // the protocol number of PCP
+    fn number(self) -> u8 {
+        match self {
+            Protocol::Tcp => PROTOCOL_TCP,
+            Protocol::Udp => PROTOCOL_UDP,
+        }
+    }
+
+    fn natpmp_opcode(self) -> u8 {
+        match self {
+            Protocol::Tcp => NATPMP_MAP_TCP,
+            Protocol::Udp => NATPMP_MAP_UDP,
+        }
+    }
+
+    // the name UPnP knows it by
+    fn name(self) -> &'static str {
+        match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        }
+    }
+}
+
+// the default IPv4 gateway in the text of /proc/net/route, where addresses are
+// hex numbers in host byte order
+fn parse_route_table(text: &str) -> Option<Ipv4Addr> {
+    text.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields[..] {
+            [_, "00000000", gateway, ..] => {
+                let gateway = u32::from_str_radix(gateway, 16).ok()?;
+                Some(Ipv4Addr::from(gateway.to_le_bytes())).filter(|ip| !ip.is_unspecified())
+            }
+            _ => None,
+        }
+    })
+}
+
+fn default_gateway() -> Option<Ipv4Addr> {
+    parse_route_table(&fs::read_to_string("/proc/net/route").ok()?)
+}
+
+// sends `request` to `addr` until an answer comes, waiting longer every time
+fn udp_request(addr: SocketAddr, request: &[u8], bind: &[IpAddr]) -> Result<Vec<u8>, MapError> {
+    let socket = bind::udp(addr.ip(), 0, bind).map_err(MapError::ConnectionFailure)?;
+    socket.connect(addr).map_err(MapError::ConnectionFailure)?;
+    let mut wait = UDP_TIMEOUT;
+    let mut buf = [0u8; 1100];
+    for _ in 0..UDP_ATTEMPTS {
+        socket.send(request).map_err(MapError::ConnectionFailure)?;
+        socket
+            .set_read_timeout(Some(wait))
+            .map_err(MapError::ConnectionFailure)?;
+        match socket.recv(&mut buf) {
+            Ok(n) => return Ok(buf[..n].to_vec()),
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                wait *= 2
+            }
+            Err(e) => return Err(MapError::ConnectionFailure(e)),
+        }
+    }
+    Err(MapError::NoGateway)
+}
+
+// our address on the way to `addr`
+fn local_ip(addr: SocketAddr, bind: &[IpAddr]) -> Result<IpAddr, MapError> {
+    let socket = bind::udp(addr.ip(), 0, bind).map_err(MapError::ConnectionFailure)?;
+    socket.connect(addr).map_err(MapError::ConnectionFailure)?;
+    let local = socket.local_addr().map_err(MapError::ConnectionFailure)?;
+    Ok(local.ip())
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn pcp_map(
+    addr: SocketAddr,
+    client: Ipv4Addr,
+    nonce: [u8; 12],
+    protocol: Protocol,
+    port: u16,
+    lifetime: Duration,
+    bind: &[IpAddr],
+) -> Result<(SocketAddr, Duration), MapError> {
+    let mut request = vec![PCP_VERSION, PCP_MAP, 0, 0];
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    request.extend_from_slice(&client.to_ipv6_mapped().octets());
+    request.extend_from_slice(&nonce);
+    request.extend_from_slice(&[protocol.number(), 0, 0, 0]);
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+    let response = udp_request(addr, &request, bind)?;
+    // a NAT-PMP gateway answers with its own version
+    if response.first() != Some(&PCP_VERSION) {
+        return Err(MapError::Unsupported);
+    }
+    if response.len() < 60 || response[1] != PCP_RESPONSE | PCP_MAP || response[24..36] != nonce {
+        return Err(MapError::InvalidResponse);
+    }
+    if response[3] != 0 {
+        return Err(MapError::Rejected(response[3] as u16));
+    }
+    let mut external = [0u8; 16];
+    external.copy_from_slice(&response[44..60]);
+    let external = Ipv6Addr::from(external);
+    let external = match external.to_ipv4_mapped() {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(external),
+    };
+    let lifetime = Duration::from_secs(u32_at(&response, 4) as u64);
+    Ok((SocketAddr::new(external, u16_at(&response, 42)), lifetime))
+}
+
+fn natpmp_external_ip(addr: SocketAddr, bind: &[IpAddr]) -> Result<Ipv4Addr, MapError> {
+    let response = udp_request(addr, &[NATPMP_VERSION, NATPMP_EXTERNAL_ADDRESS], bind)?;
+    if response.len() < 12 || response[1] != PCP_RESPONSE | NATPMP_EXTERNAL_ADDRESS {
+        return Err(MapError::InvalidResponse);
+    }
+    match u16_at(&response, 2) {
+        0 => Ok(Ipv4Addr::new(
+            response[8],
+            response[9],
+            response[10],
+            response[11],
+        )),
+        code => Err(MapError::Rejected(code)),
+    }
+}
+
+fn natpmp_map(
+    addr: SocketAddr,
+    protocol: Protocol,
+    port: u16,
+    lifetime: Duration,
+    bind: &[IpAddr],
+) -> Result<(u16, Duration), MapError> {
+    // a mapping is removed by asking for no lifetime and no external port
+    let external = if lifetime.as_secs() == 0 { 0 } else { port };
+    let opcode = protocol.natpmp_opcode();
+    let mut request = vec![NATPMP_VERSION, opcode, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&external.to_be_bytes());
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+
+    let response = udp_request(addr, &request, bind)?;
+    if response.len() < 16 || response[1] != PCP_RESPONSE | opcode {
+        return Err(MapError::InvalidResponse);
+    }
+    match u16_at(&response, 2) {
+        0 => Ok((
+            u16_at(&response, 10),
+            Duration::from_secs(u32_at(&response, 12) as u64),
+        )),
+        code => Err(MapError::Rejected(code)),
+    }
+}
+
+// the LOCATION header of an SSDP answer, where the device describes itself
+fn ssdp_location(response: &str) -> Option<&str> {
+    response.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        Some(value.trim()).filter(|_| name.trim().eq_ignore_ascii_case("location"))
+    })
+}
+
+// asks the LAN for Internet Gateway Devices and returns where the first one
+// that answers describes itself
+fn ssdp_discover(bind: &[IpAddr]) -> Result<String, MapError> {
+    let group: SocketAddr = SSDP_ADDR.parse().unwrap();
+    let socket = bind::udp(group.ip(), 0, bind).map_err(MapError::ConnectionFailure)?;
+    // multicast leaves through the interface of the address we are bound to
+    if let Ok(SocketAddr::V4(local)) = socket.local_addr() {
+        if !local.ip().is_unspecified() {
+            socket
+                .set_multicast_if_v4(local.ip())
+                .map_err(MapError::ConnectionFailure)?;
+        }
+    }
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+         ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+        SSDP_ADDR
+    );
+    socket
+        .send_to(request.as_bytes(), group)
+        .map_err(MapError::ConnectionFailure)?;
+    socket
+        .set_read_timeout(Some(SSDP_WAIT))
+        .map_err(MapError::ConnectionFailure)?;
+    let mut buf = [0u8; 2048];
+    loop {
+        let n = socket.recv(&mut buf).map_err(|_| MapError::NoGateway)?;
+        if let Some(location) = ssdp_location(&String::from_utf8_lossy(&buf[..n])) {
+            return Ok(location.to_owned());
+        }
+    }
+}
+
+// the text inside the first `<tag>` of `xml`, ignoring namespace prefixes
+fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let mut rest = xml;
+    loop {
+        let open = rest.find('<')?;
+        rest = &rest[open + 1..];
+        let end = rest.find('>')?;
+        let name = rest[..end].split_whitespace().next().unwrap_or_default();
+        let local = name.rsplit(':').next().unwrap_or_default();
+        rest = &rest[end + 1..];
+        if local == tag && !name.starts_with('/') {
+            return Some(rest[..rest.find('<')?].trim());
+        }
+    }
+}
+
+// a blocking HTTP/1.0 request; returns the status, the body and the address
+// we made the request from
+fn http_request(
+    url: &Url,
+    soap_action: Option<&str>,
+    body: &str,
+    bind: &[IpAddr],
+) -> Result<(u16, String, IpAddr), MapError> {
+    let addr = url
+        .socket_addrs(|| None)
+        .map_err(MapError::ConnectionFailure)?
+        .into_iter()
+        .next()
+        .ok_or(MapError::NoGateway)?;
+    let mut stream =
+        bind::connect_std(addr, bind, HTTP_TIMEOUT).map_err(MapError::ConnectionFailure)?;
+    let local = stream.local_addr().map_err(MapError::ConnectionFailure)?;
+    stream
+        .set_read_timeout(Some(HTTP_TIMEOUT))
+        .map_err(MapError::ConnectionFailure)?;
+    let path = &url[Position::BeforePath..Position::AfterQuery];
+    let host = &url[Position::BeforeHost..Position::AfterPort];
+    let request = match soap_action {
+        Some(action) => format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+             SOAPAction: \"{}\"\r\nContent-Length: {}\r\n\r\n{}",
+            path,
+            host,
+            action,
+            body.len(),
+            body
+        ),
+        None => format!("GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host),
+    };
+    stream
+        .write_all(request.as_bytes())
+        .map_err(MapError::ConnectionFailure)?;
+    let mut response = vec![];
+    stream
+        .read_to_end(&mut response)
+        .map_err(MapError::ConnectionFailure)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or(MapError::InvalidResponse)?;
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or(MapError::InvalidResponse)?;
+    Ok((status, body.to_owned(), local.ip()))
+}
+
+// the WAN connection service of the device described at `location`
+fn upnp_gateway(location: &str, bind: &[IpAddr]) -> Result<Gateway, MapError> {
+    let location = Url::parse(location).map_err(|_| MapError::InvalidResponse)?;
+    let (status, description, client) = http_request(&location, None, "", bind)?;
+    if status != 200 {
+        return Err(MapError::Rejected(status));
+    }
+    for block in description.split("<service>").skip(1) {
+        let service = match xml_text(block, "serviceType") {
+            Some(service) if WAN_SERVICES.contains(&service) => service,
+            _ => continue,
+        };
+        let control = xml_text(block, "controlURL").ok_or(MapError::InvalidResponse)?;
+        let control_url = location
+            .join(control)
+            .map_err(|_| MapError::InvalidResponse)?;
+        return Ok(Gateway::Upnp {
+            control_url,
+            service: service.to_owned(),
+            client,
+        });
+    }
+    Err(MapError::Unsupported)
+}
+
+// calls `action` of a UPnP service and returns the body of its answer
+fn soap_call(
+    control_url: &Url,
+    service: &str,
+    action: &str,
+    args: &[(&str, String)],
+    bind: &[IpAddr],
+) -> Result<String, MapError> {
+    let args: String = args
+        .iter()
+        .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+        .collect();
+    let body = format!(
+        "<?xml version=\"1.0\"?>\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+        action, service, args
+    );
+    let soap_action = format!("{}#{}", service, action);
+    let (status, body, _) = http_request(control_url, Some(&soap_action), &body, bind)?;
+    match status {
+        200 => Ok(body),
+        _ => Err(MapError::Rejected(
+            xml_text(&body, "errorCode")
+                .and_then(|code| code.parse().ok())
+                .unwrap_or(status),
+        )),
+    }
+}
+
+fn upnp_map(
+    control_url: &Url,
+    service: &str,
+    client: IpAddr,
+    protocol: Protocol,
+    port: u16,
+    lifetime: Duration,
+    bind: &[IpAddr],
+) -> Result<(), MapError> {
+    let add = |lease: Duration| {
+        soap_call(
+            control_url,
+            service,
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", port.to_string()),
+                ("NewProtocol", protocol.name().to_owned()),
+                ("NewInternalPort", port.to_string()),
+                ("NewInternalClient", client.to_string()),
+                ("NewEnabled", "1".to_owned()),
+                ("NewPortMappingDescription", "herb".to_owned()),
+                ("NewLeaseDuration", lease.as_secs().to_string()),
+            ],
+            bind,
+        )
+    };
+    match add(lifetime) {
+        // the mapping stays until we remove it, and renewing it is harmless
+        Err(MapError::Rejected(ONLY_PERMANENT_LEASES)) => add(Duration::from_secs(0)).map(|_| ()),
+        result => result.map(|_| ()),
+    }
+}
+
+fn upnp_external_ip(control_url: &Url, service: &str, bind: &[IpAddr]) -> Result<IpAddr, MapError> {
+    let body = soap_call(control_url, service, "GetExternalIPAddress", &[], bind)?;
+    xml_text(&body, "NewExternalIPAddress")
+        .and_then(|ip| ip.parse().ok())
+        .ok_or(MapError::InvalidResponse)
+}
+
+fn upnp_unmap(
+    control_url: &Url,
+    service: &str,
+    protocol: Protocol,
+    port: u16,
+    bind: &[IpAddr],
+) -> Result<(), MapError> {
+    soap_call(
+        control_url,
+        service,
+        "DeletePortMapping",
+        &[
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", protocol.name().to_owned()),
+        ],
+        bind,
+    )
+    .map(|_| ())
+}
+
+impl Gateway {
+    // forwards `port` of the gateway to ours for `lifetime`, talking to it
+    // from an address of `bind`; TCP has to be forwarded, UDP only if the
+    // gateway lets us
+    fn map(&self, port: u16, lifetime: Duration, bind: &[IpAddr]) -> Result<Mapping, MapError> {
+        let (ip, external_port, granted) =
+            self.map_protocol(Protocol::Tcp, port, lifetime, bind)?;
+        let udp = self
+            .map_protocol(Protocol::Udp, port, lifetime, bind)
+            .is_ok();
+        let ip = match ip {
+            Some(ip) => ip,
+            None => self.external_ip(bind)?,
+        };
+        Ok(Mapping {
+            gateway: self.clone(),
+            port,
+            external: SocketAddr::new(ip, external_port),
+            lifetime: granted,
+            udp,
+            bind: bind.to_vec(),
+        })
+    }
+
+    // forwards `port` for `protocol`, returning the external port and the
+    // lifetime the gateway granted; only PCP tells the external IP with it
+    fn map_protocol(
+        &self,
+        protocol: Protocol,
+        port: u16,
+        lifetime: Duration,
+        bind: &[IpAddr],
+    ) -> Result<(Option<IpAddr>, u16, Duration), MapError> {
+        match self {
+            Gateway::Pcp {
+                addr,
+                client,
+                nonce,
+            } => {
+                let (external, lifetime) =
+                    pcp_map(*addr, *client, *nonce, protocol, port, lifetime, bind)?;
+                Ok((Some(external.ip()), external.port(), lifetime))
+            }
+            Gateway::NatPmp(addr) => {
+                let (external, lifetime) = natpmp_map(*addr, protocol, port, lifetime, bind)?;
+                Ok((None, external, lifetime))
+            }
+            Gateway::Upnp {
+                control_url,
+                service,
+                client,
+            } => {
+                upnp_map(
+                    control_url,
+                    service,
+                    *client,
+                    protocol,
+                    port,
+                    lifetime,
+                    bind,
+                )?;
+                Ok((None, port, lifetime))
+            }
+        }
+    }
+
+    fn external_ip(&self, bind: &[IpAddr]) -> Result<IpAddr, MapError> {
+        match self {
+            Gateway::Pcp { .. } => Err(MapError::InvalidResponse),
+            Gateway::NatPmp(addr) => Ok(IpAddr::V4(natpmp_external_ip(*addr, bind)?)),
+            Gateway::Upnp {
+                control_url,
+                service,
+                ..
+            } => upnp_external_ip(control_url, service, bind),
+        }
+    }
+
+    fn unmap(&self, protocol: Protocol, port: u16, bind: &[IpAddr]) -> Result<(), MapError> {
+        match self {
+            Gateway::Upnp {
+                control_url,
+                service,
+                ..
+            } => upnp_unmap(control_url, service, protocol, port, bind),
+            gateway => gateway
+                .map_protocol(protocol, port, Duration::from_secs(0), bind)
+                .map(|_| ()),
+        }
+    }
+}
+
+// a PCP or NAT-PMP gateway at `addr`, whichever it speaks
+fn pmp_gateway(addr: SocketAddr, port: u16, bind: &[IpAddr]) -> Result<Mapping, MapError> {
+    let client = match local_ip(addr, bind)? {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Err(MapError::Unsupported),
+    };
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&peer_id::random_u64().to_be_bytes());
+    nonce[8..].copy_from_slice(&(peer_id::random_u64() as u32).to_be_bytes());
+    let pcp = Gateway::Pcp {
+        addr,
+        client,
+        nonce,
+    };
+    match pcp.map(port, LEASE, bind) {
+        Err(MapError::Unsupported) => Gateway::NatPmp(addr).map(port, LEASE, bind),
+        result => result,
+    }
+}
+
+// forwards `port` on the gateway of our network, asking it over PCP or
+// NAT-PMP first and over UPnP then; we talk to it from an address of `bind`
+// unless it is empty
+pub fn map_port(port: u16, bind: &[IpAddr]) -> Result<Mapping, MapError> {
+    let pmp = default_gateway()
+        .ok_or(MapError::NoGateway)
+        .and_then(|ip| pmp_gateway(SocketAddr::new(IpAddr::V4(ip), NATPMP_PORT), port, bind));
+    match pmp {
+        Ok(mapping) => Ok(mapping),
+        Err(_) => upnp_gateway(&ssdp_discover(bind)?, bind)?.map(port, LEASE, bind),
+    }
+}
+
+impl Mapping {
+    // asks the gateway for a new lease
+    pub fn renew(&self) -> Result<Mapping, MapError> {
+        self.gateway.map(self.port, LEASE, &self.bind)
+    }
+
+    // removes the mapping from the gateway, of both protocols
+    pub fn remove(&self) -> Result<(), MapError> {
+        let tcp = self.gateway.unmap(Protocol::Tcp, self.port, &self.bind);
+        if self.udp {
+            self.gateway.unmap(Protocol::Udp, self.port, &self.bind)?;
+        }
+        tcp
+    }
+}
+
+// PortMapper keeps our listen port forwarded on the gateway while it lives,
+// and publishes the address peers outside reach us at
+pub struct PortMapper {
+    // dropped to tell the thread to stop
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+pub fn start_port_mapper(
+    port: u16,
+    bind: Vec<IpAddr>,
+    external: Arc<Mutex<Option<SocketAddr>>>,
+) -> PortMapper {
+    spawn_mapper(port, external, move || map_port(port, &bind))
+}
+
+// keeps the mapping `map` makes renewed, or looks for a gateway again while
+// there is none
+fn spawn_mapper(
+    port: u16,
+    external: Arc<Mutex<Option<SocketAddr>>>,
+    map: impl Fn() -> Result<Mapping, MapError> + Send + 'static,
+) -> PortMapper {
+    let (stop, stopped) = channel::bounded::<()>(0);
+    let thread = thread::spawn(move || {
+        let mut mapping: Option<Mapping> = None;
+        let mut first = true;
+        loop {
+            let result = match &mapping {
+                Some(m) => m.renew(),
+                None => map(),
+            };
+            // a mapping made after we were told to stop is removed right away
+            if let Err(TryRecvError::Disconnected) = stopped.try_recv() {
+                if let Ok(m) = result {
+                    mapping = Some(m);
+                }
+                break;
+            }
+            let wait = match result {
+                Ok(m) => {
+                    if mapping.as_ref().map(|old| old.external) != Some(m.external) {
+                        println!(
+                            "port {} is reachable at {} via {}",
+                            port, m.external, m.gateway
+                        );
+                    }
+                    *external.lock().unwrap() = Some(m.external);
+                    let wait = (m.lifetime / 2).max(Duration::from_secs(60));
+                    mapping = Some(m);
+                    wait
+                }
+                Err(e) => {
+                    if mapping.take().is_some() {
+                        println!("port {} is no longer mapped: {}", port, e);
+                    } else if first {
+                        println!("port {} isn't mapped: {}", port, e);
+                    }
+                    *external.lock().unwrap() = None;
+                    RETRY_DELAY
+                }
+            };
+            first = false;
+            match stopped.recv_timeout(wait) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => break,
+            }
+        }
+        if let Some(m) = mapping {
+            if let Err(e) = m.remove() {
+                println!("couldn't remove the mapping of port {}: {}", port, e);
+            }
+        }
+    });
+    PortMapper {
+        stop: Some(stop),
+        thread: Some(thread),
+    }
+}
+
+impl Drop for PortMapper {
+    // removes the mapping before the session goes away; a search for the
+    // gateway that is still running is waited for, so that what it maps is
+    // removed as well
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, Ipv4Addr, TcpListener, UdpSocket};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[test]
+    fn gateways_and_locations_are_found() {
+        let route = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+            eth0\t0001A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+            eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+        assert_eq!(
+            super::parse_route_table(route),
+            Some(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(super::parse_route_table("Iface\n"), None);
+
+        let answer = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+            Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(
+            super::ssdp_location(answer),
+            Some("http://192.168.1.1:5000/rootDesc.xml")
+        );
+        assert_eq!(
+            super::xml_text(
+                "<s:Body><u:R><NewExternalIPAddress>1.2.3.4</NewExternalIPAddress>",
+                "NewExternalIPAddress"
+            ),
+            Some("1.2.3.4")
+        );
+    }
+
+    // the requests a gateway got, with the address each came from
+    type Requests = Arc<Mutex<Vec<(IpAddr, Vec<u8>)>>>;
+
+    // a gateway that only speaks NAT-PMP, and forwards to the port after ours
+    fn natpmp_gateway(socket: UdpSocket, requests: Requests) {
+        let mut buf = [0u8; 1100];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).unwrap();
+            let request = buf[..n].to_vec();
+            requests.lock().unwrap().push((from.ip(), request.clone()));
+            let response = match request[..2] {
+                [2, _] => vec![0, 0x81, 0, 1, 0, 0, 0, 0],
+                [0, 0] => vec![0, 0x80, 0, 0, 0, 0, 0, 9, 203, 0, 113, 7],
+                [0, op @ 1..=2] => {
+                    let port = u16::from_be_bytes([request[4], request[5]]);
+                    let mut response = vec![0, 0x80 | op, 0, 0, 0, 0, 0, 9];
+                    response.extend_from_slice(&request[4..6]);
+                    let external = if request[8..12] == [0; 4] {
+                        0
+                    } else {
+                        port + 1
+                    };
+                    response.extend_from_slice(&external.to_be_bytes());
+                    response.extend_from_slice(&request[8..12]);
+                    response
+                }
+                _ => continue,
+            };
+            socket.send_to(&response, from).unwrap();
+        }
+    }
+
+    #[test]
+    fn natpmp_gateways_forward_ports() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let gateway = socket.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&requests);
+        thread::spawn(move || natpmp_gateway(socket, seen));
+
+        // PCP comes first and is refused, so NAT-PMP is used
+        let bind: Vec<IpAddr> = vec!["127.0.0.3".parse().unwrap()];
+        let mapping = super::pmp_gateway(gateway, 6881, &bind).unwrap();
+        assert_eq!(mapping.external, "203.0.113.7:6882".parse().unwrap());
+        assert_eq!(mapping.lifetime, super::LEASE);
+        assert_eq!(requests.lock().unwrap()[0].1[..2], [2, 1]);
+
+        assert!(mapping.udp);
+
+        mapping.renew().unwrap();
+        mapping.remove().unwrap();
+        let requests = requests.lock().unwrap();
+        // both protocols are mapped and removed
+        let ops: Vec<u8> = requests.iter().map(|(_, r)| r[1]).collect();
+        assert_eq!(ops[1..], [2, 1, 0, 2, 1, 0, 2, 1]);
+        for (_, removal) in requests[requests.len() - 2..].iter() {
+            assert_eq!(removal[6..12], [0; 6]);
+        }
+        // every request came from the bind address
+        assert!(requests.iter().all(|(from, _)| *from == bind[0]));
+    }
+
+    #[test]
+    fn mappings_made_while_stopping_are_removed() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let gateway = socket.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&requests);
+        thread::spawn(move || natpmp_gateway(socket, seen));
+
+        // the mapper is dropped while it is still looking for the gateway
+        let external = Arc::new(Mutex::new(None));
+        let (searching, search) = crossbeam::channel::bounded(0);
+        let mapper = super::spawn_mapper(6881, Arc::clone(&external), move || {
+            searching.send(()).unwrap();
+            thread::sleep(std::time::Duration::from_millis(100));
+            super::pmp_gateway(gateway, 6881, &[])
+        });
+        search.recv().unwrap();
+        drop(mapper);
+
+        assert_eq!(*external.lock().unwrap(), None);
+        let requests = requests.lock().unwrap();
+        let removals: Vec<u8> = requests
+            .iter()
+            .filter(|(_, r)| r[0] == 0 && r[1] != 0 && r[8..12] == [0; 4])
+            .map(|(_, r)| r[1])
+            .collect();
+        assert_eq!(removals, [2, 1]);
+    }
+
+    const DESCRIPTION: &str = "<root><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+        <controlURL>/l3f</controlURL></service>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <controlURL>/ctl/IPConn</controlURL></service>\
+        </serviceList></device></root>";
+
+    // a UPnP gateway that only keeps permanent mappings
+    fn upnp_gateway(listener: TcpListener, requests: Arc<Mutex<Vec<String>>>) {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = String::new();
+            let mut buf = [0u8; 4096];
+            while !(request.starts_with("GET") && request.ends_with("\r\n\r\n")
+                || request.ends_with("</s:Envelope>"))
+            {
+                let n = stream.read(&mut buf).unwrap();
+                request.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+            }
+            let (status, body) = if request.starts_with("GET /desc.xml") {
+                ("200 OK", DESCRIPTION)
+            } else if request.contains("<NewLeaseDuration>3600<") {
+                (
+                    "500 Internal Server Error",
+                    "<s:Envelope><s:Body><s:Fault><detail><UPnPError>\
+                     <errorCode>725</errorCode></UPnPError></detail></s:Fault></s:Body></s:Envelope>",
+                )
+            } else if request.contains("#GetExternalIPAddress") {
+                (
+                    "200 OK",
+                    "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                     <NewExternalIPAddress>198.51.100.4</NewExternalIPAddress>\
+                     </u:GetExternalIPAddressResponse></s:Body></s:Envelope>",
+                )
+            } else {
+                ("200 OK", "")
+            };
+            requests.lock().unwrap().push(request);
+            let response = format!("HTTP/1.0 {}\r\n\r\n{}", status, body);
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    }
+
+    #[test]
+    fn upnp_gateways_forward_ports() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let location = format!("http://{}/desc.xml", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&requests);
+        thread::spawn(move || upnp_gateway(listener, seen));
+
+        let bind: Vec<IpAddr> = vec!["127.0.0.3".parse().unwrap()];
+        let gateway = super::upnp_gateway(&location, &bind).unwrap();
+        let mapping = gateway.map(6881, super::LEASE, &bind).unwrap();
+        assert_eq!(mapping.external, "198.51.100.4:6881".parse().unwrap());
+        assert_eq!(mapping.lifetime, super::LEASE);
+        mapping.remove().unwrap();
+
+        let requests = requests.lock().unwrap();
+        let actions: Vec<&str> = requests
+            .iter()
+            .map(|r| {
+                r.split("SOAPAction: \"")
+                    .nth(1)
+                    .map_or("", |a| a.split('"').next().unwrap())
+            })
+            .collect();
+        let service = "urn:schemas-upnp-org:service:WANIPConnection:1";
+        assert_eq!(
+            actions,
+            [
+                "".to_owned(),
+                format!("{}#AddPortMapping", service),
+                format!("{}#AddPortMapping", service),
+                format!("{}#AddPortMapping", service),
+                format!("{}#AddPortMapping", service),
+                format!("{}#GetExternalIPAddress", service),
+                format!("{}#DeletePortMapping", service),
+                format!("{}#DeletePortMapping", service),
+            ]
+        );
+        // the gateway only keeps permanent mappings, to the bind address we
+        // reached it from
+        assert!(requests[2].contains("<NewLeaseDuration>0</NewLeaseDuration>"));
+        assert!(requests[2].contains("<NewInternalClient>127.0.0.3</NewInternalClient>"));
+        // TCP and UDP are both forwarded and removed
+        for (request, protocol) in [(2, "TCP"), (4, "UDP"), (6, "TCP"), (7, "UDP")].iter() {
+            let protocol = format!("<NewProtocol>{}</NewProtocol>", protocol);
+            assert!(requests[*request].contains(&protocol));
+        }
+        assert!(requests[1].starts_with("POST /ctl/IPConn HTTP/1.0"));
+    }
+}
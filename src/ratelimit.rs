@@ -0,0 +1,275 @@
+use std::num::NonZeroU64;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Rate is a limit in bytes per second that can be changed while buckets are
+// using it; no limit is stored as zero
+#[derive(Debug, Clone)]
+pub struct Rate(Arc<AtomicU64>);
+
+// Limits are the download and upload rates of one level: the session, a
+// torrent, or every single peer
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub download: Rate,
+    pub upload: Rate,
+}
+
+// TokenBucket lets through `rate` bytes per second on average, with bursts of
+// up to one second worth of bytes; a take that overdraws the bucket has to wait
+// until it is paid back
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    last: Instant,
+}
+
+pub type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+// Throttle holds every bucket bytes of one direction of a connection pass
+// through, from the peer's own up to the session's
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    buckets: Vec<SharedBucket>,
+}
+
+// RateWindow replaces the session limits during part of the day, given in
+// minutes after midnight UTC; a window may wrap around midnight
+#[derive(Debug, Clone)]
+pub struct RateWindow {
+    pub start: u32,
+    pub end: u32,
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+}
+
+pub fn new_rate(limit: Option<u64>) -> Rate {
+    Rate(Arc::new(AtomicU64::new(limit.unwrap_or(0))))
+}
+
+pub fn new_limits(download: Option<u64>, upload: Option<u64>) -> Limits {
+    Limits {
+        download: new_rate(download),
+        upload: new_rate(upload),
+    }
+}
+
+pub fn new_bucket(rate: Rate) -> SharedBucket {
+    Arc::new(Mutex::new(TokenBucket {
+        rate,
+        tokens: 0.0,
+        last: Instant::now(),
+    }))
+}
+
+pub fn new_throttle(buckets: Vec<SharedBucket>) -> Throttle {
+    Throttle { buckets }
+}
+
+impl Rate {
+    pub fn get(&self) -> Option<u64> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+
+    pub fn set(&self, limit: Option<u64>) {
+        self.0.store(limit.unwrap_or(0), Ordering::Relaxed);
+    }
+}
+
+impl Limits {
+    pub fn set(&self, download: Option<u64>, upload: Option<u64>) {
+        self.download.set(download);
+        self.upload.set(upload);
+    }
+}
+
+impl TokenBucket {
+    // takes `n` bytes out of the bucket and returns how long the caller has
+    // to wait before sending or reading more
+    pub fn take(&mut self, n: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        let rate = match self.rate.get() {
+            Some(rate) => rate as f64,
+            None => {
+                self.tokens = 0.0;
+                return Duration::from_secs(0);
+            }
+        };
+
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.tokens -= n as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+impl Throttle {
+    // accounts for `n` payload bytes and waits for the slowest bucket
+    pub async fn wait(&self, n: u64) {
+        let now = Instant::now();
+        let delay = self
+            .buckets
+            .iter()
+            .map(|b| b.lock().unwrap().take(n, now))
+            .max()
+            .unwrap_or_default();
+        if delay > Duration::from_secs(0) {
+            tokio::time::delay_for(delay).await;
+        }
+    }
+}
+
+impl RateWindow {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+// minutes after midnight of `HH:MM`
+fn parse_time(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if hours < 24 && minutes < 60 {
+        Some(hours * 60 + minutes)
+    } else {
+        None
+    }
+}
+
+// parses a window of the form <HH:MM>-<HH:MM>[=<download>[/<upload>]] in UTC,
+// e.g. `01:00-07:00` for no limits at night or `09:00-17:00=50000/10000`; a
+// limit that is left out is unlimited, and a limit of 0 is refused since a
+// zero rate means no limit
+pub fn parse_window(spec: &str) -> Option<RateWindow> {
+    let (times, limits) = match spec.split_once('=') {
+        Some((times, limits)) => (times, Some(limits)),
+        None => (spec, None),
+    };
+    let (start, end) = times.split_once('-')?;
+    let (download_limit, upload_limit) = match limits {
+        None => (None, None),
+        Some(limits) => match limits.split_once('/') {
+            Some((download, upload)) => (Some(parse_limit(download)?), Some(parse_limit(upload)?)),
+            None => (Some(parse_limit(limits)?), None),
+        },
+    };
+    Some(RateWindow {
+        start: parse_time(start)?,
+        end: parse_time(end)?,
+        download_limit,
+        upload_limit,
+    })
+}
+
+fn parse_limit(limit: &str) -> Option<u64> {
+    limit.parse::<NonZeroU64>().ok().map(NonZeroU64::get)
+}
+
+// the limits in effect at `minute` after midnight: those of the first window
+// covering it, or `base` outside every window
+pub fn scheduled_limits(
+    schedule: &[RateWindow],
+    base: (Option<u64>, Option<u64>),
+    minute: u32,
+) -> (Option<u64>, Option<u64>) {
+    match schedule.iter().find(|w| w.contains(minute)) {
+        Some(w) => (w.download_limit, w.upload_limit),
+        None => base,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn unlimited_bucket_never_waits() {
+        let bucket = super::new_bucket(super::new_rate(None));
+        let now = Instant::now();
+        assert_eq!(
+            bucket.lock().unwrap().take(1 << 30, now),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn bucket_delays_by_overdraft() {
+        let rate = super::new_rate(Some(1000));
+        let bucket = super::new_bucket(rate.clone());
+        let mut bucket = bucket.lock().unwrap();
+        let start = Instant::now();
+
+        // one second refills the whole burst
+        assert_eq!(
+            bucket.take(1000, start + Duration::from_secs(1)),
+            Duration::from_secs(0)
+        );
+        // the next 500 bytes overdraw it by half a second
+        assert_eq!(
+            bucket.take(500, start + Duration::from_secs(1)),
+            Duration::from_millis(500)
+        );
+
+        // a new rate applies right away
+        rate.set(Some(2000));
+        assert_eq!(
+            bucket.take(500, start + Duration::from_secs(1)),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn scheduled_limits_wrap_around_midnight() {
+        let night = super::RateWindow {
+            start: 22 * 60,
+            end: 6 * 60,
+            download_limit: None,
+            upload_limit: None,
+        };
+        let base = (Some(100), Some(50));
+        let schedule = vec![night];
+        assert_eq!(
+            super::scheduled_limits(&schedule, base, 23 * 60),
+            (None, None)
+        );
+        assert_eq!(super::scheduled_limits(&schedule, base, 60), (None, None));
+        assert_eq!(super::scheduled_limits(&schedule, base, 12 * 60), base);
+    }
+
+    #[test]
+    fn parse_window_reads_times_and_limits() {
+        let night = super::parse_window("22:30-06:00").unwrap();
+        assert_eq!((night.start, night.end), (22 * 60 + 30, 6 * 60));
+        assert_eq!((night.download_limit, night.upload_limit), (None, None));
+
+        let day = super::parse_window("09:00-17:00=50000/10000").unwrap();
+        assert_eq!(
+            (day.download_limit, day.upload_limit),
+            (Some(50000), Some(10000))
+        );
+        let down = super::parse_window("09:00-17:00=50000").unwrap();
+        assert_eq!(
+            (down.download_limit, down.upload_limit),
+            (Some(50000), None)
+        );
+
+        assert!(super::parse_window("24:00-06:00").is_none());
+        assert!(super::parse_window("22:00").is_none());
+        assert!(super::parse_window("22:00-06:00=fast").is_none());
+        assert!(super::parse_window("22:00-06:00=0").is_none());
+        assert!(super::parse_window("22:00-06:00=100/0").is_none());
+    }
+}
@@ -0,0 +1,175 @@
+use serde_bencode::{de, ser};
+use serde_bytes::ByteBuf;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::bitfield;
+use crate::files::FilePriority;
+use crate::torrent;
+
+#[derive(Debug)]
+pub enum ResumeError {
+    ReadFailure(io::Error),
+    WriteFailure(io::Error),
+    DecodeFailure,
+    InfoHashMismatch,
+}
+
+impl fmt::Display for ResumeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResumeError::ReadFailure(e) => write!(f, "couldn't read resume state: {}", e),
+            ResumeError::WriteFailure(e) => write!(f, "couldn't write resume state: {}", e),
+            ResumeError::DecodeFailure => write!(f, "resume state is corrupt"),
+            ResumeError::InfoHashMismatch => write!(f, "resume state is for another torrent"),
+        }
+    }
+}
+
+impl Error for ResumeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ResumeError::ReadFailure(e) | ResumeError::WriteFailure(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// ResumeState is what we keep on disk between runs: which files are wanted
+// and which pieces are already written
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResumeState {
+    #[serde(rename = "info hash")]
+    info_hash: ByteBuf,
+    #[serde(rename = "file priorities")]
+    file_priorities: ByteBuf,
+    pieces: ByteBuf,
+}
+
+pub fn new_resume_state(
+    t: &torrent::Torrent,
+    file_priorities: &[FilePriority],
+    have: &bitfield::Bitfield,
+) -> ResumeState {
+    ResumeState {
+        info_hash: ByteBuf::from(t.info_hash.to_vec()),
+        file_priorities: ByteBuf::from(
+            file_priorities
+                .iter()
+                .map(|p| *p as u8)
+                .collect::<Vec<u8>>(),
+        ),
+        pieces: ByteBuf::from(have.array.clone()),
+    }
+}
+
+pub fn load(path: &Path, t: &torrent::Torrent) -> Result<ResumeState, ResumeError> {
+    let data = fs::read(path).map_err(ResumeError::ReadFailure)?;
+    let state = de::from_bytes::<ResumeState>(&data).map_err(|_| ResumeError::DecodeFailure)?;
+    if state.info_hash[..] != t.info_hash[..] {
+        return Err(ResumeError::InfoHashMismatch);
+    }
+    if state.file_priorities.len() != t.files.len() {
+        return Err(ResumeError::DecodeFailure);
+    }
+    Ok(state)
+}
+
+impl ResumeState {
+    pub fn save(&self, path: &Path) -> Result<(), ResumeError> {
+        // the state is plain data, which always encodes
+        let data = ser::to_bytes(self).unwrap();
+        fs::write(path, data).map_err(ResumeError::WriteFailure)
+    }
+
+    pub fn file_priorities(&self) -> Vec<FilePriority> {
+        self.file_priorities
+            .iter()
+            .map(|p| FilePriority::from_u8(*p).unwrap_or(FilePriority::Normal))
+            .collect()
+    }
+
+    // returns the pieces we already have on disk for the given file
+    // priorities; pieces that were only partly written because they overlap a
+    // file that was skipped back then, but is wanted now, must be fetched again
+    pub fn have(
+        &self,
+        t: &torrent::Torrent,
+        file_priorities: &[FilePriority],
+    ) -> bitfield::Bitfield {
+        let mut have = bitfield::new_bitfield(t.piece_hashes.len() as i64);
+        for (byte, stored) in have.array.iter_mut().zip(self.pieces.iter()) {
+            *byte = *stored;
+        }
+
+        let previous = self.file_priorities();
+        for (index, priority) in file_priorities.iter().enumerate() {
+            if previous[index] == FilePriority::Skip && *priority != FilePriority::Skip {
+                for piece in t.pieces_for_file(index) {
+                    have.clear_piece(piece);
+                }
+            }
+        }
+        have
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use crate::bitfield;
+    use crate::files::FilePriority;
+    use crate::torrent;
+
+    #[test]
+    fn have_refetches_pieces_of_newly_wanted_files() {
+        let files = vec![
+            torrent::TorrentFile {
+                path: PathBuf::from("t/a"),
+                length: 15,
+                offset: 0,
+                padding: false,
+            },
+            torrent::TorrentFile {
+                path: PathBuf::from("t/b"),
+                length: 15,
+                offset: 15,
+                padding: false,
+            },
+        ];
+        let t = torrent::Torrent {
+            announce: String::new(),
+            trackers: vec![],
+            name: "t".to_owned(),
+            length: 30,
+            info_hash: [1u8; 20],
+            info_hash_v2: None,
+            version: torrent::MetaVersion::V1,
+            piece_length: 10,
+            piece_hashes: vec![torrent::PieceHash::V1([0u8; 20]); 3],
+            files,
+            info_bytes: vec![],
+            piece_layers: HashMap::new(),
+            web_seeds: vec![],
+            http_seeds: vec![],
+            private: false,
+        };
+
+        // first run: only `a` wanted, pieces 0 and 1 downloaded
+        let mut have = bitfield::new_bitfield(3);
+        have.set_piece(0);
+        have.set_piece(1);
+        let state = super::new_resume_state(&t, &[FilePriority::Normal, FilePriority::Skip], &have);
+
+        // second run: `b` is wanted too, so the shared piece 1 is fetched again
+        let have = state.have(&t, &[FilePriority::Normal, FilePriority::Normal]);
+        assert!(have.has_piece(0));
+        assert!(!have.has_piece(1));
+        assert!(!have.has_piece(2));
+    }
+}
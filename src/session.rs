@@ -0,0 +1,1997 @@
+use crossbeam::channel::{Receiver, Select, Sender};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::{self, Handle, Runtime};
+use tokio::sync::watch;
+use tokio::time::timeout;
+
+use crate::bind;
+use crate::bitfield;
+use crate::choker;
+use crate::dht;
+use crate::files;
+use crate::handshake;
+use crate::ipfilter;
+use crate::magnet;
+use crate::merkle;
+use crate::metadata;
+use crate::p2p;
+use crate::peer_id;
+use crate::peers;
+use crate::portmap;
+use crate::proxy;
+use crate::ratelimit;
+use crate::resume;
+use crate::smartban;
+use crate::storage;
+use crate::torrent;
+use crate::tracker;
+use crate::webseed;
+
+// how long we wait before asking the tracker again when no peer is left,
+// and when we have some but fewer than we want
+const REANNOUNCE_DELAY: Duration = Duration::from_secs(5);
+const TOP_UP_DELAY: Duration = Duration::from_secs(60);
+
+// how often we search the DHT while short of peers, and announce to it while
+// seeding, which keeps us in the peer lists of the nodes
+const DHT_SEARCH_DELAY: Duration = Duration::from_secs(60);
+const DHT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+// how often the queue is looked at to start and stop torrents
+const QUEUE_INTERVAL: Duration = Duration::from_secs(1);
+
+// how long a session that goes away waits for its torrents to tell their
+// trackers that they stopped
+const STOP_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum SessionError {
+    InvalidTorrent(torrent::InvalidTorrentError),
+    InvalidMagnet(magnet::MagnetError),
+    InvalidSelection(files::SelectionError),
+    DuplicateTorrent,
+    ListenFailure(io::Error),
+    RuntimeFailure(io::Error),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::InvalidTorrent(e) => write!(f, "invalid torrent: {}", e),
+            SessionError::InvalidMagnet(e) => write!(f, "invalid magnet link: {}", e),
+            SessionError::InvalidSelection(e) => write!(f, "invalid file selection: {}", e),
+            SessionError::DuplicateTorrent => write!(f, "torrent was already added"),
+            SessionError::ListenFailure(e) => write!(f, "unable to listen: {}", e),
+            SessionError::RuntimeFailure(e) => write!(f, "unable to start runtime: {}", e),
+        }
+    }
+}
+
+impl Error for SessionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SessionError::InvalidTorrent(e) => Some(e),
+            SessionError::InvalidMagnet(e) => Some(e),
+            SessionError::InvalidSelection(e) => Some(e),
+            SessionError::ListenFailure(e) => Some(e),
+            SessionError::RuntimeFailure(e) => Some(e),
+            SessionError::DuplicateTorrent => None,
+        }
+    }
+}
+
+// TorrentError is why a torrent failed
+#[derive(Debug)]
+pub enum TorrentError {
+    InvalidTorrent(torrent::InvalidTorrentError),
+    InvalidSelection(files::SelectionError),
+    MetadataFailure(metadata::MetadataError),
+    NoTrackers,
+    WriteFailure(i64, io::Error),
+}
+
+impl fmt::Display for TorrentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TorrentError::InvalidTorrent(e) => write!(f, "invalid torrent: {}", e),
+            TorrentError::InvalidSelection(e) => write!(f, "invalid file selection: {}", e),
+            TorrentError::MetadataFailure(e) => write!(f, "could not fetch metadata: {}", e),
+            TorrentError::NoTrackers => write!(f, "magnet link has no trackers"),
+            TorrentError::WriteFailure(index, e) => {
+                write!(f, "couldn't write piece #{}: {}", index, e)
+            }
+        }
+    }
+}
+
+impl Error for TorrentError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TorrentError::InvalidTorrent(e) => Some(e),
+            TorrentError::InvalidSelection(e) => Some(e),
+            TorrentError::MetadataFailure(e) => Some(e),
+            TorrentError::WriteFailure(_, e) => Some(e),
+            TorrentError::NoTrackers => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TorrentState {
+    FetchingMetadata,
+    Queued,
+    Paused,
+    Downloading,
+    Seeding,
+    Finished,
+    Failed,
+    Removed,
+}
+
+// Progress is a snapshot of how far a torrent got; pieces and bytes only
+// count what was selected for download
+#[derive(Debug, Clone)]
+pub struct Progress {
+    pub state: TorrentState,
+    pub name: Option<String>,
+    pub total_pieces: usize,
+    pub done_pieces: usize,
+    pub total_bytes: i64,
+    pub done_bytes: i64,
+    pub peers: usize,
+    pub uploaded: i64,
+    // why the torrent failed, once it did
+    pub error: Option<Arc<TorrentError>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AddTorrentOptions {
+    pub output_dir: PathBuf,
+    pub file_selectors: Vec<files::FileSelector>,
+    pub upload_slots: usize,
+    // connections we try to keep open, counting the ones peers opened
+    pub target_peers: usize,
+    pub paused: bool,
+    // bytes per second for this torrent; None is unlimited
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+}
+
+// SessionOptions are shared by every torrent of a session; torrents beyond
+// the active limits wait in the queue in the order they were added
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    pub listen_port: u16,
+    pub max_connections: usize,
+    pub max_active_downloads: usize,
+    pub max_active_seeds: usize,
+    // bytes per second for the whole session and for every single peer;
+    // None is unlimited
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+    pub peer_download_limit: Option<u64>,
+    pub peer_upload_limit: Option<u64>,
+    // windows of the day in UTC with other session limits, e.g. unlimited at
+    // night
+    pub rate_schedule: Vec<ratelimit::RateWindow>,
+    // peers we never connect to or accept
+    pub ip_filter: ipfilter::IpFilter,
+    // what connections to peers and trackers go through
+    pub proxy: Option<proxy::Proxy>,
+    // the local addresses we listen on and connect from; empty for any
+    pub bind: Vec<net::IpAddr>,
+    // forward the TCP and UDP listen port on the router through PCP, NAT-PMP
+    // or UPnP
+    pub port_mapping: bool,
+    // find peers of public torrents in the DHT, with one node on the listen
+    // port for the whole session, which joins through `dht_routers`
+    pub dht: bool,
+    pub dht_routers: Vec<String>,
+}
+
+// Session owns the torrents that are being downloaded and seeded, and the
+// runtime their peer connections run on
+pub struct Session {
+    inner: Arc<Inner>,
+    runtime: Option<Runtime>,
+    port_mapper: Option<portmap::PortMapper>,
+}
+
+// TorrentHandle controls one torrent of a session; it is cheap to clone
+#[derive(Clone)]
+pub struct TorrentHandle {
+    shared: Arc<Shared>,
+}
+
+struct Inner {
+    options: SessionOptions,
+    port: u16,
+    // where peers outside our NAT reach us, once the port is mapped
+    external: Arc<Mutex<Option<net::SocketAddr>>>,
+    // sent with every announce of the session
+    key: u32,
+    peer_id: [u8; 20],
+    runtime: Handle,
+    torrents: Mutex<Vec<TorrentHandle>>,
+    connections: Arc<Connections>,
+    blocklist: Arc<Blocklist>,
+    // the session limits set by the user, before the schedule applies
+    base_limits: Mutex<(Option<u64>, Option<u64>)>,
+    limits: ratelimit::Limits,
+    buckets: (ratelimit::SharedBucket, ratelimit::SharedBucket),
+    peer_limits: ratelimit::Limits,
+    dht: Option<Arc<dht::Dht>>,
+    // finished pieces of every torrent are verified by one pool of threads
+    hash_snd: Sender<p2p::HashJob>,
+}
+
+// Connections counts the peer connections of the whole session
+struct Connections {
+    max: usize,
+    open: Mutex<usize>,
+}
+
+// Blocklist is the session's IP filter, replaced whole when it is reloaded,
+// and how many peers it kept us from
+struct Blocklist {
+    filter: RwLock<ipfilter::IpFilter>,
+    blocked: AtomicU64,
+}
+
+// ConnectionSlot is one open connection; it is given back when dropped
+struct ConnectionSlot {
+    connections: Arc<Connections>,
+}
+
+// Incoming is a connection a peer opened to us for one of our torrents
+struct Incoming {
+    conn: TcpStream,
+    handshake: handshake::Handshake,
+    slot: ConnectionSlot,
+}
+
+enum Source {
+    Torrent(Vec<u8>),
+    Magnet(magnet::Magnet),
+}
+
+struct Shared {
+    info_hash: [u8; 20],
+    // every hash peers may know the torrent by, `info_hash` first
+    swarm_hashes: Vec<[u8; 20]>,
+    options: AddTorrentOptions,
+    status: Mutex<Status>,
+    changed: Condvar,
+    incoming: Sender<Incoming>,
+    limits: ratelimit::Limits,
+}
+
+struct Status {
+    progress: Progress,
+    paused: bool,
+    queued: bool,
+    complete: bool,
+    removed: bool,
+    // the torrent's controller is gone, and with it its announces
+    exited: bool,
+}
+
+impl Default for AddTorrentOptions {
+    fn default() -> Self {
+        AddTorrentOptions {
+            output_dir: PathBuf::from("."),
+            file_selectors: vec![],
+            upload_slots: choker::DEFAULT_UPLOAD_SLOTS,
+            target_peers: peers::DEFAULT_TARGET_PEERS,
+            paused: false,
+            download_limit: None,
+            upload_limit: None,
+        }
+    }
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions {
+            listen_port: 6881,
+            max_connections: 200,
+            max_active_downloads: 3,
+            max_active_seeds: 5,
+            download_limit: None,
+            upload_limit: None,
+            peer_download_limit: None,
+            peer_upload_limit: None,
+            rate_schedule: vec![],
+            ip_filter: ipfilter::new_ip_filter(),
+            proxy: None,
+            bind: vec![],
+            port_mapping: true,
+            dht: true,
+            dht_routers: dht::DEFAULT_ROUTERS.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+}
+
+// a listener on every bind address, or on all IPv4 and all IPv6 addresses if
+// there are none; they share the port the first one got
+fn bind_listeners(options: &SessionOptions) -> io::Result<Vec<net::TcpListener>> {
+    let any = [
+        net::IpAddr::V4(net::Ipv4Addr::UNSPECIFIED),
+        net::IpAddr::V6(net::Ipv6Addr::UNSPECIFIED),
+    ];
+    let addrs = if options.bind.is_empty() {
+        &any[..]
+    } else {
+        &options.bind[..]
+    };
+    let mut port = options.listen_port;
+    let mut listeners = vec![];
+    for ip in addrs {
+        let listener = match bind::listen(net::SocketAddr::new(*ip, port)) {
+            Ok(listener) => listener,
+            // a host without IPv6 still listens on IPv4
+            Err(e) if options.bind.is_empty() && !listeners.is_empty() => {
+                println!("not listening on IPv6: {}", e);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        port = listener.local_addr()?.port();
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+// the session's DHT node, on the UDP side of the listen port if it is free;
+// behind a SOCKS5 proxy, or any proxy we must never go around, it talks
+// through the proxy's UDP relay instead
+fn start_dht(options: &SessionOptions, port: u16, runtime: &Handle) -> io::Result<dht::Dht> {
+    let mut routers = options.dht_routers.clone();
+    let sockets = match &options.proxy {
+        Some(proxy) if proxy.kind == proxy::ProxyKind::Socks5 || proxy.only => {
+            let relay = runtime
+                .block_on(proxy.udp_associate(&options.bind))
+                .map_err(io::Error::other)?;
+            // looking router names up ourselves would give us away
+            if proxy.only {
+                routers.retain(|router| router.parse::<net::SocketAddr>().is_ok());
+            }
+            vec![dht::Socket::Proxied(relay)]
+        }
+        _ => {
+            // a socket for each family we can bind, so that IPv6 nodes
+            // (BEP 32) reach us too
+            let any = [
+                net::IpAddr::V4(net::Ipv4Addr::UNSPECIFIED),
+                net::IpAddr::V6(net::Ipv6Addr::UNSPECIFIED),
+            ];
+            let mut sockets = vec![];
+            let mut failure = None;
+            for ip in any.iter() {
+                match bind::udp(*ip, port, &options.bind)
+                    .or_else(|_| bind::udp(*ip, 0, &options.bind))
+                {
+                    Ok(socket) => sockets.push(dht::Socket::Direct(socket)),
+                    Err(e) => failure = Some(e),
+                }
+            }
+            match failure {
+                Some(e) if sockets.is_empty() => return Err(e),
+                _ => sockets,
+            }
+        }
+    };
+    dht::start_dht(sockets, routers)
+}
+
+// starts a session listening on `options.listen_port`; port 0 picks any free
+// port
+pub fn new_session(options: SessionOptions) -> Result<Session, SessionError> {
+    let runtime = runtime::Builder::new()
+        .threaded_scheduler()
+        .enable_all()
+        .build()
+        .map_err(SessionError::RuntimeFailure)?;
+
+    let listeners = bind_listeners(&options).map_err(SessionError::ListenFailure)?;
+    let port = listeners[0]
+        .local_addr()
+        .map_err(SessionError::ListenFailure)?
+        .port();
+    let listeners = listeners
+        .into_iter()
+        .map(|listener| runtime.enter(|| TcpListener::from_std(listener)))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(SessionError::ListenFailure)?;
+
+    let limits = ratelimit::new_limits(options.download_limit, options.upload_limit);
+    let buckets = (
+        ratelimit::new_bucket(limits.download.clone()),
+        ratelimit::new_bucket(limits.upload.clone()),
+    );
+    let dht = if options.dht {
+        match start_dht(&options, port, runtime.handle()) {
+            Ok(dht) => Some(Arc::new(dht)),
+            Err(e) => {
+                println!("not joining the DHT: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let (hash_snd, hash_rcv) = crossbeam::unbounded::<p2p::HashJob>();
+    let hashers = thread::available_parallelism().map_or(1, |n| n.get());
+    for _ in 0..hashers {
+        let hash_rcv = hash_rcv.clone();
+        thread::spawn(move || p2p::start_hash_worker(hash_rcv));
+    }
+    let inner = Arc::new(Inner {
+        base_limits: Mutex::new((options.download_limit, options.upload_limit)),
+        limits,
+        buckets,
+        peer_limits: ratelimit::new_limits(options.peer_download_limit, options.peer_upload_limit),
+        connections: Arc::new(Connections {
+            max: options.max_connections,
+            open: Mutex::new(0),
+        }),
+        blocklist: Arc::new(Blocklist {
+            filter: RwLock::new(options.ip_filter.clone()),
+            blocked: AtomicU64::new(0),
+        }),
+        options,
+        port,
+        external: Arc::new(Mutex::new(None)),
+        key: peer_id::random_u64() as u32,
+        peer_id: peer_id::new_peer_id(),
+        runtime: runtime.handle().clone(),
+        torrents: Mutex::new(vec![]),
+        dht,
+        hash_snd,
+    });
+    apply_schedule(&inner);
+
+    for listener in listeners {
+        runtime.spawn(listen(listener, Arc::downgrade(&inner)));
+    }
+    let weak = Arc::downgrade(&inner);
+    thread::spawn(move || run_queue(weak));
+
+    // nobody may reach us directly when everything goes through a proxy
+    let proxy_only = inner.options.proxy.as_ref().is_some_and(|p| p.only);
+    let port_mapper = if inner.options.port_mapping && !proxy_only {
+        Some(portmap::start_port_mapper(
+            port,
+            inner.options.bind.clone(),
+            Arc::clone(&inner.external),
+        ))
+    } else {
+        None
+    };
+
+    Ok(Session {
+        inner,
+        runtime: Some(runtime),
+        port_mapper,
+    })
+}
+
+impl Session {
+    pub fn listen_port(&self) -> u16 {
+        self.inner.port
+    }
+
+    // the address the router forwards to our listen port, if it does
+    pub fn external_address(&self) -> Option<net::SocketAddr> {
+        *self.inner.external.lock().unwrap()
+    }
+
+    // how many nodes the session's DHT node knows, 0 without the DHT
+    pub fn dht_nodes(&self) -> usize {
+        self.inner.dht.as_ref().map_or(0, |dht| dht.nodes())
+    }
+
+    // the peer id the session shows trackers and peers
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.inner.peer_id
+    }
+
+    // adds a torrent from the contents of a .torrent file
+    pub fn add_torrent(
+        &self,
+        data: &[u8],
+        options: AddTorrentOptions,
+    ) -> Result<TorrentHandle, SessionError> {
+        let bencode_torrent = torrent::parse_torrent(data).map_err(SessionError::InvalidTorrent)?;
+        let t = torrent::new_torrent(&bencode_torrent).map_err(SessionError::InvalidTorrent)?;
+        files::resolve_priorities(&t, &options.file_selectors)
+            .map_err(SessionError::InvalidSelection)?;
+        self.add(
+            t.swarm_hashes(),
+            Some(t.name),
+            Source::Torrent(data.to_vec()),
+            options,
+        )
+    }
+
+    // adds a torrent from a magnet link; its metadata is fetched from peers
+    // before the download starts
+    pub fn add_magnet(
+        &self,
+        uri: &str,
+        options: AddTorrentOptions,
+    ) -> Result<TorrentHandle, SessionError> {
+        let m = magnet::parse_magnet(uri).map_err(SessionError::InvalidMagnet)?;
+        self.add(
+            vec![m.info_hash],
+            m.name.clone(),
+            Source::Magnet(m),
+            options,
+        )
+    }
+
+    fn add(
+        &self,
+        swarm_hashes: Vec<[u8; 20]>,
+        name: Option<String>,
+        source: Source,
+        options: AddTorrentOptions,
+    ) -> Result<TorrentHandle, SessionError> {
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        if torrents
+            .iter()
+            .any(|h| swarm_hashes.iter().any(|hash| h.shared.known_as(hash)))
+        {
+            return Err(SessionError::DuplicateTorrent);
+        }
+
+        let (incoming_snd, incoming_rcv) = crossbeam::unbounded();
+        let paused = options.paused;
+        let limits = ratelimit::new_limits(options.download_limit, options.upload_limit);
+        let (global_download, global_upload) = &self.inner.buckets;
+        let download_buckets = vec![
+            ratelimit::new_bucket(limits.download.clone()),
+            Arc::clone(global_download),
+        ];
+        let upload_buckets = vec![
+            ratelimit::new_bucket(limits.upload.clone()),
+            Arc::clone(global_upload),
+        ];
+        let handle = TorrentHandle {
+            shared: Arc::new(Shared {
+                info_hash: swarm_hashes[0],
+                swarm_hashes,
+                options,
+                status: Mutex::new(Status {
+                    progress: Progress {
+                        state: TorrentState::Queued,
+                        name,
+                        total_pieces: 0,
+                        done_pieces: 0,
+                        total_bytes: 0,
+                        done_bytes: 0,
+                        peers: 0,
+                        uploaded: 0,
+                        error: None,
+                    },
+                    paused,
+                    queued: true,
+                    complete: false,
+                    removed: false,
+                    exited: false,
+                }),
+                changed: Condvar::new(),
+                incoming: incoming_snd,
+                limits,
+            }),
+        };
+
+        let controller = Controller {
+            shared: Arc::clone(&handle.shared),
+            port: self.inner.port,
+            external: Arc::clone(&self.inner.external),
+            key: self.inner.key,
+            peer_id: self.inner.peer_id,
+            runtime: self.inner.runtime.clone(),
+            connections: Arc::clone(&self.inner.connections),
+            blocklist: Arc::clone(&self.inner.blocklist),
+            proxy: self.inner.options.proxy.clone().map(Arc::new),
+            bind: Arc::new(self.inner.options.bind.clone()),
+            incoming: incoming_rcv,
+            download_buckets,
+            upload_buckets,
+            peer_limits: self.inner.peer_limits.clone(),
+            dht: self.inner.dht.clone(),
+            hash_snd: self.inner.hash_snd.clone(),
+            counter: 0,
+        };
+        thread::spawn(move || controller.run(source));
+
+        torrents.push(handle.clone());
+        drop(torrents);
+        rebalance(&self.inner);
+        Ok(handle)
+    }
+
+    // the torrents of the session in queue order
+    pub fn torrents(&self) -> Vec<TorrentHandle> {
+        self.inner.torrents.lock().unwrap().clone()
+    }
+
+    // stops a torrent and forgets about it; downloaded files stay on disk
+    pub fn remove(&self, handle: &TorrentHandle) {
+        self.inner
+            .torrents
+            .lock()
+            .unwrap()
+            .retain(|h| h.shared.info_hash != handle.shared.info_hash);
+        handle.shared.remove();
+        rebalance(&self.inner);
+    }
+
+    // changes the session limits in bytes per second; connections pick them
+    // up right away, and scheduled windows still take precedence
+    pub fn set_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        *self.inner.base_limits.lock().unwrap() = (download, upload);
+        apply_schedule(&self.inner);
+    }
+
+    // changes the limits every single peer connection is held to
+    pub fn set_peer_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.inner.peer_limits.set(download, upload);
+    }
+
+    // replaces the IP filter; peers that are already connected stay
+    pub fn set_ip_filter(&self, filter: ipfilter::IpFilter) {
+        *self.inner.blocklist.filter.write().unwrap() = filter;
+    }
+
+    // how many peers the IP filter turned away so far
+    pub fn blocked_peers(&self) -> u64 {
+        self.inner.blocklist.blocked.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let handles: Vec<TorrentHandle> = self.inner.torrents.lock().unwrap().drain(..).collect();
+        for handle in handles.iter() {
+            handle.shared.remove();
+        }
+        let deadline = Instant::now() + STOP_ANNOUNCE_TIMEOUT;
+        for handle in handles.iter() {
+            handle.shared.wait_for_exit(deadline);
+        }
+        // connections that are still open are dropped, without waiting on
+        // tasks that block
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_timeout(Duration::from_secs(1));
+        }
+        // waits for the router to forget our port
+        drop(self.port_mapper.take());
+    }
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.shared.info_hash
+    }
+
+    // lets the queue start the torrent again
+    pub fn start(&self) {
+        self.set_paused(false);
+    }
+
+    pub fn pause(&self) {
+        self.set_paused(true);
+    }
+
+    // changes the limits of this torrent in bytes per second
+    pub fn set_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.shared.limits.set(download, upload);
+    }
+
+    fn set_paused(&self, paused: bool) {
+        let mut status = self.shared.status.lock().unwrap();
+        status.paused = paused;
+        self.shared.changed.notify_all();
+    }
+
+    pub fn progress(&self) -> Progress {
+        self.shared.status.lock().unwrap().progress.clone()
+    }
+
+    // blocks until the download is complete, failed or was removed
+    pub fn wait(&self) -> Progress {
+        let mut status = self.shared.status.lock().unwrap();
+        loop {
+            match status.progress.state {
+                TorrentState::Seeding
+                | TorrentState::Finished
+                | TorrentState::Failed
+                | TorrentState::Removed => {
+                    return status.progress.clone();
+                }
+                _ => status = self.shared.changed.wait(status).unwrap(),
+            }
+        }
+    }
+}
+
+impl Shared {
+    fn known_as(&self, info_hash: &[u8; 20]) -> bool {
+        self.swarm_hashes.contains(info_hash)
+    }
+
+    fn update<F: FnOnce(&mut Progress)>(&self, f: F) {
+        let mut status = self.status.lock().unwrap();
+        if status.removed {
+            return;
+        }
+        f(&mut status.progress);
+        self.changed.notify_all();
+    }
+
+    // blocks while the torrent is paused or queued; returns false once it is
+    // removed
+    fn wait_until_active(&self) -> bool {
+        let mut status = self.status.lock().unwrap();
+        loop {
+            if status.removed {
+                return false;
+            }
+            if !status.paused && !status.queued {
+                return true;
+            }
+            if status.progress.state != TorrentState::Failed {
+                status.progress.state = if status.paused {
+                    TorrentState::Paused
+                } else if status.complete {
+                    TorrentState::Finished
+                } else {
+                    TorrentState::Queued
+                };
+                self.changed.notify_all();
+            }
+            status = self.changed.wait(status).unwrap();
+        }
+    }
+
+    fn should_stop(&self) -> bool {
+        let status = self.status.lock().unwrap();
+        status.paused || status.queued || status.removed
+    }
+
+    // whether the torrent takes connections from the listener
+    fn accepting(&self) -> bool {
+        let status = self.status.lock().unwrap();
+        match status.progress.state {
+            TorrentState::Downloading | TorrentState::Seeding => !status.removed,
+            _ => false,
+        }
+    }
+
+    fn remove(&self) {
+        let mut status = self.status.lock().unwrap();
+        status.removed = true;
+        status.progress.state = TorrentState::Removed;
+        self.changed.notify_all();
+    }
+
+    // waits until the controller is gone, or until `deadline`
+    fn wait_for_exit(&self, deadline: Instant) {
+        let mut status = self.status.lock().unwrap();
+        while !status.exited {
+            let now = Instant::now();
+            if now >= deadline {
+                return;
+            }
+            status = self.changed.wait_timeout(status, deadline - now).unwrap().0;
+        }
+    }
+
+    fn fail(&self, error: TorrentError) {
+        println!("{}: failed: {}", hex(&self.info_hash), error);
+        self.update(|p| {
+            p.state = TorrentState::Failed;
+            p.error = Some(Arc::new(error));
+        });
+    }
+}
+
+impl Blocklist {
+    // whether the filter blocks `ip`, counting it if so
+    fn blocks(&self, ip: &net::IpAddr) -> bool {
+        let blocked = self.filter.read().unwrap().is_blocked(ip);
+        if blocked {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+        }
+        blocked
+    }
+
+    // the peers of a DHT search or a PEX message that aren't blocked;
+    // trackers filter theirs as they parse the response
+    fn retain(&self, mut peers: Vec<p2p::Peer>) -> Vec<p2p::Peer> {
+        peers.retain(|p| !self.blocks(&p.ip));
+        peers
+    }
+}
+
+fn try_acquire(connections: &Arc<Connections>) -> Option<ConnectionSlot> {
+    let mut open = connections.open.lock().unwrap();
+    if *open >= connections.max {
+        return None;
+    }
+    *open += 1;
+    Some(ConnectionSlot {
+        connections: Arc::clone(connections),
+    })
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        *self.connections.open.lock().unwrap() -= 1;
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// accepts peer connections for every torrent of the session
+async fn listen(mut listener: TcpListener, inner: Weak<Inner>) {
+    loop {
+        let stream = listener.accept().await;
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        if let Ok((stream, _)) = stream {
+            tokio::spawn(async move { accept_peer(&inner, stream).await });
+        }
+    }
+}
+
+// reads the handshake of a new connection and hands it to the torrent it is
+// for
+async fn accept_peer(inner: &Inner, mut stream: TcpStream) {
+    // behind a proxy nobody should be able to reach us directly
+    if inner.options.proxy.as_ref().is_some_and(|p| p.only) {
+        return;
+    }
+    match stream.peer_addr() {
+        Ok(addr) if !inner.blocklist.blocks(&addr.ip()) => {}
+        _ => return,
+    }
+    let mut data = [0u8; handshake::HANDSHAKE_LENGTH];
+    match timeout(Duration::from_secs(5), stream.read_exact(&mut data)).await {
+        Ok(Ok(_)) => {}
+        _ => return,
+    }
+    let handshake = match handshake::read_handshake(&data) {
+        Ok(handshake) => handshake,
+        Err(_) => return,
+    };
+
+    let handle = inner
+        .torrents
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|h| h.shared.known_as(&handshake.info_hash))
+        .cloned();
+    let handle = match handle {
+        Some(handle) if handle.shared.accepting() => handle,
+        _ => return,
+    };
+    if let Some(slot) = try_acquire(&inner.connections) {
+        let incoming = Incoming {
+            conn: stream,
+            handshake,
+            slot,
+        };
+        let _ = handle.shared.incoming.send(incoming);
+    }
+}
+
+fn run_queue(inner: Weak<Inner>) {
+    loop {
+        thread::sleep(QUEUE_INTERVAL);
+        match inner.upgrade() {
+            Some(inner) => {
+                rebalance(&inner);
+                apply_schedule(&inner);
+            }
+            None => return,
+        }
+    }
+}
+
+// sets the session limits of the current time of day
+fn apply_schedule(inner: &Inner) {
+    let base = *inner.base_limits.lock().unwrap();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let minute = (now.as_secs() / 60 % (24 * 60)) as u32;
+    let (download, upload) =
+        ratelimit::scheduled_limits(&inner.options.rate_schedule, base, minute);
+    inner.limits.set(download, upload);
+}
+
+// decides which torrents may run; `torrents` holds whether each one is paused
+// and whether its download is complete, in queue order
+fn queue_slots(torrents: &[(bool, bool)], max_downloads: usize, max_seeds: usize) -> Vec<bool> {
+    let (mut downloads, mut seeds) = (0, 0);
+    torrents
+        .iter()
+        .map(|(paused, complete)| {
+            if *paused {
+                return false;
+            }
+            let (active, max) = if *complete {
+                (&mut seeds, max_seeds)
+            } else {
+                (&mut downloads, max_downloads)
+            };
+            if *active < max {
+                *active += 1;
+                true
+            } else {
+                false
+            }
+        })
+        .collect()
+}
+
+fn rebalance(inner: &Inner) {
+    let torrents = inner.torrents.lock().unwrap();
+    let states: Vec<(bool, bool)> = torrents
+        .iter()
+        .map(|h| {
+            let status = h.shared.status.lock().unwrap();
+            (status.paused, status.complete)
+        })
+        .collect();
+    let slots = queue_slots(
+        &states,
+        inner.options.max_active_downloads,
+        inner.options.max_active_seeds,
+    );
+    for (h, active) in torrents.iter().zip(slots) {
+        let mut status = h.shared.status.lock().unwrap();
+        if status.queued == active {
+            status.queued = !active;
+            h.shared.changed.notify_all();
+        }
+    }
+}
+
+// Controller runs one torrent: it waits for the queue, downloads the wanted
+// pieces and then seeds them until the queue stops it
+struct Controller {
+    shared: Arc<Shared>,
+    port: u16,
+    external: Arc<Mutex<Option<net::SocketAddr>>>,
+    key: u32,
+    peer_id: [u8; 20],
+    runtime: Handle,
+    connections: Arc<Connections>,
+    blocklist: Arc<Blocklist>,
+    proxy: Option<Arc<proxy::Proxy>>,
+    bind: Arc<Vec<net::IpAddr>>,
+    incoming: Receiver<Incoming>,
+    download_buckets: Vec<ratelimit::SharedBucket>,
+    upload_buckets: Vec<ratelimit::SharedBucket>,
+    peer_limits: ratelimit::Limits,
+    dht: Option<Arc<dht::Dht>>,
+    hash_snd: Sender<p2p::HashJob>,
+    counter: i32,
+}
+
+// Swarm state that lives as long as the torrent runs, across pauses
+struct Running {
+    torrent: torrent::Torrent,
+    wanted: Vec<usize>,
+    file_priorities: Vec<files::FilePriority>,
+    resume_path: PathBuf,
+    have: Arc<Mutex<bitfield::Bitfield>>,
+    storage: Arc<storage::Storage>,
+    work_snd: Sender<p2p::PieceWork>,
+    work_rcv: Receiver<p2p::PieceWork>,
+    result_rcv: Receiver<p2p::PieceResult>,
+    hasher: p2p::Hasher,
+    choker_peers: choker::Peers,
+    seeding: Arc<AtomicBool>,
+    uploaded: Arc<AtomicI64>,
+    // payload bytes of the pieces we got and verified
+    downloaded: AtomicI64,
+    metadata: Arc<Vec<u8>>,
+    piece_layers: Arc<merkle::PieceLayers>,
+    web_seeds: Vec<webseed::WebSeed>,
+    peers: Arc<Mutex<peers::PeerManager>>,
+    smart_ban: Arc<Mutex<smartban::SmartBan>>,
+    // whether a DHT search of the torrent is running
+    searching: Arc<AtomicBool>,
+    // the torrent's tracker tiers, with the tracker that last answered moved
+    // to the front of its tier
+    trackers: Mutex<Vec<Vec<String>>>,
+    // the trackers, and the swarm hashes, that were told we started and
+    // haven't been told we stopped
+    started: Mutex<HashSet<(String, [u8; 20])>>,
+}
+
+enum Event {
+    Result(p2p::PieceResult),
+    Incoming(Incoming),
+    Tick,
+}
+
+impl Drop for Controller {
+    fn drop(&mut self) {
+        let mut status = self.shared.status.lock().unwrap();
+        status.exited = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Controller {
+    fn run(mut self, source: Source) {
+        if !self.shared.wait_until_active() {
+            return;
+        }
+        let data = match source {
+            Source::Torrent(data) => data,
+            Source::Magnet(m) => match self.resolve_magnet(&m) {
+                Some(data) => data,
+                None => return,
+            },
+        };
+        let mut running = match self.prepare(&data) {
+            Some(running) => running,
+            None => return,
+        };
+
+        // the choker hands out upload slots to the connected peers
+        let stopped = Arc::new(AtomicBool::new(false));
+        {
+            let (choker_peers, seeding, stopped) = (
+                Arc::clone(&running.choker_peers),
+                Arc::clone(&running.seeding),
+                Arc::clone(&stopped),
+            );
+            let upload_slots = self.shared.options.upload_slots;
+            thread::spawn(move || {
+                choker::start_choker(choker_peers, upload_slots, seeding, stopped)
+            });
+        }
+
+        // set once this run downloaded the last piece, for the trackers
+        let mut finished = None;
+        while self.shared.wait_until_active() {
+            let (stop, stop_rcv) = watch::channel(false);
+            // private torrents only take peers from their trackers (BEP 27)
+            let pex = if running.torrent.private {
+                None
+            } else {
+                let (peers, blocklist) = (Arc::clone(&running.peers), Arc::clone(&self.blocklist));
+                let found: p2p::PexSink = Arc::new(move |info_hash, found| {
+                    let found = blocklist.retain(found);
+                    let source = peers::PeerSource::Pex;
+                    peers
+                        .lock()
+                        .unwrap()
+                        .add(info_hash, found, source, Instant::now());
+                });
+                Some(p2p::Pex {
+                    connected: Arc::new(Mutex::new(HashSet::new())),
+                    found,
+                })
+            };
+            let swarm = p2p::Swarm {
+                info_hash: running.torrent.info_hash,
+                peer_id: self.peer_id,
+                work_snd: running.work_snd.clone(),
+                work_rcv: running.work_rcv.clone(),
+                hasher: running.hasher.clone(),
+                peers: Arc::clone(&running.choker_peers),
+                stop: stop_rcv,
+                have: Arc::clone(&running.have),
+                storage: Arc::clone(&running.storage),
+                download_buckets: self.download_buckets.clone(),
+                upload_buckets: self.upload_buckets.clone(),
+                peer_limits: self.peer_limits.clone(),
+                uploaded: Arc::clone(&running.uploaded),
+                metadata: Arc::clone(&running.metadata),
+                piece_layers: Arc::clone(&running.piece_layers),
+                smart_ban: Arc::clone(&running.smart_ban),
+                proxy: self.proxy.clone(),
+                bind: Arc::clone(&self.bind),
+                pex,
+            };
+            let complete = self.shared.status.lock().unwrap().complete;
+            let ok = if complete {
+                self.seed(&running, &swarm, finished.take());
+                true
+            } else {
+                let ok = self.download(&mut running, &swarm);
+                if ok && self.shared.status.lock().unwrap().complete {
+                    finished = Some(torrent::AnnounceEvent::Completed);
+                }
+                ok
+            };
+            let _ = stop.broadcast(true);
+            if !ok || self.shared.should_stop() {
+                self.announce_stopped(&running);
+            }
+            if !ok {
+                break;
+            }
+        }
+        stopped.store(true, Ordering::Relaxed);
+    }
+
+    // fetches the metadata of a magnet link and turns it into the bytes of a
+    // .torrent file
+    fn resolve_magnet(&self, m: &magnet::Magnet) -> Option<Vec<u8>> {
+        let shared = &self.shared;
+        let proxy = self.proxy.as_deref();
+        shared.update(|p| p.state = TorrentState::FetchingMetadata);
+
+        let blocked = |ip: &net::IpAddr| self.blocklist.blocks(ip);
+        let mut peers = vec![];
+        for tr in m.trackers.iter() {
+            // the size is unknown yet; anything but zero tells the tracker we are
+            // not a seed
+            let report = torrent::Announce {
+                port: self.announce_port(),
+                uploaded: 0,
+                downloaded: 0,
+                left: 1,
+                event: None,
+            };
+            let url = match torrent::build_tracker_url(
+                tr,
+                &m.info_hash,
+                &self.peer_id,
+                self.key,
+                &report,
+            ) {
+                Ok(url) => url,
+                Err(_) => continue,
+            };
+            match self
+                .runtime
+                .block_on(tracker::announce(&url, proxy, &self.bind, &blocked))
+            {
+                Ok(mut p) => peers.append(&mut p),
+                Err(e) => println!("{}: tracker error: {}", tr, e),
+            }
+        }
+
+        if let Some(dht) = &self.dht {
+            let found = dht.get_peers(m.info_hash, None);
+            peers.append(&mut self.blocklist.retain(found));
+        }
+
+        let fetched = metadata::fetch_metadata(peers, m.info_hash, self.peer_id, proxy, &self.bind);
+        let info = match self.runtime.block_on(fetched) {
+            Ok(info) => info,
+            Err(e) => {
+                shared.fail(TorrentError::MetadataFailure(e));
+                return None;
+            }
+        };
+
+        let announce = match m.trackers.first() {
+            Some(announce) => announce.as_bytes(),
+            None => {
+                shared.fail(TorrentError::NoTrackers);
+                return None;
+            }
+        };
+        let mut data = format!("d8:announce{}:", announce.len()).into_bytes();
+        data.extend_from_slice(announce);
+        // every tracker of the link in a tier of its own, tried in order
+        if m.trackers.len() > 1 {
+            data.extend_from_slice(b"13:announce-listl");
+            for tr in m.trackers.iter() {
+                data.extend_from_slice(format!("l{}:{}e", tr.len(), tr).as_bytes());
+            }
+            data.push(b'e');
+        }
+        data.extend_from_slice(b"4:info");
+        data.extend_from_slice(&info);
+        data.push(b'e');
+        Some(data)
+    }
+
+    // parses the torrent and picks up where a previous run left off
+    fn prepare(&self, data: &[u8]) -> Option<Running> {
+        let shared = &self.shared;
+        let our_torrent = match torrent::parse_torrent(data).and_then(|b| torrent::new_torrent(&b))
+        {
+            Ok(t) => t,
+            Err(e) => {
+                shared.fail(TorrentError::InvalidTorrent(e));
+                return None;
+            }
+        };
+
+        // explicit selectors win over the priorities stored in the resume
+        // state
+        let options = &shared.options;
+        let resume_path = options
+            .output_dir
+            .join(format!("{}.resume", our_torrent.name));
+        let resume_state = resume::load(&resume_path, &our_torrent).ok();
+        let file_priorities = match (&resume_state, options.file_selectors.is_empty()) {
+            (Some(state), true) => state.file_priorities(),
+            _ => match files::resolve_priorities(&our_torrent, &options.file_selectors) {
+                Ok(p) => p,
+                Err(e) => {
+                    shared.fail(TorrentError::InvalidSelection(e));
+                    return None;
+                }
+            },
+        };
+        let have = match &resume_state {
+            Some(state) => state.have(&our_torrent, &file_priorities),
+            None => bitfield::new_bitfield(our_torrent.piece_hashes.len() as i64),
+        };
+        let piece_priorities = files::piece_priorities(&our_torrent, &file_priorities);
+
+        // the pieces we want, highest priority first
+        let mut wanted: Vec<usize> = (0..our_torrent.piece_hashes.len())
+            .filter(|i| piece_priorities[*i] != files::FilePriority::Skip)
+            .collect();
+        wanted.sort_by(|a, b| piece_priorities[*b].cmp(&piece_priorities[*a]));
+
+        let (work_snd, work_rcv) = crossbeam::unbounded();
+        let (result_snd, result_rcv) = crossbeam::unbounded::<p2p::PieceResult>();
+
+        // finished pieces are verified by the session's hash workers, which
+        // look for the senders of pieces that fail
+        let smart_ban = Arc::new(Mutex::new(smartban::new_smart_ban()));
+        let hasher = p2p::Hasher {
+            jobs: self.hash_snd.clone(),
+            torrent: Arc::new(p2p::Verdicts {
+                work_snd: work_snd.clone(),
+                result_snd,
+                smart_ban: Arc::clone(&smart_ban),
+            }),
+        };
+
+        let storage = storage::new(
+            options.output_dir.clone(),
+            &our_torrent,
+            file_priorities.clone(),
+        );
+        let running = Running {
+            metadata: Arc::new(our_torrent.info_bytes.clone()),
+            piece_layers: Arc::new(our_torrent.piece_layers.clone()),
+            web_seeds: webseed::new_web_seeds(&our_torrent),
+            peers: Arc::new(Mutex::new(peers::new_peer_manager())),
+            smart_ban,
+            searching: Arc::new(AtomicBool::new(false)),
+            trackers: Mutex::new(our_torrent.trackers.clone()),
+            started: Mutex::new(HashSet::new()),
+            torrent: our_torrent,
+            wanted,
+            file_priorities,
+            resume_path,
+            have: Arc::new(Mutex::new(have)),
+            storage: Arc::new(storage),
+            work_snd,
+            work_rcv,
+            result_rcv,
+            hasher,
+            choker_peers: Arc::new(Mutex::new(HashMap::new())),
+            seeding: Arc::new(AtomicBool::new(false)),
+            uploaded: Arc::new(AtomicI64::new(0)),
+            downloaded: AtomicI64::new(0),
+        };
+
+        let (done_pieces, done_bytes) = running.done();
+        let total_bytes: i64 = running
+            .wanted
+            .iter()
+            .map(|i| running.torrent.calculate_piece_size(*i as i64))
+            .sum();
+        let mut status = shared.status.lock().unwrap();
+        status.complete = done_pieces == running.wanted.len();
+        status.progress.name = Some(running.torrent.name.clone());
+        status.progress.total_pieces = running.wanted.len();
+        status.progress.done_pieces = done_pieces;
+        status.progress.total_bytes = total_bytes;
+        status.progress.done_bytes = done_bytes;
+        shared.changed.notify_all();
+        Some(running)
+    }
+
+    // waits up to a second for a verified piece or a new connection
+    fn next_event(&self, running: &Running) -> Event {
+        let mut sel = Select::new();
+        let results = sel.recv(&running.result_rcv);
+        sel.recv(&self.incoming);
+        match sel.select_timeout(Duration::from_secs(1)) {
+            Ok(op) if op.index() == results => match op.recv(&running.result_rcv) {
+                Ok(res) => Event::Result(res),
+                Err(_) => Event::Tick,
+            },
+            Ok(op) => match op.recv(&self.incoming) {
+                Ok(incoming) => Event::Incoming(incoming),
+                Err(_) => Event::Tick,
+            },
+            Err(_) => Event::Tick,
+        }
+    }
+
+    // the port trackers hand out to peers: the one the router forwards to us,
+    // or our listen port
+    fn announce_port(&self) -> u16 {
+        match *self.external.lock().unwrap() {
+            Some(external) => external.port(),
+            None => self.port,
+        }
+    }
+
+    // what the trackers are told about the torrent
+    fn report(
+        &self,
+        running: &Running,
+        left: i64,
+        event: Option<torrent::AnnounceEvent>,
+    ) -> torrent::Announce {
+        torrent::Announce {
+            port: self.announce_port(),
+            uploaded: running.uploaded.load(Ordering::Relaxed),
+            downloaded: running.downloaded.load(Ordering::Relaxed),
+            left,
+            event,
+        }
+    }
+
+    // asks the torrent's trackers for peers, which are also all a private
+    // torrent may use: tiers are tried in order and the trackers of a tier
+    // one by one until one answers, which then goes first in its tier (BEP 12);
+    // a tracker that hasn't heard from us yet is told we started instead
+    // of `event`
+    fn announce(
+        &self,
+        running: &Running,
+        info_hash: &[u8; 20],
+        left: i64,
+        event: Option<torrent::AnnounceEvent>,
+    ) -> Vec<p2p::Peer> {
+        let proxy = self.proxy.as_deref();
+        let blocked = |ip: &net::IpAddr| self.blocklist.blocks(ip);
+        let mut tiers = running.trackers.lock().unwrap();
+        for tier in tiers.iter_mut() {
+            for i in 0..tier.len() {
+                let key = (tier[i].clone(), *info_hash);
+                let event = if running.started.lock().unwrap().contains(&key) {
+                    event
+                } else {
+                    Some(torrent::AnnounceEvent::Started)
+                };
+                let report = self.report(running, left, event);
+                let answer = torrent::build_tracker_url(
+                    &tier[i],
+                    info_hash,
+                    &self.peer_id,
+                    self.key,
+                    &report,
+                )
+                .map_err(torrent::TrackerError::InvalidUrl)
+                .and_then(|url| {
+                    self.runtime
+                        .block_on(tracker::announce(&url, proxy, &self.bind, &blocked))
+                });
+                match answer {
+                    Ok(peers) => {
+                        running.started.lock().unwrap().insert(key);
+                        let tracker = tier.remove(i);
+                        tier.insert(0, tracker);
+                        return peers;
+                    }
+                    Err(e) => println!("{}: tracker error: {}", tier[i], e),
+                }
+            }
+        }
+        vec![]
+    }
+
+    // tells the trackers that were told we started that we are gone; the
+    // peers they answer with are of no use anymore
+    fn announce_stopped(&self, running: &Running) {
+        let proxy = self.proxy.as_deref();
+        let left = running.torrent.length - running.done().1;
+        let report = self.report(running, left, Some(torrent::AnnounceEvent::Stopped));
+        let started: Vec<(String, [u8; 20])> = running.started.lock().unwrap().drain().collect();
+        for (tracker, info_hash) in started {
+            let answer =
+                torrent::build_tracker_url(&tracker, &info_hash, &self.peer_id, self.key, &report)
+                    .map_err(torrent::TrackerError::InvalidUrl)
+                    .and_then(|url| {
+                        self.runtime
+                            .block_on(tracker::announce(&url, proxy, &self.bind, &|_| true))
+                    });
+            if let Err(e) = answer {
+                println!("{}: tracker error: {}", tracker, e);
+            }
+        }
+    }
+
+    // looks the torrent up in the DHT in the background and hands the peers
+    // it finds to the manager; with `announce` the DHT learns about us too
+    fn search_dht(&self, running: &Running, announce: bool) {
+        let dht = match &self.dht {
+            Some(dht) if !running.torrent.private => Arc::clone(dht),
+            _ => return,
+        };
+        if running.searching.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let (peers, searching, blocklist) = (
+            Arc::clone(&running.peers),
+            Arc::clone(&running.searching),
+            Arc::clone(&self.blocklist),
+        );
+        let swarm_hashes = running.torrent.swarm_hashes();
+        let port = Some(self.announce_port()).filter(|_| announce);
+        thread::spawn(move || {
+            for info_hash in swarm_hashes {
+                let found = blocklist.retain(dht.get_peers(info_hash, port));
+                peers
+                    .lock()
+                    .unwrap()
+                    .add(info_hash, found, peers::PeerSource::Dht, Instant::now());
+            }
+            searching.store(false, Ordering::Relaxed);
+        });
+    }
+
+    // connects to the peers the manager picks until the torrent has its
+    // target of connections or the session runs out of them
+    fn spawn_outgoing(
+        &mut self,
+        peers: &Arc<Mutex<peers::PeerManager>>,
+        swarm: &p2p::Swarm,
+        workers: &Arc<Mutex<usize>>,
+    ) {
+        while *workers.lock().unwrap() < self.shared.options.target_peers {
+            let slot = match try_acquire(&self.connections) {
+                Some(slot) => slot,
+                None => break,
+            };
+            let (info_hash, p) = match peers.lock().unwrap().next_peer(Instant::now()) {
+                Some(next) => next,
+                None => break,
+            };
+            let addr = net::SocketAddr::new(p.ip, p.port);
+            if swarm.smart_ban.lock().unwrap().is_banned(&p.ip) || self.blocklist.blocks(&p.ip) {
+                peers.lock().unwrap().forget(addr);
+                continue;
+            }
+            let swarm = p2p::Swarm {
+                info_hash,
+                ..swarm.clone()
+            };
+            let (peers, workers, counter) = (Arc::clone(peers), Arc::clone(workers), self.counter);
+            *workers.lock().unwrap() += 1;
+            let identified = {
+                let peers = Arc::clone(&peers);
+                move |id| peers.lock().unwrap().identified(addr, id)
+            };
+            self.runtime.spawn(async move {
+                let established = p2p::start_download_worker(p, swarm, counter, identified).await;
+                peers
+                    .lock()
+                    .unwrap()
+                    .disconnected(addr, established, Instant::now());
+                *workers.lock().unwrap() -= 1;
+                drop(slot);
+            });
+            self.counter += 1;
+        }
+    }
+
+    fn spawn_incoming(
+        &mut self,
+        incoming: Incoming,
+        swarm: &p2p::Swarm,
+        workers: &Arc<Mutex<usize>>,
+    ) {
+        if let Ok(addr) = incoming.conn.peer_addr() {
+            if swarm.smart_ban.lock().unwrap().is_banned(&addr.ip()) {
+                println!("{}: refusing banned peer", addr);
+                return;
+            }
+        }
+        let (swarm, workers, counter) = (swarm.clone(), Arc::clone(workers), self.counter);
+        *workers.lock().unwrap() += 1;
+        self.runtime.spawn(async move {
+            let Incoming {
+                conn,
+                handshake,
+                slot,
+            } = incoming;
+            p2p::start_incoming_worker(conn, handshake, swarm, counter).await;
+            *workers.lock().unwrap() -= 1;
+            drop(slot);
+        });
+        self.counter += 1;
+    }
+
+    // downloads until every wanted piece is there or the torrent is stopped;
+    // returns false if the torrent failed
+    fn download(&mut self, running: &mut Running, swarm: &p2p::Swarm) -> bool {
+        let shared = Arc::clone(&self.shared);
+        shared.update(|p| p.state = TorrentState::Downloading);
+
+        // queue everything we still need; pieces that are already queued or
+        // come back from old workers turn up twice, which is harmless
+        while running.work_rcv.try_recv().is_ok() {}
+        let missing: Vec<p2p::PieceWork> = {
+            let have = running.have.lock().unwrap();
+            running
+                .wanted
+                .iter()
+                .filter(|i| !have.has_piece(**i as i64))
+                .map(|index| p2p::PieceWork {
+                    index: *index as i64,
+                    hash: running.torrent.piece_hashes[*index],
+                    length: running.torrent.calculate_piece_size(*index as i64),
+                })
+                .collect()
+        };
+        for piece_work in missing {
+            running.work_snd.send(piece_work).unwrap();
+        }
+
+        // web seeds have every piece and stay until the download is over,
+        // unless they keep sending pieces that fail their hash
+        for seed in running.web_seeds.iter().cloned() {
+            let swarm = swarm.clone();
+            self.runtime
+                .spawn(webseed::start_web_seed_worker(seed, swarm, self.counter));
+            self.counter += 1;
+        }
+
+        let workers = Arc::new(Mutex::new(0));
+        let mut last_announce = None;
+        let mut last_search = None;
+        let (mut done_pieces, _) = running.done();
+        while done_pieces < running.wanted.len() {
+            if shared.should_stop() {
+                return true;
+            }
+
+            // ask the tracker for more peers once we ran out of peers to
+            // try, soon when none is connected
+            let open = *workers.lock().unwrap();
+            let delay = if open == 0 {
+                REANNOUNCE_DELAY
+            } else {
+                TOP_UP_DELAY
+            };
+            let short = open < shared.options.target_peers
+                && running.peers.lock().unwrap().ready(Instant::now()) == 0;
+            if short && last_announce.is_none_or(|t: Instant| t.elapsed() >= delay) {
+                let left = running.torrent.length - running.done().1;
+                for info_hash in running.torrent.swarm_hashes() {
+                    let found = self.announce(running, &info_hash, left, None);
+                    running.peers.lock().unwrap().add(
+                        info_hash,
+                        found,
+                        peers::PeerSource::Tracker,
+                        Instant::now(),
+                    );
+                }
+                last_announce = Some(Instant::now());
+            }
+            if short && last_search.is_none_or(|t: Instant| t.elapsed() >= DHT_SEARCH_DELAY) {
+                self.search_dht(running, true);
+                last_search = Some(Instant::now());
+            }
+            self.spawn_outgoing(&running.peers, swarm, &workers);
+
+            // write results to disk as they arrive
+            let res = match self.next_event(running) {
+                Event::Result(res) => res,
+                Event::Incoming(incoming) => {
+                    self.spawn_incoming(incoming, swarm, &workers);
+                    continue;
+                }
+                Event::Tick => {
+                    running.report_peers(&shared);
+                    continue;
+                }
+            };
+            if running.have.lock().unwrap().has_piece(res.index) {
+                continue;
+            }
+
+            if let Err(why) = running.storage.write_piece(res.index, &res.buf) {
+                shared.fail(TorrentError::WriteFailure(res.index, why));
+                return false;
+            }
+            running
+                .downloaded
+                .fetch_add(res.buf.len() as i64, Ordering::Relaxed);
+            let state = {
+                let mut have = running.have.lock().unwrap();
+                have.set_piece(res.index);
+                resume::new_resume_state(&running.torrent, &running.file_priorities, &have)
+            };
+            if let Err(e) = state.save(&running.resume_path) {
+                println!("couldn't save resume state: {}", e);
+            }
+
+            let (done, done_bytes) = running.done();
+            done_pieces = done;
+            let connected = running.choker_peers.lock().unwrap().len();
+            shared.update(|p| {
+                p.done_pieces = done_pieces;
+                p.done_bytes = done_bytes;
+                p.peers = connected;
+            });
+        }
+
+        self.shared.status.lock().unwrap().complete = true;
+        true
+    }
+
+    // serves the peers that connect to us until the torrent is stopped;
+    // `event` is Completed when we just finished the download
+    fn seed(
+        &mut self,
+        running: &Running,
+        swarm: &p2p::Swarm,
+        event: Option<torrent::AnnounceEvent>,
+    ) {
+        let shared = Arc::clone(&self.shared);
+        shared.update(|p| p.state = TorrentState::Seeding);
+        running.seeding.store(true, Ordering::Relaxed);
+
+        // let the tracker and the DHT know we have everything; peers find us
+        // through them
+        for info_hash in running.torrent.swarm_hashes() {
+            self.announce(running, &info_hash, 0, event);
+        }
+        self.search_dht(running, true);
+        let mut last_search = Instant::now();
+
+        let workers = Arc::new(Mutex::new(0));
+        while !shared.should_stop() {
+            match self.next_event(running) {
+                Event::Incoming(incoming) => self.spawn_incoming(incoming, swarm, &workers),
+                Event::Result(_) => {}
+                Event::Tick => running.report_peers(&shared),
+            }
+            if last_search.elapsed() >= DHT_ANNOUNCE_INTERVAL {
+                self.search_dht(running, true);
+                last_search = Instant::now();
+            }
+        }
+        running.seeding.store(false, Ordering::Relaxed);
+    }
+}
+
+impl Running {
+    fn report_peers(&self, shared: &Shared) {
+        let connected = self.choker_peers.lock().unwrap().len();
+        let uploaded = self.uploaded.load(Ordering::Relaxed);
+        shared.update(|p| {
+            p.peers = connected;
+            p.uploaded = uploaded;
+        });
+    }
+
+    // counts the wanted pieces we have and their bytes
+    fn done(&self) -> (usize, i64) {
+        let have = self.have.lock().unwrap();
+        let done: Vec<&usize> = self
+            .wanted
+            .iter()
+            .filter(|i| have.has_piece(**i as i64))
+            .collect();
+        let bytes = done
+            .iter()
+            .map(|i| self.torrent.calculate_piece_size(**i as i64))
+            .sum();
+        (done.len(), bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::handshake;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    // a one piece torrent whose tracker refuses connections, so it downloads
+    // without ever finding peers
+    fn torrent_data(name: &str) -> Vec<u8> {
+        let mut data = format!(
+            "d8:announce27:http://127.0.0.1:1/announce4:infod6:lengthi5e4:name{}:{}12:piece lengthi16384e6:pieces20:",
+            name.len(),
+            name
+        )
+        .into_bytes();
+        data.extend_from_slice(&[7u8; 20]);
+        data.extend_from_slice(b"ee");
+        data
+    }
+
+    fn loopback_session(max_connections: usize, max_active_downloads: usize) -> super::Session {
+        super::new_session(super::SessionOptions {
+            listen_port: 0,
+            max_connections,
+            max_active_downloads,
+            bind: vec![net::IpAddr::V4(net::Ipv4Addr::LOCALHOST)],
+            port_mapping: false,
+            dht: false,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    // an empty directory of its own for each test to download into
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("herb-session-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn add(session: &super::Session, dir: &Path, name: &str) -> super::TorrentHandle {
+        let options = super::AddTorrentOptions {
+            output_dir: dir.to_path_buf(),
+            ..Default::default()
+        };
+        session.add_torrent(&torrent_data(name), options).unwrap()
+    }
+
+    fn wait_for(handle: &super::TorrentHandle, state: super::TorrentState) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while handle.progress().state != state {
+            assert!(Instant::now() < deadline, "{:?}", handle.progress().state);
+            std::thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    // opens a connection for `info_hash` and returns the info hash of the
+    // handshake we get back, if any
+    fn connect(
+        session: &super::Session,
+        info_hash: [u8; 20],
+    ) -> (net::TcpStream, Option<[u8; 20]>) {
+        let addr = net::SocketAddr::from(([127, 0, 0, 1], session.listen_port()));
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        let mut request = handshake::new_handshake(info_hash, [9u8; 20]);
+        request.extensions = [0u8; 8];
+        stream.write_all(&request.serialize()).unwrap();
+
+        let mut reply = [0u8; handshake::HANDSHAKE_LENGTH];
+        let answer = match stream.read_exact(&mut reply) {
+            Ok(()) => Some(handshake::read_handshake(&reply).unwrap().info_hash),
+            Err(_) => None,
+        };
+        (stream, answer)
+    }
+
+    #[test]
+    fn queue_slots_limits_downloads_and_seeds() {
+        // (paused, complete) in queue order
+        let torrents = vec![
+            (false, false),
+            (true, false), // paused, takes no slot
+            (false, true),
+            (false, false),
+            (false, false), // third download, waits
+            (false, true),  // second seed, waits
+        ];
+        assert_eq!(
+            super::queue_slots(&torrents, 2, 1),
+            vec![true, false, true, true, false, false]
+        );
+    }
+
+    #[test]
+    fn connection_slots_are_given_back() {
+        let connections = Arc::new(super::Connections {
+            max: 2,
+            open: Mutex::new(0),
+        });
+        let first = super::try_acquire(&connections).unwrap();
+        let _second = super::try_acquire(&connections).unwrap();
+        assert!(super::try_acquire(&connections).is_none());
+
+        drop(first);
+        assert!(super::try_acquire(&connections).is_some());
+    }
+
+    #[test]
+    fn listener_is_shared_by_torrents() {
+        let dir = test_dir("shared");
+        let session = loopback_session(10, 3);
+        let first = add(&session, &dir, "first");
+        let second = add(&session, &dir, "second");
+        wait_for(&first, super::TorrentState::Downloading);
+        wait_for(&second, super::TorrentState::Downloading);
+
+        for handle in [&first, &second].iter() {
+            let (_stream, answer) = connect(&session, handle.info_hash());
+            assert_eq!(answer, Some(handle.info_hash()));
+        }
+        // a torrent the session doesn't have
+        assert_eq!(connect(&session, [1u8; 20]).1, None);
+
+        drop(session);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn connections_beyond_the_limit_are_refused() {
+        let dir = test_dir("limited");
+        let session = loopback_session(1, 3);
+        let handle = add(&session, &dir, "limited");
+        wait_for(&handle, super::TorrentState::Downloading);
+
+        let (open, answer) = connect(&session, handle.info_hash());
+        assert_eq!(answer, Some(handle.info_hash()));
+        assert_eq!(connect(&session, handle.info_hash()).1, None);
+
+        // closing the connection gives its slot back
+        drop(open);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while connect(&session, handle.info_hash()).1.is_none() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        drop(session);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn queued_torrents_start_when_a_slot_frees_up() {
+        let dir = test_dir("queued");
+        let session = loopback_session(10, 1);
+        let first = add(&session, &dir, "running");
+        let second = add(&session, &dir, "waiting");
+        wait_for(&first, super::TorrentState::Downloading);
+        wait_for(&second, super::TorrentState::Queued);
+        // queued torrents take no connections
+        assert_eq!(connect(&session, second.info_hash()).1, None);
+
+        session.remove(&first);
+        wait_for(&second, super::TorrentState::Downloading);
+        assert_eq!(
+            connect(&session, second.info_hash()).1,
+            Some(second.info_hash())
+        );
+
+        drop(session);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_torrents_report_why() {
+        let session = loopback_session(10, 3);
+        let magnet = "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567";
+        let handle = session.add_magnet(magnet, Default::default()).unwrap();
+        let progress = handle.wait();
+        assert_eq!(progress.state, super::TorrentState::Failed);
+        match progress.error.as_deref() {
+            Some(super::TorrentError::MetadataFailure(_)) => {}
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn private_torrents_only_use_their_trackers() {
+        // the router sees the session's DHT traffic
+        let router = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        router
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let session = super::new_session(super::SessionOptions {
+            listen_port: 0,
+            bind: vec![net::IpAddr::V4(net::Ipv4Addr::LOCALHOST)],
+            port_mapping: false,
+            dht: true,
+            dht_routers: vec![router.local_addr().unwrap().to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+
+        // the first tier is down, so the tracker of the second one is asked
+        let tracker = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", tracker.local_addr().unwrap());
+        let mut data = format!(
+            "d8:announce27:http://127.0.0.1:1/announce13:announce-listll27:http://127.0.0.1:1/announceel{}:{}ee4:infod6:lengthi5e4:name7:private12:piece lengthi16384e6:pieces20:",
+            url.len(),
+            url
+        )
+        .into_bytes();
+        data.extend_from_slice(&[7u8; 20]);
+        data.extend_from_slice(b"7:privatei1eee");
+        let dir = test_dir("private");
+        let options = super::AddTorrentOptions {
+            output_dir: dir.clone(),
+            ..Default::default()
+        };
+        let handle = session.add_torrent(&data, options).unwrap();
+
+        let (mut stream, _) = tracker.accept().unwrap();
+        let mut buf = [0u8; 512];
+        let n = stream.read(&mut buf).unwrap();
+        let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+        assert!(request.starts_with("GET /announce?"));
+        assert!(request.contains("&event=started"));
+        stream
+            .write_all(b"HTTP/1.0 200 OK\r\n\r\nd8:intervali60e5:peers0:e")
+            .unwrap();
+        drop(stream);
+        wait_for(&handle, super::TorrentState::Downloading);
+
+        // the DHT is joined, but never asked about the torrent
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let mut packet = [0u8; 1500];
+        while Instant::now() < deadline {
+            if let Ok(n) = router.recv(&mut packet) {
+                let query = &packet[..n];
+                assert!(!query.windows(9).any(|w| w == b"get_peers"));
+            }
+        }
+
+        // the tracker hears that we left
+        session.remove(&handle);
+        tracker.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            assert!(Instant::now() < deadline);
+            let mut stream = match tracker.accept() {
+                Ok((stream, _)) => stream,
+                Err(_) => {
+                    std::thread::sleep(Duration::from_millis(20));
+                    continue;
+                }
+            };
+            stream.set_nonblocking(false).unwrap();
+            let n = stream.read(&mut buf).unwrap();
+            let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+            stream
+                .write_all(b"HTTP/1.0 200 OK\r\n\r\nd8:intervali60e5:peers0:e")
+                .unwrap();
+            if request.contains("&event=stopped") {
+                break;
+            }
+        }
+
+        drop(session);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::pipeline;
+
+type BlockHash = [u8; 20];
+
+// SmartBan finds the peers behind pieces that fail the hash check: the blocks
+// of a failed piece are remembered with who sent them, and once a good copy
+// of the piece arrives every peer whose block differs from it is banned
+pub struct SmartBan {
+    // piece index -> the blocks of failed copies, with the peer that sent
+    // each of them
+    failed: HashMap<i64, Vec<(usize, SocketAddr, BlockHash)>>,
+    // how many failed pieces each address sent data for
+    strikes: HashMap<IpAddr, u32>,
+    banned: HashSet<IpAddr>,
+}
+
+pub fn new_smart_ban() -> SmartBan {
+    SmartBan {
+        failed: HashMap::new(),
+        strikes: HashMap::new(),
+        banned: HashSet::new(),
+    }
+}
+
+fn block_hashes(buf: &[u8]) -> impl Iterator<Item = BlockHash> + '_ {
+    buf.chunks(pipeline::BLOCK_SIZE as usize).map(|block| {
+        let mut hash = [0u8; 20];
+        hash.copy_from_slice(&Sha1::digest(block));
+        hash
+    })
+}
+
+impl SmartBan {
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
+    // remembers the blocks of a piece that failed the hash check; `sources`
+    // has the sender of every block, None for blocks from web seeds
+    pub fn piece_failed(&mut self, index: i64, buf: &[u8], sources: &[Option<SocketAddr>]) {
+        let blocks = self.failed.entry(index).or_default();
+        let mut senders = HashSet::new();
+        for (block, hash) in block_hashes(buf).enumerate() {
+            if let Some(Some(addr)) = sources.get(block) {
+                blocks.push((block, *addr, hash));
+                senders.insert(addr.ip());
+            }
+        }
+        for ip in senders {
+            let strikes = self.strikes.entry(ip).or_default();
+            *strikes += 1;
+            if *strikes > 1 {
+                println!(
+                    "{}: sent data for {} pieces that failed the hash check",
+                    ip, strikes
+                );
+            }
+        }
+    }
+
+    // compares the failed copies of a piece with the good one that just
+    // passed the hash check, and bans the peers whose blocks differ
+    pub fn piece_passed(&mut self, index: i64, buf: &[u8]) {
+        let blocks = match self.failed.remove(&index) {
+            Some(blocks) => blocks,
+            None => return,
+        };
+        let good: Vec<BlockHash> = block_hashes(buf).collect();
+        for (block, addr, hash) in blocks {
+            if good.get(block) == Some(&hash) || !self.banned.insert(addr.ip()) {
+                continue;
+            }
+            println!(
+                "{}: banned for sending a corrupt block #{} of piece #{}",
+                addr, block, index
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::pipeline::BLOCK_SIZE;
+
+    #[test]
+    fn the_peer_with_the_bad_block_is_banned() {
+        let honest: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let liar: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let good = vec![7u8; BLOCK_SIZE as usize * 3];
+        let mut bad = good.clone();
+        bad[BLOCK_SIZE as usize * 2 + 5] = 0;
+
+        let mut ban = super::new_smart_ban();
+        let sources = [Some(honest), None, Some(liar)];
+        ban.piece_failed(4, &bad, &sources);
+        assert!(!ban.is_banned(&liar.ip()));
+
+        ban.piece_passed(4, &good);
+        assert!(ban.is_banned(&liar.ip()));
+        assert!(!ban.is_banned(&honest.ip()));
+        assert!(ban.failed.is_empty());
+    }
+}
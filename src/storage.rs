@@ -0,0 +1,121 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::files::FilePriority;
+use crate::torrent;
+
+// Storage writes verified pieces into the files of the torrent, leaving out
+// the bytes that belong to skipped files, and reads blocks back for upload
+pub struct Storage {
+    root: PathBuf,
+    files: Vec<torrent::TorrentFile>,
+    priorities: Vec<FilePriority>,
+    piece_length: i64,
+    piece_sizes: Vec<i64>,
+}
+
+pub fn new(root: PathBuf, t: &torrent::Torrent, priorities: Vec<FilePriority>) -> Storage {
+    Storage {
+        root,
+        files: t.files.clone(),
+        priorities,
+        piece_length: t.piece_length,
+        piece_sizes: (0..t.piece_hashes.len() as i64)
+            .map(|index| t.calculate_piece_size(index))
+            .collect(),
+    }
+}
+
+impl Storage {
+    pub fn write_piece(&self, index: i64, buf: &[u8]) -> io::Result<()> {
+        let begin = index * self.piece_length;
+        let end = begin + buf.len() as i64;
+
+        for (f, priority) in self.files.iter().zip(self.priorities.iter()) {
+            if *priority == FilePriority::Skip || f.padding {
+                continue;
+            }
+            if f.offset >= end || f.offset + f.length <= begin {
+                continue;
+            }
+
+            // overlap of the piece with the file, in torrent-wide offsets
+            let start = begin.max(f.offset);
+            let stop = end.min(f.offset + f.length);
+
+            let path = self.root.join(&f.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            file.seek(SeekFrom::Start((start - f.offset) as u64))?;
+            file.write_all(&buf[(start - begin) as usize..(stop - begin) as usize])?;
+        }
+        Ok(())
+    }
+
+    pub fn piece_length(&self) -> i64 {
+        self.piece_length
+    }
+
+    // the size of a piece, which is short at the end of the torrent and, in v2
+    // torrents, at the end of every file
+    pub fn piece_size(&self, index: i64) -> Option<i64> {
+        if index < 0 {
+            return None;
+        }
+        self.piece_sizes.get(index as usize).copied()
+    }
+
+    // reads a block of a piece we have; blocks that reach into a skipped file
+    // can't be served since those bytes were never written
+    pub fn read_block(&self, index: i64, begin: i64, length: i64) -> io::Result<Vec<u8>> {
+        let begin = index * self.piece_length + begin;
+        let end = begin + length;
+        let mut buf = vec![0u8; length as usize];
+
+        for (f, priority) in self.files.iter().zip(self.priorities.iter()) {
+            // padding files are zeros, which the buffer starts out as
+            if f.offset >= end || f.offset + f.length <= begin || f.padding {
+                continue;
+            }
+            if *priority == FilePriority::Skip {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "file is skipped"));
+            }
+
+            let start = begin.max(f.offset);
+            let stop = end.min(f.offset + f.length);
+
+            let mut file = OpenOptions::new()
+                .read(true)
+                .open(self.root.join(&f.path))?;
+            file.seek(SeekFrom::Start((start - f.offset) as u64))?;
+            file.read_exact(&mut buf[(start - begin) as usize..(stop - begin) as usize])?;
+        }
+        Ok(buf)
+    }
+}
+
+// checks every piece of the torrent's files under `root` against its hash;
+// pieces of missing or short files are bad
+pub fn verify_pieces(root: &Path, t: &torrent::Torrent) -> Vec<bool> {
+    let storage = new(
+        root.to_path_buf(),
+        t,
+        vec![FilePriority::Normal; t.files.len()],
+    );
+    (0..t.piece_hashes.len())
+        .map(|index| {
+            let length = t.calculate_piece_size(index as i64);
+            match storage.read_block(index as i64, 0, length) {
+                Ok(buf) => t.piece_hashes[index].matches(&buf),
+                Err(_) => false,
+            }
+        })
+        .collect()
+}
@@ -1,184 +1,705 @@
-use serde_bencode::ser;
+use serde_bencode::{de, ser};
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
-use std::net::{IpAddr, Ipv4Addr};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Component, Path, PathBuf};
 use url::{form_urlencoded, ParseError};
 
+use crate::extension;
+use crate::merkle;
 use crate::p2p;
+use crate::peer_id;
+use crate::proxy;
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Node(String, i64);
+pub struct Node(pub String, pub i64);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct File {
-    path: Vec<String>,
-    length: i64,
+    pub path: Vec<String>,
+    pub length: i64,
+    #[serde(default)]
+    pub md5sum: Option<String>,
+    // `p` marks the padding files of hybrid torrents
+    #[serde(default)]
+    pub attr: Option<String>,
+}
+
+// FileTreeNode is a directory of a v2 file tree, or a file, which is a
+// dictionary under an empty key (BEP 52); a file with other keys next to it
+// is read as a directory, and then rejected for its empty name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum FileTreeNode {
+    File {
+        #[serde(rename = "")]
+        file: FileTreeEntry,
+    },
+    Dir(BTreeMap<String, FileTreeNode>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileTreeEntry {
+    pub length: i64,
+    // the root of the file's merkle tree; empty files have none
     #[serde(default)]
-    md5sum: Option<String>,
+    #[serde(rename = "pieces root")]
+    pub pieces_root: Option<ByteBuf>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BencodeInfo {
-    name: String,
-    pieces: ByteBuf,
+    pub name: String,
+    #[serde(default)]
+    pub pieces: ByteBuf,
     #[serde(rename = "piece length")]
-    piece_length: i64,
+    pub piece_length: i64,
     #[serde(default)]
-    md5sum: Option<String>,
+    pub md5sum: Option<String>,
     #[serde(default)]
-    length: Option<i64>,
+    pub length: Option<i64>,
     #[serde(default)]
-    files: Option<Vec<File>>,
+    pub files: Option<Vec<File>>,
     #[serde(default)]
-    private: Option<u8>,
+    pub private: Option<u8>,
     #[serde(default)]
-    path: Option<Vec<String>>,
+    pub path: Option<Vec<String>>,
     #[serde(default)]
     #[serde(rename = "root hash")]
-    root_hash: Option<String>,
+    pub root_hash: Option<String>,
+    #[serde(default)]
+    #[serde(rename = "meta version")]
+    pub meta_version: Option<i64>,
+    #[serde(default)]
+    #[serde(rename = "file tree")]
+    pub file_tree: Option<BTreeMap<String, FileTreeNode>>,
+    // the info dictionary exactly as it appeared in the .torrent; keys the
+    // struct doesn't model are part of the info hash too
+    #[serde(skip)]
+    pub raw: Vec<u8>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BencodeTorrent {
-    info: BencodeInfo,
+    pub info: BencodeInfo,
     #[serde(default)]
-    announce: Option<String>,
+    pub announce: Option<String>,
     #[serde(default)]
-    nodes: Option<Vec<Node>>,
+    pub nodes: Option<Vec<Node>>,
     #[serde(default)]
-    encoding: Option<String>,
+    pub encoding: Option<String>,
     #[serde(default)]
-    httpseeds: Option<Vec<String>>,
+    pub httpseeds: Option<Vec<String>>,
+    // the piece layer of every v2 file bigger than a piece, keyed by the
+    // file's pieces root
+    #[serde(default)]
+    #[serde(rename = "piece layers")]
+    pub piece_layers: Option<HashMap<ByteBuf, ByteBuf>>,
+    #[serde(default)]
+    #[serde(rename = "url-list")]
+    pub url_list: Option<UrlList>,
     #[serde(default)]
     #[serde(rename = "announce-list")]
-    announce_list: Option<Vec<Vec<String>>>,
+    pub announce_list: Option<Vec<Vec<String>>>,
     #[serde(default)]
     #[serde(rename = "creation date")]
-    creation_date: Option<i64>,
+    pub creation_date: Option<i64>,
     #[serde(rename = "comment")]
-    comment: Option<String>,
+    pub comment: Option<String>,
     #[serde(default)]
     #[serde(rename = "created by")]
-    created_by: Option<String>,
+    pub created_by: Option<String>,
+}
+
+// UrlList holds the web seeds of a torrent; torrents with a single one may
+// store it as a plain string
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BencodeTrackerResp {
+    #[serde(default)]
+    #[serde(rename = "failure reason")]
+    pub failure_reason: Option<String>,
     #[serde(default)]
     interval: i64,
     #[serde(default)]
-    peers: ByteBuf,
+    peers: TrackerPeers,
+    #[serde(default)]
+    peers6: ByteBuf,
+}
+
+// TrackerPeers are the peers of an announce: 6 bytes for each in the compact
+// model, or dictionaries that carry peer ids too
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TrackerPeers {
+    Compact(ByteBuf),
+    Dicts(Vec<TrackerPeer>),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrackerPeer {
+    #[serde(default)]
+    #[serde(rename = "peer id")]
+    pub peer_id: Option<ByteBuf>,
+    pub ip: String,
+    pub port: u16,
+}
+
+impl Default for TrackerPeers {
+    fn default() -> Self {
+        TrackerPeers::Compact(ByteBuf::new())
+    }
+}
+
+// ScrapeStats are what a tracker knows about one torrent's swarm
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScrapeStats {
+    #[serde(default)]
+    pub complete: i64,
+    #[serde(default)]
+    pub downloaded: i64,
+    #[serde(default)]
+    pub incomplete: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BencodeScrapeResp {
+    #[serde(default)]
+    pub files: HashMap<ByteBuf, ScrapeStats>,
+}
+
+// AnnounceEvent tells a tracker that we joined the swarm, finished the
+// download or left; announces in between carry none
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    fn name(self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+// Announce is what a tracker is told about our part in a torrent's swarm:
+// the port peers reach us on, payload bytes sent and received since we
+// started, and the bytes we still miss
+#[derive(Debug, Clone, Copy)]
+pub struct Announce {
+    pub port: u16,
+    pub uploaded: i64,
+    pub downloaded: i64,
+    pub left: i64,
+    pub event: Option<AnnounceEvent>,
+}
+
+// TorrentFile is one file of the torrent, placed at `offset` bytes into the
+// concatenation of all files that the pieces are computed over
+#[derive(Debug, Clone)]
+pub struct TorrentFile {
+    pub path: PathBuf,
+    pub length: i64,
+    pub offset: i64,
+    // padding files of hybrid torrents are zeros that are never written
+    pub padding: bool,
+}
+
+// MetaVersion tells which hashes a torrent carries: v1 SHA-1 pieces, v2
+// per-file merkle trees (BEP 52), or both in a hybrid torrent
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum MetaVersion {
+    V1,
+    V2,
+    Hybrid,
+}
+
+// PieceHash is what a piece is checked against: the SHA-1 of its bytes, or
+// the root of its subtree of 16 KiB leaves together with the subtree's width
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PieceHash {
+    V1([u8; 20]),
+    V2(merkle::Hash, usize),
 }
 
 pub struct Torrent {
     pub announce: String,
+    // every tracker of the torrent in tiers (BEP 12), tried in order
+    pub trackers: Vec<Vec<String>>,
     pub name: String,
     pub length: i64,
     pub info_hash: [u8; 20],
+    // the SHA-256 info hash of v2 and hybrid torrents
+    pub info_hash_v2: Option<merkle::Hash>,
+    pub version: MetaVersion,
     pub piece_length: i64,
-    pub piece_hashes: Vec<[u8; 20]>,
+    pub piece_hashes: Vec<PieceHash>,
+    pub files: Vec<TorrentFile>,
+    // the bencoded info dictionary, served to peers over ut_metadata
+    pub info_bytes: Vec<u8>,
+    // the piece layers of v2 files, keyed by pieces root, served to peers
+    // that ask for hashes
+    pub piece_layers: merkle::PieceLayers,
+    // HTTP servers with the torrent's files (BEP 19) or pieces (BEP 17)
+    pub web_seeds: Vec<String>,
+    pub http_seeds: Vec<String>,
+    // private torrents only get peers from their own trackers (BEP 27)
+    pub private: bool,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum InvalidTorrentError {
+    InvalidBencode(serde_bencode::Error),
+    MissingInfo,
     WrongNumberOfPieces,
+    MissingLength,
+    MissingAnnounce,
+    InvalidPieceLength,
+    InvalidFileTree,
+    InvalidPieceLayers,
+    // a file length is negative or the lengths overflow
+    InvalidLength,
+    // a name or path component could step outside the download directory
+    InvalidPath(String),
 }
 
-#[derive(Debug, Clone)]
+impl fmt::Display for InvalidTorrentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidTorrentError::InvalidBencode(e) => write!(f, "invalid bencode: {}", e),
+            InvalidTorrentError::MissingInfo => write!(f, "no info dictionary"),
+            InvalidTorrentError::WrongNumberOfPieces => {
+                write!(f, "piece hashes don't match the size")
+            }
+            InvalidTorrentError::MissingLength => write!(f, "neither a length nor files"),
+            InvalidTorrentError::MissingAnnounce => write!(f, "no announce url"),
+            InvalidTorrentError::InvalidPieceLength => write!(f, "invalid piece length"),
+            InvalidTorrentError::InvalidFileTree => write!(f, "invalid v2 file tree"),
+            InvalidTorrentError::InvalidPieceLayers => {
+                write!(f, "piece layers don't match the file tree")
+            }
+            InvalidTorrentError::InvalidLength => write!(f, "invalid file length"),
+            InvalidTorrentError::InvalidPath(name) => write!(f, "invalid file name {:?}", name),
+        }
+    }
+}
+
+impl Error for InvalidTorrentError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            InvalidTorrentError::InvalidBencode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum TrackerError {
+    InvalidUrl(ParseError),
+    RequestFailure(reqwest::Error),
+    ProxyFailure(proxy::ProxyError),
+    // the tracker can't be reached from the addresses we are bound to
+    BindFailure(io::Error),
+    InvalidTrackerResponse(serde_bencode::Error),
+    // the tracker answered with a failure reason
+    Rejected(String),
     InvalidPeerResponse,
+    ScrapeUnsupported,
+    // the scrape answer doesn't mention the torrent
+    NotTracked,
 }
 
-impl BencodeTrackerResp {
-    pub fn get_peers(&self) -> Result<Vec<p2p::Peer>, TrackerError> {
-        let mut final_peers: Vec<p2p::Peer> = vec![];
-
-        let peer_size = 6; // 4 for IP, 2 for port
-        let num_peers = self.peers.len() / peer_size;
-        if self.peers.len() % peer_size != 0 {
-            return Err(TrackerError::InvalidPeerResponse);
-        }
-
-        for i in 0..num_peers {
-            let offset = i * peer_size;
-
-            let ip_slice = &self.peers[offset..offset + 4];
-            let port_arr: [u8; 2] = [self.peers[offset + 4], self.peers[offset + 5]];
-            let port = u16::from_be_bytes(port_arr);
-
-            let newpeer = p2p::Peer {
-                ip: IpAddr::V4(Ipv4Addr::new(
-                    ip_slice[0],
-                    ip_slice[1],
-                    ip_slice[2],
-                    ip_slice[3],
-                )),
-                port,
-            };
-            final_peers.push(newpeer);
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrackerError::InvalidUrl(e) => write!(f, "invalid tracker url: {}", e),
+            TrackerError::RequestFailure(e) => write!(f, "tracker request failed: {}", e),
+            TrackerError::ProxyFailure(e) => write!(f, "tracker request failed: {}", e),
+            TrackerError::BindFailure(e) => write!(f, "tracker request failed: {}", e),
+            TrackerError::InvalidTrackerResponse(e) => {
+                write!(f, "invalid tracker response: {}", e)
+            }
+            TrackerError::Rejected(reason) => write!(f, "tracker said: {}", reason),
+            TrackerError::InvalidPeerResponse => write!(f, "invalid peer list from tracker"),
+            TrackerError::ScrapeUnsupported => write!(f, "tracker doesn't support scrape"),
+            TrackerError::NotTracked => write!(f, "tracker doesn't know the torrent"),
         }
+    }
+}
+
+impl Error for TrackerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TrackerError::InvalidUrl(e) => Some(e),
+            TrackerError::RequestFailure(e) => Some(e),
+            TrackerError::ProxyFailure(e) => Some(e),
+            TrackerError::BindFailure(e) => Some(e),
+            TrackerError::InvalidTrackerResponse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// the peers of a compact list, where each is an address of `ip_len` bytes
+// followed by a port, both big endian
+pub fn compact_peers(buf: &[u8], ip_len: usize) -> Result<Vec<p2p::Peer>, TrackerError> {
+    let peer_size = ip_len + 2;
+    if !buf.len().is_multiple_of(peer_size) {
+        return Err(TrackerError::InvalidPeerResponse);
+    }
+    let peers = buf
+        .chunks(peer_size)
+        .map(|peer| {
+            let (ip, port) = peer.split_at(ip_len);
+            let ip = match *ip {
+                [a, b, c, d] => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+                _ => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(ip);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+            };
+            p2p::Peer {
+                ip,
+                port: u16::from_be_bytes([port[0], port[1]]),
+                id: None,
+            }
+        })
+        .collect();
+    Ok(peers)
+}
 
-        Ok(final_peers)
+impl BencodeTrackerResp {
+    // the peers of the response, less those `blocked` turns away
+    pub fn get_peers(
+        &self,
+        blocked: impl Fn(&IpAddr) -> bool,
+    ) -> Result<Vec<p2p::Peer>, TrackerError> {
+        let mut peers = match &self.peers {
+            TrackerPeers::Compact(peers) => compact_peers(peers, 4)?,
+            // peers given by host name are skipped
+            TrackerPeers::Dicts(peers) => peers
+                .iter()
+                .filter_map(|p| {
+                    Some(p2p::Peer {
+                        ip: p.ip.parse().ok()?,
+                        port: p.port,
+                        id: p.peer_id.as_ref().and_then(|id| id[..].try_into().ok()),
+                    })
+                })
+                .collect(),
+        };
+        // IPv6 peers come in a list of their own (BEP 7)
+        peers.append(&mut compact_peers(&self.peers6, 16)?);
+        peers.retain(|p| !blocked(&p.ip));
+        Ok(peers)
     }
 }
 
 impl BencodeInfo {
     pub fn calculate_info_hash(&self) -> [u8; 20] {
-        let info_bencoded = ser::to_bytes(&self).unwrap();
         let mut hasher = Sha1::new();
-        hasher.input(info_bencoded);
+        hasher.input(self.info_bytes());
         let sum_hex = hasher.result();
         let mut sum_bytes: [u8; 20] = Default::default();
         sum_bytes.copy_from_slice(sum_hex.as_slice());
         sum_bytes
     }
 
+    // the bencoded dictionary: the original bytes if it was parsed, otherwise
+    // it is serialized
+    pub fn info_bytes(&self) -> Vec<u8> {
+        if self.raw.is_empty() {
+            ser::to_bytes(self).unwrap()
+        } else {
+            self.raw.clone()
+        }
+    }
+
     pub fn split_piece_hashes(&self) -> Result<Vec<[u8; 20]>, InvalidTorrentError> {
         // handle info.pieces length not being divided by 20
-        if self.pieces.len() % 20 != 0 {
+        if !self.pieces.len().is_multiple_of(20) {
             return Err(InvalidTorrentError::WrongNumberOfPieces);
         }
 
         let mut hash_list: Vec<[u8; 20]> = Vec::new();
-        let mut hash = [0u8; 20];
-        let mut current_index: usize = 0;
-        for piece in self.pieces.iter() {
-            if current_index >= 20 {
-                hash_list.push(hash);
-                hash = [0u8; 20];
-                current_index = 0;
-            }
-            hash[current_index] = *piece;
-            current_index += 1;
+        for piece in self.pieces.chunks(20) {
+            let mut hash = [0u8; 20];
+            hash.copy_from_slice(piece);
+            hash_list.push(hash);
         }
-        hash_list.push(hash);
 
         // println!("hash_list: {:?}", hash_list);
         Ok(hash_list)
     }
+
+    // lays out the files of the torrent; a single-file torrent is one file
+    // named after the torrent, a multi-file torrent nests its files under a
+    // directory named after the torrent
+    pub fn build_files(&self) -> Result<Vec<TorrentFile>, InvalidTorrentError> {
+        let name = check_component(&self.name)?;
+        if let Some(length) = self.length {
+            if length < 0 {
+                return Err(InvalidTorrentError::InvalidLength);
+            }
+            return Ok(vec![TorrentFile {
+                path: PathBuf::from(&self.name),
+                length,
+                offset: 0,
+                padding: false,
+            }]);
+        }
+
+        let files = match &self.files {
+            Some(files) => files,
+            None => return Err(InvalidTorrentError::MissingLength),
+        };
+
+        let mut torrent_files = Vec::with_capacity(files.len());
+        let mut offset: i64 = 0;
+        for f in files {
+            if f.path.is_empty() {
+                return Err(InvalidTorrentError::InvalidPath(String::new()));
+            }
+            let mut path = PathBuf::from(name);
+            for component in &f.path {
+                path.push(check_component(component)?);
+            }
+            if f.length < 0 {
+                return Err(InvalidTorrentError::InvalidLength);
+            }
+            torrent_files.push(TorrentFile {
+                path,
+                length: f.length,
+                offset,
+                padding: f.attr.as_ref().is_some_and(|a| a.contains('p')),
+            });
+            offset = offset
+                .checked_add(f.length)
+                .ok_or(InvalidTorrentError::InvalidLength)?;
+        }
+        Ok(torrent_files)
+    }
+
+    // lays out the files of a v2 file tree; every file starts at a piece
+    // boundary, and the pieces root of each file is returned alongside
+    fn build_v2_files(
+        &self,
+    ) -> Result<Vec<(TorrentFile, Option<merkle::Hash>)>, InvalidTorrentError> {
+        let tree = self
+            .file_tree
+            .as_ref()
+            .ok_or(InvalidTorrentError::InvalidFileTree)?;
+        // a single file is named after the torrent, like in v1
+        let root = match tree.values().next() {
+            Some(FileTreeNode::File { .. }) if tree.len() == 1 => PathBuf::new(),
+            _ => PathBuf::from(check_component(&self.name)?),
+        };
+        let mut entries = vec![];
+        walk_file_tree(tree, root, &mut entries)?;
+        if entries.is_empty() {
+            return Err(InvalidTorrentError::InvalidFileTree);
+        }
+
+        let mut files = Vec::with_capacity(entries.len());
+        let mut offset = 0;
+        for (path, entry) in entries {
+            if entry.length < 0 {
+                return Err(InvalidTorrentError::InvalidLength);
+            }
+            let pieces_root = match &entry.pieces_root {
+                Some(root) if root.len() == 32 => {
+                    let mut hash = [0u8; 32];
+                    hash.copy_from_slice(root);
+                    Some(hash)
+                }
+                Some(_) => return Err(InvalidTorrentError::InvalidFileTree),
+                None if entry.length > 0 => return Err(InvalidTorrentError::InvalidFileTree),
+                None => None,
+            };
+            files.push((
+                TorrentFile {
+                    path,
+                    length: entry.length,
+                    offset,
+                    padding: false,
+                },
+                pieces_root,
+            ));
+            // files start at piece boundaries
+            let pieces =
+                entry.length / self.piece_length + i64::from(entry.length % self.piece_length != 0);
+            offset = pieces
+                .checked_mul(self.piece_length)
+                .and_then(|size| size.checked_add(offset))
+                .ok_or(InvalidTorrentError::InvalidLength)?;
+        }
+        Ok(files)
+    }
+}
+
+// a file or directory name from the torrent, which has to be a single normal
+// path component so that joining it can't leave the download directory
+fn check_component(name: &str) -> Result<&str, InvalidTorrentError> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(c)), None) if c == name && !name.contains(['/', '\\', '\0']) => {
+            Ok(name)
+        }
+        _ => Err(InvalidTorrentError::InvalidPath(name.to_owned())),
+    }
+}
+
+fn walk_file_tree(
+    tree: &BTreeMap<String, FileTreeNode>,
+    path: PathBuf,
+    entries: &mut Vec<(PathBuf, FileTreeEntry)>,
+) -> Result<(), InvalidTorrentError> {
+    for (name, node) in tree {
+        let path = path.join(check_component(name)?);
+        match node {
+            FileTreeNode::File { file } => entries.push((path, file.clone())),
+            FileTreeNode::Dir(dir) => walk_file_tree(dir, path, entries)?,
+        }
+    }
+    Ok(())
+}
+
+// the hashes of every piece of a v2 torrent: files that fit in one piece are
+// checked against their pieces root, bigger ones against their piece layer,
+// which has to add up to the pieces root
+fn v2_piece_hashes(
+    files: &[(TorrentFile, Option<merkle::Hash>)],
+    piece_length: i64,
+    piece_layers: Option<&HashMap<ByteBuf, ByteBuf>>,
+) -> Result<(Vec<PieceHash>, merkle::PieceLayers), InvalidTorrentError> {
+    let leaves_per_piece = (piece_length as usize) / merkle::BLOCK_SIZE;
+    let mut hashes = vec![];
+    let mut layers = HashMap::new();
+    for (f, pieces_root) in files {
+        let root = match pieces_root {
+            Some(root) => *root,
+            None => continue,
+        };
+        if f.length <= piece_length {
+            let blocks = (f.length as usize).div_ceil(merkle::BLOCK_SIZE);
+            hashes.push(PieceHash::V2(root, blocks.next_power_of_two()));
+            continue;
+        }
+
+        let layer = piece_layers
+            .and_then(|l| l.get(&ByteBuf::from(root.to_vec())))
+            .ok_or(InvalidTorrentError::InvalidPieceLayers)?;
+        let pieces = ((f.length + piece_length - 1) / piece_length) as usize;
+        if layer.len() != pieces * 32 {
+            return Err(InvalidTorrentError::InvalidPieceLayers);
+        }
+        let layer: Vec<merkle::Hash> = layer
+            .chunks(32)
+            .map(|c| {
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(c);
+                hash
+            })
+            .collect();
+        let pad = merkle::pad_hash(leaves_per_piece);
+        if merkle::root(&layer, pieces.next_power_of_two(), pad) != root {
+            return Err(InvalidTorrentError::InvalidPieceLayers);
+        }
+        hashes.extend(layer.iter().map(|h| PieceHash::V2(*h, leaves_per_piece)));
+        layers.insert(root, layer);
+    }
+    Ok((hashes, layers))
+}
+
+impl PieceHash {
+    pub fn matches(&self, buf: &[u8]) -> bool {
+        match self {
+            PieceHash::V1(hash) => Sha1::digest(buf).as_slice() == hash,
+            PieceHash::V2(root, width) => {
+                merkle::root(&merkle::block_hashes(buf), *width, [0u8; 32]) == *root
+            }
+        }
+    }
+}
+
+// `key` stays the same for every announce of a session, so trackers can tell
+// us apart from other clients behind the same address
+pub fn build_tracker_url(
+    announce: &str,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    key: u32,
+    report: &Announce,
+) -> Result<String, ParseError> {
+    let infohash_urlencoded: String = form_urlencoded::byte_serialize(info_hash).collect();
+
+    let peer_id_urlencoded: String = form_urlencoded::byte_serialize(peer_id).collect();
+
+    let mut querystring = format!(
+        "?info_hash={info_hash}&peer_id={peer_id}&port={port}&uploaded={uploaded}&downloaded={downloaded}&compact={compact}&left={left}&key={key:08x}",
+        info_hash=infohash_urlencoded,
+        peer_id=peer_id_urlencoded,
+        port=report.port,
+        uploaded=report.uploaded,
+        downloaded=report.downloaded,
+        left=report.left,
+        compact="1",
+        key=key,
+    );
+    if let Some(event) = report.event {
+        querystring.push_str(&format!("&event={}", event.name()));
+    }
+    let mut final_url = announce.to_owned();
+    final_url.push_str(&querystring);
+    Ok(final_url)
+}
+
+// trackers that support scraping serve it next to the announce path, with
+// `announce` in the last path segment replaced by `scrape`
+pub fn build_scrape_url(announce: &str, info_hash: &[u8; 20]) -> Result<String, TrackerError> {
+    let slash = announce.rfind('/').ok_or(TrackerError::ScrapeUnsupported)?;
+    let (base, last) = announce.split_at(slash + 1);
+    if !last.starts_with("announce") {
+        return Err(TrackerError::ScrapeUnsupported);
+    }
+    let infohash_urlencoded: String = form_urlencoded::byte_serialize(info_hash).collect();
+    let rest = &last["announce".len()..];
+    let separator = if rest.contains('?') { '&' } else { '?' };
+    Ok(format!(
+        "{}scrape{}{}info_hash={}",
+        base, rest, separator, infohash_urlencoded
+    ))
 }
 
 impl Torrent {
-    pub fn build_tracker_url(&self) -> Result<String, ParseError> {
-        let infohash_urlencoded: String =
-            form_urlencoded::byte_serialize(&self.info_hash).collect();
-
-        let peer_id_urlencoded: String =
-            form_urlencoded::byte_serialize(p2p::PEER_ID_STRING.as_bytes()).collect();
-
-        let querystring = format!(
-            "?info_hash={info_hash}&peer_id={peer_id}&port={port}&uploaded={uploaded}&downloaded={downloaded}&compact={compact}&left={left}",
-            info_hash=infohash_urlencoded,
-            peer_id=peer_id_urlencoded,
-            port="6881",
-            uploaded="0",
-            downloaded="0",
-            left=self.length,
-            compact="1",
-        );
-        let mut final_url = self.announce.to_owned();
-        final_url.push_str(&querystring);
-        Ok(final_url)
+    pub fn build_tracker_url(
+        &self,
+        peer_id: &[u8; 20],
+        key: u32,
+        report: &Announce,
+    ) -> Result<String, ParseError> {
+        build_tracker_url(&self.announce, &self.info_hash, peer_id, key, report)
+    }
+
+    pub fn build_scrape_url(&self) -> Result<String, TrackerError> {
+        build_scrape_url(&self.announce, &self.info_hash)
     }
 
     pub fn render_torrent(&self) {
@@ -190,42 +711,202 @@ impl Torrent {
         // println!("piece_hashes: {:?}", self.piece_hashes);
     }
 
+    // the hashes the torrent's swarms go by; a hybrid torrent is shared both
+    // as a v1 and as a v2 torrent
+    pub fn swarm_hashes(&self) -> Vec<[u8; 20]> {
+        match (self.version, self.truncated_info_hash_v2()) {
+            (MetaVersion::Hybrid, Some(v2)) => vec![self.info_hash, v2],
+            _ => vec![self.info_hash],
+        }
+    }
+
+    // the 20 bytes v2 torrents use in handshakes and announces in place of a
+    // v1 info hash
+    pub fn truncated_info_hash_v2(&self) -> Option<[u8; 20]> {
+        self.info_hash_v2.map(|hash| {
+            let mut truncated = [0u8; 20];
+            truncated.copy_from_slice(&hash[..20]);
+            truncated
+        })
+    }
+
     pub fn calculate_bounds_for_piece(&self, index: i64) -> (i64, i64) {
         let begin: i64 = index * self.piece_length;
         let mut end: i64 = begin + self.piece_length;
-        if end > self.length {
-            end = self.length;
+        // v2 pieces don't cross files, the last piece of a file is short
+        let limit = if self.version == MetaVersion::V2 {
+            let i = self.files.partition_point(|f| f.offset + f.length <= begin);
+            self.files.get(i).map_or(begin, |f| f.offset + f.length)
+        } else {
+            self.length
+        };
+        if end > limit {
+            end = limit;
         }
-        return (begin, end);
+        (begin, end)
     }
 
     pub fn calculate_piece_size(&self, index: i64) -> i64 {
         let (begin, end) = self.calculate_bounds_for_piece(index);
-        return end - begin;
+        end - begin
+    }
+
+    // returns the range of pieces that hold the bytes of a file
+    pub fn pieces_for_file(&self, file_index: usize) -> std::ops::Range<i64> {
+        let f = &self.files[file_index];
+        if f.length == 0 {
+            return 0..0;
+        }
+        let first = f.offset / self.piece_length;
+        let last = (f.offset + f.length - 1) / self.piece_length;
+        first..last + 1
     }
 }
 
-pub fn new_torrent(bencode_torrent: &BencodeTorrent) -> Torrent {
-    Torrent {
-        announce: bencode_torrent.announce.as_ref().unwrap().to_string(),
-        name: bencode_torrent.info.name.clone(),
-        length: bencode_torrent.info.length.unwrap(),
-        info_hash: bencode_torrent.info.calculate_info_hash(),
-        piece_length: bencode_torrent.info.piece_length,
-        piece_hashes: bencode_torrent.info.split_piece_hashes().unwrap(),
+// the announce-list takes the place of `announce` when there is one; the
+// trackers of a tier are shuffled so the swarm spreads over them
+fn tracker_tiers(bencode_torrent: &BencodeTorrent) -> Vec<Vec<String>> {
+    let mut tiers: Vec<Vec<String>> = match &bencode_torrent.announce_list {
+        Some(list) => list
+            .iter()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect(),
+        None => vec![],
+    };
+    if tiers.is_empty() {
+        tiers = bencode_torrent
+            .announce
+            .iter()
+            .map(|announce| vec![announce.clone()])
+            .collect();
+    }
+    for tier in tiers.iter_mut() {
+        for i in (1..tier.len()).rev() {
+            let j = (peer_id::random_u64() % (i as u64 + 1)) as usize;
+            tier.swap(i, j);
+        }
     }
+    tiers
+}
+
+pub fn new_torrent(bencode_torrent: &BencodeTorrent) -> Result<Torrent, InvalidTorrentError> {
+    let info = &bencode_torrent.info;
+    let trackers = tracker_tiers(bencode_torrent);
+    let announce = match trackers.first().and_then(|tier| tier.first()) {
+        Some(announce) => announce.to_string(),
+        None => return Err(InvalidTorrentError::MissingAnnounce),
+    };
+    let version = match info.meta_version {
+        Some(2) if info.pieces.is_empty() => MetaVersion::V2,
+        Some(2) => MetaVersion::Hybrid,
+        _ => MetaVersion::V1,
+    };
+    if info.piece_length <= 0 {
+        return Err(InvalidTorrentError::InvalidPieceLength);
+    }
+    if version != MetaVersion::V1
+        && (info.piece_length < merkle::BLOCK_SIZE as i64
+            || !(info.piece_length as u64).is_power_of_two())
+    {
+        return Err(InvalidTorrentError::InvalidPieceLength);
+    }
+
+    // hybrid torrents are downloaded as v1, whose pieces line up with the v2
+    // ones thanks to padding files
+    let info_bytes = info.info_bytes();
+    let info_hash_v2 = match version {
+        MetaVersion::V1 => None,
+        _ => Some(merkle::sha256(&info_bytes)),
+    };
+    let (files, piece_hashes, piece_layers) = match version {
+        MetaVersion::V2 => {
+            let files = info.build_v2_files()?;
+            let (hashes, layers) = v2_piece_hashes(
+                &files,
+                info.piece_length,
+                bencode_torrent.piece_layers.as_ref(),
+            )?;
+            let files: Vec<TorrentFile> = files.into_iter().map(|(f, _)| f).collect();
+            (files, hashes, layers)
+        }
+        _ => {
+            let files = info.build_files()?;
+            let hashes = info.split_piece_hashes()?;
+            // every piece but the last one is full
+            let total: i64 = files.iter().map(|f| f.length).sum();
+            let pieces = total / info.piece_length + i64::from(total % info.piece_length != 0);
+            if hashes.len() as i64 != pieces {
+                return Err(InvalidTorrentError::WrongNumberOfPieces);
+            }
+            let hashes = hashes.into_iter().map(PieceHash::V1).collect();
+            (files, hashes, HashMap::new())
+        }
+    };
+
+    let mut t = Torrent {
+        announce,
+        trackers,
+        name: info.name.clone(),
+        length: files.iter().map(|f| f.length).sum(),
+        info_hash: info.calculate_info_hash(),
+        info_hash_v2,
+        version,
+        piece_length: info.piece_length,
+        piece_hashes,
+        files,
+        info_bytes,
+        piece_layers,
+        web_seeds: match &bencode_torrent.url_list {
+            Some(UrlList::One(url)) => vec![url.clone()],
+            Some(UrlList::Many(urls)) => urls.clone(),
+            None => vec![],
+        },
+        http_seeds: bencode_torrent.httpseeds.clone().unwrap_or_default(),
+        private: info.private == Some(1),
+    };
+    if version == MetaVersion::V2 {
+        t.info_hash = t.truncated_info_hash_v2().unwrap();
+    }
+    Ok(t)
+}
+
+pub fn parse_torrent(data: &[u8]) -> Result<BencodeTorrent, InvalidTorrentError> {
+    let mut torrent =
+        de::from_bytes::<BencodeTorrent>(data).map_err(InvalidTorrentError::InvalidBencode)?;
+    let span = info_span(data).ok_or(InvalidTorrentError::MissingInfo)?;
+    torrent.info.raw = data[span].to_vec();
+    Ok(torrent)
+}
+
+// finds the bytes of the value under the `info` key of the top-level dictionary
+fn info_span(data: &[u8]) -> Option<std::ops::Range<usize>> {
+    if *data.first()? != b'd' {
+        return None;
+    }
+    let mut pos = 1;
+    while *data.get(pos)? != b'e' {
+        let key_end = pos + extension::bencode_length(&data[pos..])?;
+        let value_end = key_end + extension::bencode_length(&data[key_end..])?;
+        if &data[pos..key_end] == b"4:info" {
+            return Some(key_end..value_end);
+        }
+        pos = value_end;
+    }
+    None
 }
 
 pub fn render_bencode_torrent(torrent: &BencodeTorrent) {
     println!("name:\t\t{}", torrent.info.name);
     println!("announce:\t{:?}", torrent.announce);
     println!("nodes:\t\t{:?}", torrent.nodes);
-    if let &Some(ref al) = &torrent.announce_list {
+    if let Some(al) = &torrent.announce_list {
         for a in al {
             println!("announce list:\t{}", a[0]);
         }
     }
     println!("httpseeds:\t{:?}", torrent.httpseeds);
+    println!("url-list:\t{:?}", torrent.url_list);
     println!("creation date:\t{:?}", torrent.creation_date);
     println!("comment:\t{:?}", torrent.comment);
     println!("created by:\t{:?}", torrent.created_by);
@@ -235,7 +916,7 @@ pub fn render_bencode_torrent(torrent: &BencodeTorrent) {
     println!("root hash:\t{:?}", torrent.info.root_hash);
     println!("md5sum:\t\t{:?}", torrent.info.md5sum);
     println!("path:\t\t{:?}", torrent.info.path);
-    if let &Some(ref files) = &torrent.info.files {
+    if let Some(files) = &torrent.info.files {
         for f in files {
             println!("file path:\t{:?}", f.path);
             println!("file length:\t{}", f.length);
@@ -243,3 +924,359 @@ pub fn render_bencode_torrent(torrent: &BencodeTorrent) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    #[test]
+    fn info_hash_covers_unknown_keys() {
+        let info =
+            b"d6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:abce";
+        let mut data = b"d8:announce3:url4:info".to_vec();
+        data.extend_from_slice(info);
+        data.push(b'e');
+
+        let t = super::new_torrent(&super::parse_torrent(&data).unwrap()).unwrap();
+        assert_eq!(t.info_hash[..], Sha1::digest(&info[..])[..]);
+        assert_eq!(t.info_bytes, info.to_vec());
+    }
+
+    #[test]
+    fn unsafe_paths_and_sizes_are_rejected() {
+        use super::InvalidTorrentError;
+
+        let torrent = |info: &str| {
+            let data = format!("d8:announce3:url4:info{}e", info);
+            super::parse_torrent(data.as_bytes()).and_then(|b| super::new_torrent(&b))
+        };
+        let files = |path: &str| {
+            torrent(&format!(
+                "d5:filesld6:lengthi1e4:pathl{}eee4:name1:x12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+                path
+            ))
+        };
+        assert!(files("1:a1:b").is_ok());
+        for path in &[
+            "2:..2:..4:evil",
+            "1:.",
+            "0:",
+            "4:/etc",
+            "3:a/b",
+            "3:a\\b",
+            "",
+        ] {
+            match files(path) {
+                Err(InvalidTorrentError::InvalidPath(_)) => {}
+                other => panic!("{} was accepted: {:?}", path, other.map(|t| t.files)),
+            }
+        }
+        for name in &["2:..", "4:/tmp", "0:"] {
+            let info = format!(
+                "d6:lengthi1e4:name{}12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae",
+                name
+            );
+            assert!(matches!(
+                torrent(&info),
+                Err(InvalidTorrentError::InvalidPath(_))
+            ));
+        }
+
+        let single = |length: i64, piece_length: i64, pieces: usize| {
+            torrent(&format!(
+                "d6:lengthi{}e4:name1:a12:piece lengthi{}e6:pieces{}:{}e",
+                length,
+                piece_length,
+                pieces * 20,
+                "a".repeat(pieces * 20)
+            ))
+        };
+        assert!(single(5, 2, 3).is_ok());
+        assert!(matches!(
+            single(5, 0, 3),
+            Err(InvalidTorrentError::InvalidPieceLength)
+        ));
+        assert!(matches!(
+            single(5, -2, 3),
+            Err(InvalidTorrentError::InvalidPieceLength)
+        ));
+        assert!(matches!(
+            single(-100, 2, 1),
+            Err(InvalidTorrentError::InvalidLength)
+        ));
+        assert!(matches!(
+            single(5, 2, 2),
+            Err(InvalidTorrentError::WrongNumberOfPieces)
+        ));
+        assert!(matches!(
+            single(5, 2, 4),
+            Err(InvalidTorrentError::WrongNumberOfPieces)
+        ));
+    }
+
+    #[test]
+    fn unsafe_file_tree_entries_are_rejected() {
+        use super::InvalidTorrentError;
+
+        let file = |length: i64| {
+            format!(
+                "d0:d6:lengthi{}e11:pieces root32:{}ee",
+                length,
+                "r".repeat(32)
+            )
+        };
+        let tree = |entries: &str| {
+            let data = format!(
+                "d8:announce3:url4:infod9:file treed{}e12:meta versioni2e4:name1:x12:piece lengthi16384eee",
+                entries
+            );
+            super::parse_torrent(data.as_bytes()).and_then(|b| super::new_torrent(&b))
+        };
+        let ok = tree(&format!("1:ad1:b{}e1:c{}", file(1), file(2))).unwrap();
+        let paths: Vec<_> = ok.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![super::Path::new("x/a/b"), super::Path::new("x/c")]
+        );
+
+        for key in &["2:..", "4:../x", "6:/etc/x", "1:.", "3:a/b"] {
+            match tree(&format!("{}{}1:c{}", key, file(1), file(1))) {
+                Err(InvalidTorrentError::InvalidPath(_)) => {}
+                other => panic!("{} was accepted: {:?}", key, other.map(|t| t.files)),
+            }
+        }
+        // an empty key, and a directory that is also a file
+        match tree(&format!("1:ad0:{}e", file(1))) {
+            Err(InvalidTorrentError::InvalidPath(name)) => assert_eq!(name, ""),
+            other => panic!("empty key was accepted: {:?}", other.map(|t| t.files)),
+        }
+        let both = format!(
+            "1:ad0:d6:lengthi1e11:pieces root32:{}e1:b{}e",
+            "r".repeat(32),
+            file(1)
+        );
+        assert!(tree(&both).is_err());
+        assert!(matches!(
+            tree(&format!("1:a{}1:b{}", file(-5), file(1))),
+            Err(InvalidTorrentError::InvalidLength)
+        ));
+    }
+
+    #[test]
+    fn url_list_is_a_string_or_a_list() {
+        let one = b"d8:announce3:url4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list5:http:e";
+        match super::parse_torrent(one).unwrap().url_list {
+            Some(super::UrlList::One(url)) => assert_eq!(url, "http:"),
+            other => panic!("unexpected url-list: {:?}", other),
+        }
+        let many = b"d8:announce3:url4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-listl5:http:ee";
+        match super::parse_torrent(many).unwrap().url_list {
+            Some(super::UrlList::Many(urls)) => assert_eq!(urls, vec!["http:"]),
+            other => panic!("unexpected url-list: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn announce_list_gives_the_tiers() {
+        let info = "4:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let tiers = |data: String| {
+            let b = super::parse_torrent(data.as_bytes()).unwrap();
+            super::new_torrent(&b).map(|t| (t.announce, t.trackers))
+        };
+
+        let (announce, trackers) = tiers(format!(
+            "d8:announce1:a13:announce-listll1:b1:cel1:dee{}e",
+            info
+        ))
+        .unwrap();
+        assert_eq!(trackers.len(), 2);
+        let mut first = trackers[0].clone();
+        first.sort();
+        assert_eq!(first, vec!["b", "c"]);
+        assert_eq!(trackers[1], vec!["d"]);
+        assert_eq!(announce, trackers[0][0]);
+
+        // without an announce-list `announce` is the only tier
+        let (_, trackers) = tiers(format!("d8:announce1:a{}e", info)).unwrap();
+        assert_eq!(trackers, vec![vec!["a"]]);
+        // an announce-list is enough on its own
+        let (announce, _) = tiers(format!("d13:announce-listll1:bee{}e", info)).unwrap();
+        assert_eq!(announce, "b");
+        match tiers(format!("d13:announce-listle{}e", info)) {
+            Err(super::InvalidTorrentError::MissingAnnounce) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn v2_files_are_piece_aligned_and_verified() {
+        use crate::merkle;
+        use serde_bytes::ByteBuf;
+        use std::collections::{BTreeMap, HashMap};
+
+        let piece_length = 2 * merkle::BLOCK_SIZE;
+        let a = vec![1u8; 20000];
+        let b: Vec<u8> = (0..70000).map(|i| i as u8).collect();
+        let a_root = merkle::root(&merkle::block_hashes(&a), 2, [0u8; 32]);
+        let layer: Vec<merkle::Hash> = b
+            .chunks(piece_length)
+            .map(|piece| merkle::root(&merkle::block_hashes(piece), 2, [0u8; 32]))
+            .collect();
+        let b_root = merkle::root(&layer, 4, merkle::pad_hash(2));
+
+        let entry = |length: usize, root: merkle::Hash| super::FileTreeNode::File {
+            file: super::FileTreeEntry {
+                length: length as i64,
+                pieces_root: Some(ByteBuf::from(root.to_vec())),
+            },
+        };
+        let mut tree = BTreeMap::new();
+        tree.insert("a".to_owned(), entry(a.len(), a_root));
+        tree.insert("b".to_owned(), entry(b.len(), b_root));
+        let mut piece_layers = HashMap::new();
+        piece_layers.insert(
+            ByteBuf::from(b_root.to_vec()),
+            ByteBuf::from(layer.concat()),
+        );
+        let data = serde_bencode::ser::to_bytes(&super::BencodeTorrent {
+            info: super::BencodeInfo {
+                name: "ds".to_owned(),
+                pieces: ByteBuf::new(),
+                piece_length: piece_length as i64,
+                md5sum: None,
+                length: None,
+                files: None,
+                private: None,
+                path: None,
+                root_hash: None,
+                meta_version: Some(2),
+                file_tree: Some(tree),
+                raw: vec![],
+            },
+            announce: Some("http://t.example/announce".to_owned()),
+            nodes: None,
+            encoding: None,
+            httpseeds: None,
+            piece_layers: Some(piece_layers),
+            url_list: None,
+            announce_list: None,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+        })
+        .unwrap();
+
+        let t = super::new_torrent(&super::parse_torrent(&data).unwrap()).unwrap();
+        assert_eq!(t.version, super::MetaVersion::V2);
+        assert_eq!(Some(t.info_hash), t.truncated_info_hash_v2());
+        assert_eq!(t.files[1].offset, piece_length as i64);
+        let sizes: Vec<i64> = (0..4).map(|i| t.calculate_piece_size(i)).collect();
+        assert_eq!(sizes, vec![20000, 32768, 32768, 4464]);
+
+        assert!(t.piece_hashes[0].matches(&a));
+        for (i, piece) in b.chunks(piece_length).enumerate() {
+            assert!(t.piece_hashes[i + 1].matches(piece));
+        }
+        assert!(!t.piece_hashes[1].matches(&b[..piece_length - 1]));
+    }
+
+    #[test]
+    fn announce_url_carries_the_key() {
+        let mut report = super::Announce {
+            port: 6881,
+            uploaded: 7,
+            downloaded: 9,
+            left: 5,
+            event: None,
+        };
+        let url = super::build_tracker_url(
+            "http://t.example/announce",
+            &[0x41u8; 20],
+            b"-HB0100-abcdefghijkl",
+            0xbeef,
+            &report,
+        )
+        .unwrap();
+        assert!(url.starts_with("http://t.example/announce?info_hash=AAAAAAAAAAAAAAAAAAAA&"));
+        assert!(url.contains("&peer_id=-HB0100-abcdefghijkl&"));
+        assert!(url.contains("&uploaded=7&downloaded=9&"));
+        assert!(url.ends_with("&left=5&key=0000beef"));
+
+        report.event = Some(super::AnnounceEvent::Completed);
+        let url = super::build_tracker_url("http://t", &[0x41u8; 20], &[0u8; 20], 1, &report);
+        assert!(url.unwrap().ends_with("&key=00000001&event=completed"));
+    }
+
+    #[test]
+    fn tracker_peers_are_compact_or_dictionaries() {
+        let compact = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+        let resp: super::BencodeTrackerResp = serde_bencode::de::from_bytes(compact).unwrap();
+        let peers = resp.get_peers(|_| false).unwrap();
+        assert_eq!(peers[0].ip.to_string(), "127.0.0.1");
+        assert_eq!((peers[0].port, peers[0].id), (6881, None));
+
+        let dicts = b"d8:intervali60e5:peersld2:ip8:10.0.0.17:peer id20:-TR2940-abcdefghijkl4:porti51413eed2:ip4:host4:porti1eeee";
+        let resp: super::BencodeTrackerResp = serde_bencode::de::from_bytes(dicts).unwrap();
+        let peers = resp.get_peers(|_| false).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].ip.to_string(), "10.0.0.1");
+        assert_eq!(peers[0].port, 51413);
+        assert_eq!(peers[0].id, Some(*b"-TR2940-abcdefghijkl"));
+
+        let dual = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\xc8\xd5e";
+        let resp: super::BencodeTrackerResp = serde_bencode::de::from_bytes(dual).unwrap();
+        let peers = resp.get_peers(|_| false).unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[1].ip.to_string(), "2001:db8::1");
+        assert_eq!(peers[1].port, 51413);
+
+        let broken = b"d5:peers0:6:peers64:\x00\x00\x00\x01e";
+        let resp: super::BencodeTrackerResp = serde_bencode::de::from_bytes(broken).unwrap();
+        assert!(resp.get_peers(|_| false).is_err());
+    }
+
+    #[test]
+    fn blocked_tracker_peers_are_dropped() {
+        let dual = b"d8:intervali60e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x01\x1a\xe16:peers618:\x20\x01\x0d\xb8\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\xc8\xd5e";
+        let resp: super::BencodeTrackerResp = serde_bencode::de::from_bytes(dual).unwrap();
+        let mut filter = crate::ipfilter::new_ip_filter();
+        filter.add_list("10.0.0.0/8\n2001:db8::/32\n").unwrap();
+        let peers = resp.get_peers(|ip| filter.is_blocked(ip)).unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].ip.to_string(), "127.0.0.1");
+    }
+
+    #[test]
+    fn scrape_url_replaces_announce() {
+        let hash = [0x41u8; 20];
+        assert_eq!(
+            super::build_scrape_url("http://t.example/announce", &hash).unwrap(),
+            "http://t.example/scrape?info_hash=AAAAAAAAAAAAAAAAAAAA"
+        );
+        assert_eq!(
+            super::build_scrape_url("http://t.example/x/announce.php?key=1", &hash).unwrap(),
+            "http://t.example/x/scrape.php?key=1&info_hash=AAAAAAAAAAAAAAAAAAAA"
+        );
+        assert!(super::build_scrape_url("http://t.example/a", &hash).is_err());
+    }
+    #[test]
+    fn errors_keep_their_source() {
+        use std::error::Error;
+
+        let e = super::parse_torrent(b"d8:announce").unwrap_err();
+        assert!(matches!(e, super::InvalidTorrentError::InvalidBencode(_)));
+        assert!(e.source().is_some());
+        assert!(e.to_string().starts_with("invalid bencode: "));
+
+        let e = super::TrackerError::Rejected("unregistered torrent".to_owned());
+        assert_eq!(e.to_string(), "tracker said: unregistered torrent");
+        assert!(e.source().is_none());
+
+        let e = super::TrackerError::InvalidUrl(url::Url::parse("not a url").unwrap_err());
+        assert_eq!(
+            e.to_string(),
+            "invalid tracker url: relative URL without a base"
+        );
+        assert!(e.source().is_some());
+    }
+}
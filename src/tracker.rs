@@ -0,0 +1,99 @@
+use serde_bencode::de;
+use serde_bytes::ByteBuf;
+use std::net::IpAddr;
+use url::Url;
+
+use crate::bind;
+use crate::p2p;
+use crate::proxy;
+use crate::torrent::{BencodeScrapeResp, BencodeTrackerResp, ScrapeStats, TrackerError};
+
+// the address of `bind` to reach the tracker behind `url` from
+async fn source(url: &str, bind: &[IpAddr]) -> Result<Option<IpAddr>, TrackerError> {
+    if bind.is_empty() {
+        return Ok(None);
+    }
+    let url = Url::parse(url).map_err(TrackerError::InvalidUrl)?;
+    let host = url.host_str().unwrap_or_default();
+    let host = host.trim_matches(|c| c == '[' || c == ']');
+    let port = url.port_or_known_default().unwrap_or_default();
+    bind::source_for_host(host, port, bind)
+        .await
+        .map_err(TrackerError::BindFailure)
+}
+
+// fetches `url` directly from an address of `bind`, or through the proxy if
+// there is one
+async fn get(
+    url: &str,
+    proxy: Option<&proxy::Proxy>,
+    bind: &[IpAddr],
+) -> Result<Vec<u8>, TrackerError> {
+    if let Some(proxy) = proxy {
+        return proxy
+            .get(url, bind)
+            .await
+            .map_err(TrackerError::ProxyFailure);
+    }
+    let client = reqwest::Client::builder()
+        .local_address(source(url, bind).await?)
+        .build()
+        .map_err(TrackerError::RequestFailure)?;
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(TrackerError::RequestFailure)?;
+    let body = res.bytes().await.map_err(TrackerError::RequestFailure)?;
+    Ok(body.to_vec())
+}
+
+// announces to the tracker behind `url` and returns the peers it knows that
+// `blocked` lets through; when we are bound to addresses, the tracker is told
+// the one we reach it from, and it learns our IPv6 address even when we
+// reach it over IPv4 (BEP 7)
+pub async fn announce(
+    url: &str,
+    proxy: Option<&proxy::Proxy>,
+    bind: &[IpAddr],
+    blocked: &dyn Fn(&IpAddr) -> bool,
+) -> Result<Vec<p2p::Peer>, TrackerError> {
+    let mut url = url.to_owned();
+    if proxy.is_none() {
+        let source = source(&url, bind).await?;
+        if let Some(ip) = source {
+            url.push_str(&format!("&ip={}", ip));
+        }
+        match bind::public_ipv6(bind) {
+            Some(ip) if source != Some(IpAddr::V6(ip)) => url.push_str(&format!("&ipv6={}", ip)),
+            _ => {}
+        }
+    }
+    let resp_buffer = get(&url, proxy, bind).await?;
+
+    // deserialize tracker response into bencode struct
+    let bencode_tracker_resp = de::from_bytes::<BencodeTrackerResp>(&resp_buffer)
+        .map_err(TrackerError::InvalidTrackerResponse)?;
+    if let Some(reason) = bencode_tracker_resp.failure_reason {
+        return Err(TrackerError::Rejected(reason));
+    }
+
+    bencode_tracker_resp.get_peers(blocked)
+}
+
+// asks the tracker behind the scrape `url` how many peers share the torrent
+pub async fn scrape(
+    url: &str,
+    info_hash: &[u8; 20],
+    proxy: Option<&proxy::Proxy>,
+    bind: &[IpAddr],
+) -> Result<ScrapeStats, TrackerError> {
+    let resp_buffer = get(url, proxy, bind).await?;
+
+    let mut scrape_resp = de::from_bytes::<BencodeScrapeResp>(&resp_buffer)
+        .map_err(TrackerError::InvalidTrackerResponse)?;
+    scrape_resp
+        .files
+        .remove(&ByteBuf::from(info_hash.to_vec()))
+        .ok_or(TrackerError::NotTracked)
+}
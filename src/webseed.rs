@@ -0,0 +1,541 @@
+use crossbeam::channel::Receiver;
+use std::error::Error;
+use std::fmt;
+use std::path::{Component, Path};
+use std::time::Duration;
+use tokio::time::delay_for;
+use url::{form_urlencoded, Url};
+
+use crate::bind;
+use crate::bitfield;
+use crate::choker;
+use crate::p2p;
+use crate::pipeline;
+use crate::proxy;
+use crate::ratelimit;
+use crate::torrent;
+
+// a failing web seed is retried after a delay that doubles up to a limit
+const MIN_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// a web seed is given up on once this many of its pieces failed their hash
+const MAX_HASH_FAILURES: u32 = 5;
+
+// how long one piece may take to arrive
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+// how often an idle web seed looks for pieces that went back on the queue
+const IDLE_DELAY: Duration = Duration::from_secs(1);
+
+// WebSeed is an HTTP server that has the whole torrent: either a plain server
+// with the files, asked for byte ranges (BEP 19), or a script that hands out
+// pieces (BEP 17)
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    pub url: String,
+    source: Source,
+    piece_length: i64,
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+    Files(Vec<FileUrl>),
+    Pieces([u8; 20]),
+}
+
+// FileUrl is where one file of the torrent is on a BEP 19 server, and where
+// it sits in the concatenation of all files
+#[derive(Debug, Clone)]
+struct FileUrl {
+    url: String,
+    offset: i64,
+    length: i64,
+    padding: bool,
+}
+
+#[derive(Debug)]
+pub enum WebSeedError {
+    InvalidUrl,
+    RequestFailure(reqwest::Error),
+    BadStatus(u16),
+    ShortResponse,
+    // the server is busy and wants us back after this many seconds
+    Busy(u64),
+}
+
+impl fmt::Display for WebSeedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebSeedError::InvalidUrl => write!(f, "invalid url"),
+            WebSeedError::RequestFailure(e) => write!(f, "request failed: {}", e),
+            WebSeedError::BadStatus(status) => write!(f, "server answered {}", status),
+            WebSeedError::ShortResponse => write!(f, "server sent less than asked for"),
+            WebSeedError::Busy(seconds) => write!(f, "server busy for {}s", seconds),
+        }
+    }
+}
+
+impl Error for WebSeedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WebSeedError::RequestFailure(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// the web seeds of a torrent, skipping urls that can't be used
+pub fn new_web_seeds(t: &torrent::Torrent) -> Vec<WebSeed> {
+    let mut seeds = vec![];
+    for url in t.web_seeds.iter().filter(|url| !url.is_empty()) {
+        let files: Result<Vec<FileUrl>, WebSeedError> = t
+            .files
+            .iter()
+            .map(|f| {
+                Ok(FileUrl {
+                    url: file_url(url, &t.name, &f.path, t.files.len() == 1)?,
+                    offset: f.offset,
+                    length: f.length,
+                    padding: f.padding,
+                })
+            })
+            .collect();
+        match files {
+            Ok(files) => seeds.push(WebSeed {
+                url: url.clone(),
+                source: Source::Files(files),
+                piece_length: t.piece_length,
+            }),
+            Err(e) => println!("skipping web seed {}: {}", url, e),
+        }
+    }
+    for url in t.http_seeds.iter().filter(|url| !url.is_empty()) {
+        seeds.push(WebSeed {
+            url: url.clone(),
+            source: Source::Pieces(t.info_hash),
+            piece_length: t.piece_length,
+        });
+    }
+    seeds
+}
+
+// where a file is on a BEP 19 server: a url that doesn't end in a slash is the
+// file itself for single-file torrents, otherwise the path inside the torrent
+// is appended to it
+fn file_url(base: &str, name: &str, path: &Path, single: bool) -> Result<String, WebSeedError> {
+    if single && path == Path::new(name) && !base.ends_with('/') {
+        return Ok(base.to_owned());
+    }
+    let mut url = Url::parse(base).map_err(|_| WebSeedError::InvalidUrl)?;
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| WebSeedError::InvalidUrl)?;
+        segments.pop_if_empty();
+        for component in path.components() {
+            match component {
+                Component::Normal(c) => {
+                    segments.push(c.to_str().ok_or(WebSeedError::InvalidUrl)?);
+                }
+                _ => return Err(WebSeedError::InvalidUrl),
+            }
+        }
+    }
+    Ok(url.into_string())
+}
+
+impl WebSeed {
+    // downloads piece `index` of `length` bytes, as fast as `throttle` lets
+    // it; the caller checks its hash
+    pub async fn fetch_piece(
+        &self,
+        client: &reqwest::Client,
+        throttle: &ratelimit::Throttle,
+        index: i64,
+        length: i64,
+    ) -> Result<Vec<u8>, WebSeedError> {
+        match &self.source {
+            Source::Files(files) => {
+                let begin = index * self.piece_length;
+                let end = begin + length;
+                let mut buf = vec![0u8; length as usize];
+                for f in files {
+                    // padding files are zeros, which the buffer starts out as
+                    if f.offset >= end || f.offset + f.length <= begin || f.padding {
+                        continue;
+                    }
+                    let start = begin.max(f.offset);
+                    let stop = end.min(f.offset + f.length);
+                    let range = format!("bytes={}-{}", start - f.offset, stop - f.offset - 1);
+                    let req = client.get(&f.url).header(reqwest::header::RANGE, range);
+                    let data = fetch(req, throttle).await?;
+
+                    // servers that ignore the range send the whole file
+                    let skip = if data.len() as i64 == f.length && stop - start < f.length {
+                        (start - f.offset) as usize
+                    } else {
+                        0
+                    };
+                    let wanted = (stop - start) as usize;
+                    if data.len() < skip + wanted {
+                        return Err(WebSeedError::ShortResponse);
+                    }
+                    buf[(start - begin) as usize..(stop - begin) as usize]
+                        .copy_from_slice(&data[skip..skip + wanted]);
+                }
+                Ok(buf)
+            }
+            Source::Pieces(info_hash) => {
+                let info_hash: String = form_urlencoded::byte_serialize(info_hash).collect();
+                let separator = if self.url.contains('?') { '&' } else { '?' };
+                let url = format!(
+                    "{}{}info_hash={}&piece={}",
+                    self.url, separator, info_hash, index
+                );
+                let data = fetch(client.get(&url), throttle).await?;
+                if (data.len() as i64) < length {
+                    return Err(WebSeedError::ShortResponse);
+                }
+                Ok(data[..length as usize].to_vec())
+            }
+        }
+    }
+}
+
+// the body of a response, read chunk by chunk so that it counts against the
+// rate limits like the payload of peers
+async fn fetch(
+    req: reqwest::RequestBuilder,
+    throttle: &ratelimit::Throttle,
+) -> Result<Vec<u8>, WebSeedError> {
+    let mut res = req.send().await.map_err(WebSeedError::RequestFailure)?;
+    let status = res.status();
+    let mut body = vec![];
+    while let Some(chunk) = res.chunk().await.map_err(WebSeedError::RequestFailure)? {
+        throttle.wait(chunk.len() as u64).await;
+        body.extend_from_slice(&chunk);
+    }
+    if status.as_u16() == 503 {
+        // BEP 17 servers put the seconds to wait in the body
+        let retry = String::from_utf8_lossy(&body).trim().parse().unwrap_or(0);
+        return Err(WebSeedError::Busy(retry));
+    }
+    if !status.is_success() {
+        return Err(WebSeedError::BadStatus(status.as_u16()));
+    }
+    Ok(body)
+}
+
+// Health is how a web seed has been doing: failed requests and pieces that
+// fail their hash push the next request back
+struct Health {
+    backoff: Duration,
+    hash_failures: u32,
+}
+
+fn new_health() -> Health {
+    Health {
+        backoff: MIN_BACKOFF,
+        hash_failures: 0,
+    }
+}
+
+impl Health {
+    // the delay before the next request
+    fn failed(&mut self) -> Duration {
+        let delay = self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        delay
+    }
+
+    // takes the verdicts of the hash workers on the pieces sent so far, and
+    // returns the delay before the next request, or None once the seed sent
+    // too many bad pieces
+    fn read_verdicts(&mut self, verdicts: &Receiver<bool>) -> Option<Duration> {
+        let mut delay = Duration::from_secs(0);
+        for passed in verdicts.try_iter() {
+            if passed {
+                self.backoff = MIN_BACKOFF;
+                continue;
+            }
+            self.hash_failures += 1;
+            if self.hash_failures >= MAX_HASH_FAILURES {
+                return None;
+            }
+            delay = self.failed();
+        }
+        Some(delay)
+    }
+}
+
+// downloads pieces from a web seed until the swarm is stopped; the web seed
+// takes work from the same queue as the peers, and has every piece
+pub async fn start_web_seed_worker(seed: WebSeed, swarm: p2p::Swarm, counter: i32) {
+    let p2p::Swarm {
+        work_snd,
+        work_rcv,
+        hasher,
+        peers,
+        mut stop,
+        have,
+        download_buckets,
+        proxy,
+        bind,
+        ..
+    } = swarm;
+    let mut builder = reqwest::Client::builder().timeout(REQUEST_TIMEOUT);
+    // the server we connect to: the web seed, or the proxy in front of it
+    let mut server = Url::parse(&seed.url)
+        .ok()
+        .and_then(|url| Some((url.host_str()?.to_owned(), url.port_or_known_default()?)));
+    match proxy.as_deref() {
+        Some(p) if p.kind == proxy::ProxyKind::Http => match p.reqwest_proxy() {
+            Ok(reqwest_proxy) => {
+                builder = builder.proxy(reqwest_proxy);
+                server = Some((p.host.clone(), p.port));
+            }
+            Err(e) => {
+                println!("{}: can't start web seed: {}", seed.url, e);
+                return;
+            }
+        },
+        // reqwest can only tunnel through HTTP proxies
+        Some(p) if p.only => {
+            println!("{}: web seeds can't go through a SOCKS5 proxy", seed.url);
+            return;
+        }
+        _ => {}
+    }
+    if let Some((host, port)) = server {
+        let host = host.trim_matches(|c| c == '[' || c == ']');
+        match bind::source_for_host(host, port, &bind).await {
+            Ok(source) => builder = builder.local_address(source),
+            Err(e) => {
+                println!("{}: can't start web seed: {}", seed.url, e);
+                return;
+            }
+        }
+    }
+    let client = match builder.build() {
+        Ok(client) => client,
+        Err(e) => {
+            println!("{}: can't start web seed: {}", seed.url, e);
+            return;
+        }
+    };
+    let all = bitfield::Bitfield {
+        array: vec![0xff; have.lock().unwrap().array.len()],
+    };
+    let throttle = ratelimit::new_throttle(download_buckets);
+    peers
+        .lock()
+        .unwrap()
+        .insert(counter as usize, choker::new_peer_state());
+
+    let (verdict_snd, verdict_rcv) = crossbeam::unbounded();
+    let mut health = new_health();
+    loop {
+        match health.read_verdicts(&verdict_rcv) {
+            None => {
+                println!(
+                    "{}: dropping web seed after {} bad pieces",
+                    seed.url, MAX_HASH_FAILURES
+                );
+                break;
+            }
+            Some(delay) if delay > Duration::from_secs(0) => {
+                tokio::select! {
+                    _ = delay_for(delay) => {}
+                    _ = p2p::stopped(&mut stop) => break,
+                }
+            }
+            Some(_) => {}
+        }
+        let work = match p2p::take_work(&all, &work_snd, &work_rcv) {
+            Some(work) => work,
+            None => {
+                tokio::select! {
+                    _ = delay_for(IDLE_DELAY) => continue,
+                    _ = p2p::stopped(&mut stop) => break,
+                }
+            }
+        };
+
+        let fetched = tokio::select! {
+            fetched = seed.fetch_piece(&client, &throttle, work.index, work.length) => fetched,
+            _ = p2p::stopped(&mut stop) => {
+                work_snd.send(work).unwrap();
+                break;
+            }
+        };
+        let delay = match fetched {
+            Ok(buf) => {
+                if let Some(state) = peers.lock().unwrap().get_mut(&(counter as usize)) {
+                    state.downloaded += buf.len() as i64;
+                }
+                let sources = vec![None; buf.len().div_ceil(pipeline::BLOCK_SIZE as usize)];
+                hasher.hash(work, buf, sources, Some(verdict_snd.clone()));
+                continue;
+            }
+            Err(WebSeedError::Busy(seconds)) if seconds > 0 => Duration::from_secs(seconds),
+            Err(e) => {
+                println!("{}: piece #{} failed: {}", seed.url, work.index, e);
+                health.failed()
+            }
+        };
+        work_snd.send(work).unwrap();
+        tokio::select! {
+            _ = delay_for(delay) => {}
+            _ = p2p::stopped(&mut stop) => break,
+        }
+    }
+    peers.lock().unwrap().remove(&(counter as usize));
+    println!("{}: web seed #{}: end", seed.url, counter);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::ratelimit;
+
+    // serves `files` by path, honouring `Range: bytes=a-b`
+    fn serve(files: Vec<(&'static str, Vec<u8>)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = vec![0u8; 4096];
+                let n = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..n]).into_owned();
+                let path = request.split(' ').nth(1).unwrap();
+                let data = match files.iter().find(|(p, _)| *p == path) {
+                    Some((_, data)) => data,
+                    None => {
+                        let _ = stream
+                            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+                        continue;
+                    }
+                };
+                let range = request
+                    .lines()
+                    .map(str::to_ascii_lowercase)
+                    .find_map(|l| l.strip_prefix("range: bytes=").map(str::to_owned))
+                    .map(|r| {
+                        let (a, b) = r.split_once('-').unwrap();
+                        (a.parse::<usize>().unwrap(), b.parse::<usize>().unwrap())
+                    });
+                let body = match range {
+                    Some((a, b)) => &data[a..=b],
+                    None => &data[..],
+                };
+                let header = format!(
+                    "HTTP/1.1 {} OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    if range.is_some() { 206 } else { 200 },
+                    body.len()
+                );
+                let _ = stream.write_all(header.as_bytes());
+                let _ = stream.write_all(body);
+            }
+        });
+        format!("http://{}/", addr)
+    }
+
+    #[test]
+    fn file_urls_follow_the_torrent_layout() {
+        let single = super::file_url("http://h/f.iso", "f.iso", Path::new("f.iso"), true);
+        assert_eq!(single.unwrap(), "http://h/f.iso");
+        let single = super::file_url("http://h/d/", "f.iso", Path::new("f.iso"), true);
+        assert_eq!(single.unwrap(), "http://h/d/f.iso");
+        let multi = super::file_url("http://h/d", "ds", Path::new("ds/a b.txt"), false);
+        assert_eq!(multi.unwrap(), "http://h/d/ds/a%20b.txt");
+    }
+
+    #[test]
+    fn pieces_are_assembled_from_ranges() {
+        let a: Vec<u8> = (0..10).collect();
+        let b: Vec<u8> = (10..30).collect();
+        let base = serve(vec![("/ds/a", a), ("/ds/sub/b", b)]);
+        let file = |url: &str, offset, length| super::FileUrl {
+            url: format!("{}{}", base, url),
+            offset,
+            length,
+            padding: false,
+        };
+        let seed = super::WebSeed {
+            url: base.clone(),
+            source: super::Source::Files(vec![file("ds/a", 0, 10), file("ds/sub/b", 10, 20)]),
+            piece_length: 8,
+        };
+
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+        let client = reqwest::Client::new();
+        let unlimited = ratelimit::Throttle::default();
+        let piece = runtime
+            .block_on(seed.fetch_piece(&client, &unlimited, 1, 8))
+            .unwrap();
+        assert_eq!(piece, (8..16).collect::<Vec<u8>>());
+
+        // 40 bytes per second make the 6 bytes of the last piece take 150ms
+        let limited =
+            ratelimit::new_throttle(vec![ratelimit::new_bucket(ratelimit::new_rate(Some(40)))]);
+        let started = Instant::now();
+        let last = runtime
+            .block_on(seed.fetch_piece(&client, &limited, 3, 6))
+            .unwrap();
+        assert_eq!(last, (24..30).collect::<Vec<u8>>());
+        assert!(started.elapsed() >= Duration::from_millis(140));
+
+        let missing = super::WebSeed {
+            source: super::Source::Files(vec![file("ds/c", 0, 30)]),
+            ..seed
+        };
+        match runtime.block_on(missing.fetch_piece(&client, &unlimited, 0, 8)) {
+            Err(super::WebSeedError::BadStatus(404)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn seeds_sending_bad_pieces_back_off_and_are_dropped() {
+        let (verdicts, verdict_rcv) = crossbeam::unbounded();
+        let mut health = super::new_health();
+        assert_eq!(
+            health.read_verdicts(&verdict_rcv),
+            Some(Duration::from_secs(0))
+        );
+
+        // each bad piece waits longer, and a good one starts over
+        verdicts.send(false).unwrap();
+        assert_eq!(health.read_verdicts(&verdict_rcv), Some(super::MIN_BACKOFF));
+        verdicts.send(false).unwrap();
+        assert_eq!(
+            health.read_verdicts(&verdict_rcv),
+            Some(super::MIN_BACKOFF * 2)
+        );
+        verdicts.send(true).unwrap();
+        verdicts.send(false).unwrap();
+        assert_eq!(health.read_verdicts(&verdict_rcv), Some(super::MIN_BACKOFF));
+
+        // but doesn't make up for the bad ones
+        verdicts.send(true).unwrap();
+        assert_eq!(
+            health.read_verdicts(&verdict_rcv),
+            Some(Duration::from_secs(0))
+        );
+        for _ in 3..super::MAX_HASH_FAILURES {
+            verdicts.send(false).unwrap();
+        }
+        assert_eq!(health.read_verdicts(&verdict_rcv), None);
+    }
+}